@@ -22,7 +22,7 @@
 // license. Please contact ithai at pendicepaper.com
 // for more information about commercial licensing terms.
 */
-use std::collections::HashSet;
+use std::collections::{HashSet, VecDeque};
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
@@ -30,6 +30,7 @@ use anyhow::{anyhow, Result};
 use crate::frame::*;
 use crate::generators::*;
 use crate::instance::*;
+use crate::references::ReferenceKind;
 use crate::renderer::render_entity;
 use crate::repository::*;
 use crate::semantics::*;
@@ -61,6 +62,24 @@ pub trait EntityAssigner {
         max: CardinalityValue,
         injectors: Injectors,
     ) -> Self;
+
+    /// Sets how entities are selected, for commands selecting collected
+    /// entities.
+    fn with_selection(self, _selection: Selection) -> Self
+    where
+        Self: Sized,
+    {
+        self
+    }
+
+    /// Sets what to do when no collected entity is available, for commands
+    /// selecting collected entities.
+    fn with_fallback(self, _fallback: Fallback) -> Self
+    where
+        Self: Sized,
+    {
+        self
+    }
 }
 
 pub trait RefInjectCommand {
@@ -134,7 +153,10 @@ impl AttrCommand for AttrCommandAssigner {
         Ok(())
     }
     fn value(&self) -> Option<String> {
-        Some(self.value.as_str().unwrap().to_string())
+        match &self.value {
+            serde_json::Value::String(value) => Some(value.clone()),
+            value => Some(value.to_string()),
+        }
     }
 }
 
@@ -316,6 +338,17 @@ impl EntityAssigner for AttrCommandRollEntity {
 }
 
 impl AttrCommand for AttrCommandRollEntity {
+    fn expected_rolls(&self, builder: &SandboxBuilder) -> Option<(Vec<String>, f64)> {
+        match &self.class_names {
+            ClassNamesToRoll::List(class_names) => Some((
+                class_names.clone(),
+                (resolve_value(builder, &self.min) + resolve_value(builder, &self.max)) as f64
+                    / 2.0,
+            )),
+            _ => None,
+        }
+    }
+
     fn apply(
         &self,
         ctx: &mut Context,
@@ -344,24 +377,45 @@ impl AttrCommand for AttrCommandRollEntity {
             let entity = tx.load(euid)?;
             entity["uid"].as_str().unwrap().to_string()
         };
-        let seq =
-            if max == 0 && min == 0 {
-                serde_json::json!([])
+        let seq = if max == 0 && min == 0 {
+            serde_json::json!([])
+        } else {
+            let n = if max - min > 0 {
+                builder.randomizer.in_range(min, max)
+            } else {
+                min
+            };
+            let mut ret: serde_json::Value = {
+                let entity = tx.load(euid)?;
+                match ctx {
+                    Context::Appending(_) => entity[&self.name].clone(),
+                    Context::Rerolling(_) => entity[&self.name].clone(),
+                    Context::Rolling => serde_json::json!([]),
+                    _ => return Err(anyhow!("Invalid context when applying roll: {:#?}", ctx)),
+                }
+            };
+            let concurrent = matches!(ctx, Context::Rolling)
+                && n > 1
+                && class_names
+                    .iter()
+                    .all(|class_name| builder.options.concurrent_classes.contains(class_name));
+            if concurrent {
+                let actual_class_names: Vec<&str> = (0..n)
+                    .map(|_| builder.randomizer.choose::<String>(&class_names).as_str())
+                    .collect();
+                for generated_uid in roll_concurrently(
+                    builder,
+                    tx,
+                    &actual_class_names,
+                    &uid,
+                    &self.name,
+                    Some(&self.injectors),
+                )? {
+                    ret.as_array_mut()
+                        .unwrap()
+                        .push(serde_json::Value::from(generated_uid));
+                }
             } else {
-                let n = if max - min > 0 {
-                    builder.randomizer.in_range(min, max)
-                } else {
-                    min
-                };
-                let mut ret: serde_json::Value = {
-                    let entity = tx.load(euid)?;
-                    match ctx {
-                        Context::Appending(_) => entity[&self.name].clone(),
-                        Context::Rerolling(_) => entity[&self.name].clone(),
-                        Context::Rolling => serde_json::json!([]),
-                        _ => return Err(anyhow!("Invalid context when applying roll: {:#?}", ctx)),
-                    }
-                };
                 for _ in 0..n {
                     let actual_class_name = builder.randomizer.choose::<String>(&class_names);
                     let generated_uid =
@@ -395,8 +449,9 @@ impl AttrCommand for AttrCommandRollEntity {
                     }
                     collect(builder, tx, &uid, generated_uid.as_str(), actual_class_name)?;
                 }
-                serde_json::json!(ret)
-            };
+            }
+            serde_json::json!(ret)
+        };
         {
             let entity = tx.load(euid)?;
             entity[&self.name] = seq;
@@ -430,12 +485,20 @@ impl AttrCommand for AttrCommandRollEntity {
         // to revert it, and will find nothing.
         if let Some(arr) = entity[&self.name].as_array() {
             for euid in arr.clone() {
-                unroll(builder, tx, euid.as_str().unwrap(), Some(&self.injectors))?;
+                unroll_entity(builder, tx, euid.as_str().unwrap(), Some(&self.injectors))?;
             }
         }
         // entity.clear(&self.name);
         Ok(())
     }
+
+    fn reference_kind(&self) -> Option<ReferenceKind> {
+        Some(ReferenceKind::Child)
+    }
+
+    fn injected_attrs(&self) -> Vec<String> {
+        self.injectors.attr_names()
+    }
 }
 
 ///
@@ -489,21 +552,34 @@ impl AttrCommand for AttrCommandRollFromList {
 /// ```text
 /// attribute = :ClassName.attribute_name
 /// ```
+///
+/// Siblings and descendants can be referenced as well (see `ContextRelation`).
+/// These are resolved when the attribute is applied, so the referenced entity
+/// has to be rolled before, and the referencing entity is registered in its
+/// `$users` to re-resolve the reference when it is unrolled.
 #[derive(Clone)]
 pub struct AttrCommandContext {
     pub name: String,
     pub context_parent: String,
     pub context_attr: String,
+    pub relation: ContextRelation,
 }
 
 impl AttrCommand for AttrCommandContext {
     fn apply(
         &self,
         _ctx: &mut Context,
-        _builder: &SandboxBuilder,
+        builder: &SandboxBuilder,
         tx: &mut ReadWriteTransaction,
         euid: &str,
     ) -> Result<()> {
+        let context_uid = match &self.relation {
+            ContextRelation::Ancestor => context_ancestor(builder, tx, euid, &self.context_parent)?,
+            ContextRelation::Sibling => context_sibling(tx, euid, &self.context_parent)?,
+            ContextRelation::Descendant(scope) => {
+                context_descendant(builder, tx, euid, scope.as_deref(), &self.context_parent)?
+            }
+        };
         let entity = tx.load(euid)?;
         entity[&self.name] = serde_json::json!({
             "type" : "context",
@@ -512,6 +588,32 @@ impl AttrCommand for AttrCommandContext {
             "attr" : self.context_attr
         }
         });
+        match &self.relation {
+            ContextRelation::Ancestor => {}
+            ContextRelation::Sibling => {
+                entity[&self.name]["spec"]["relation"] = serde_json::json!("sibling")
+            }
+            ContextRelation::Descendant(_) => {
+                entity[&self.name]["spec"]["relation"] = serde_json::json!("descendant")
+            }
+        }
+        if let Some(context_uid) = context_uid {
+            entity[&self.name]["spec"]["uid"] = serde_json::Value::from(context_uid.as_str());
+            if self.relation != ContextRelation::Ancestor {
+                return add_user_to_entity(tx, &context_uid, euid, &self.name);
+            }
+            let frame_uid = format!("{}_frame", context_uid);
+            let frame = tx.load(&frame_uid)?;
+            if !frame["$contexts"].is_array() {
+                frame["$contexts"] = serde_json::json!([]);
+            }
+            let user = serde_json::json!({"uid": euid, "attr": self.name});
+            let contexts = frame["$contexts"].as_array_mut().unwrap();
+            if !contexts.contains(&user) {
+                contexts.push(user);
+            }
+            tx.save(&frame_uid)?;
+        }
         Ok(())
     }
 
@@ -523,9 +625,223 @@ impl AttrCommand for AttrCommandContext {
         euid: &str,
     ) -> Result<()> {
         let entity = tx.load(euid)?;
+        let context_uid = entity[&self.name]["spec"]["uid"].as_str().map(String::from);
         entity.clear(&self.name);
+        if let Some(context_uid) = context_uid {
+            let (holder_uid, key) = if self.relation == ContextRelation::Ancestor {
+                (format!("{}_frame", context_uid), "$contexts")
+            } else {
+                (context_uid, "$users")
+            };
+            if let Ok(holder) = tx.load(&holder_uid) {
+                if let Some(users) = holder[key].as_array_mut() {
+                    users.retain(|user| !(user["uid"] == euid && user["attr"] == self.name));
+                    tx.save(&holder_uid)?;
+                }
+            }
+        }
         Ok(())
     }
+
+    fn reference_kind(&self) -> Option<ReferenceKind> {
+        Some(ReferenceKind::Context)
+    }
+}
+
+/// Find the nearest ancestor of an entity of the given class, if any.
+fn context_ancestor(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    euid: &str,
+    class_name: &str,
+) -> Result<Option<String>> {
+    let mut uid = tx.load(euid)?["parent_uid"]
+        .as_str()
+        .unwrap_or("root")
+        .to_string();
+    while uid != "root" {
+        let ancestor = tx.load(&uid)?;
+        let ancestor_class_name = ancestor["class"].as_str().unwrap();
+        if builder.sandbox.classes[ancestor_class_name]
+            .hierarchy
+            .iter()
+            .any(|c| c == class_name)
+        {
+            return Ok(Some(uid));
+        }
+        uid = ancestor["parent_uid"].as_str().unwrap().to_string();
+    }
+    Ok(None)
+}
+
+/// Find the first entity, other than the entity itself, held by the given
+/// attribute of the entity's parent.
+fn context_sibling(
+    tx: &mut ReadWriteTransaction,
+    euid: &str,
+    sibling_attr: &str,
+) -> Result<Option<String>> {
+    let Some(parent_uid) = tx.load(euid)?["parent_uid"]
+        .as_str()
+        .filter(|uid| *uid != "root")
+        .map(String::from)
+    else {
+        return Ok(None);
+    };
+    Ok(tx.load(&parent_uid)?[sibling_attr]
+        .as_array()
+        .and_then(|uids| {
+            uids.iter()
+                .filter_map(|uid| uid.as_str())
+                .find(|uid| *uid != euid)
+        })
+        .map(String::from))
+}
+
+/// Find the entity of the given class nearest to an entity among its
+/// descendants, or, when a scope class is given, among the descendants of
+/// its ancestors up to the nearest one of the scope class.
+///
+/// Indexed transactions look up the entities of the class using the class
+/// index rather than walking the whole scope for every entity resolving a
+/// descendant context, with the same outcome.
+fn context_descendant(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    euid: &str,
+    scope: Option<&str>,
+    class_name: &str,
+) -> Result<Option<String>> {
+    let levels = context_levels(builder, tx, euid, scope)?;
+    if tx.is_indexed() {
+        indexed_descendant(builder, tx, &levels, class_name)
+    } else {
+        searched_descendant(builder, tx, &levels, class_name)
+    }
+}
+
+/// The entity and its ancestors to search the descendants of, from the
+/// entity up to the nearest ancestor of the scope class, if any.
+fn context_levels(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    euid: &str,
+    scope: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut levels = vec![euid.to_string()];
+    let Some(scope) = scope else {
+        return Ok(levels);
+    };
+    loop {
+        let entity = tx.load(levels.last().unwrap())?;
+        let class_name = entity["class"].as_str().unwrap();
+        if builder.sandbox.classes[class_name]
+            .hierarchy
+            .iter()
+            .any(|c| c == scope)
+        {
+            return Ok(levels);
+        }
+        match entity["parent_uid"].as_str() {
+            Some(parent_uid) if parent_uid != "root" => levels.push(parent_uid.to_string()),
+            _ => return Ok(levels),
+        }
+    }
+}
+
+/// Search the descendants of every level breadth-first, skipping the
+/// level searched before it.
+fn searched_descendant(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    levels: &[String],
+    class_name: &str,
+) -> Result<Option<String>> {
+    for (level, uid) in levels.iter().enumerate() {
+        let searched = level.checked_sub(1).map(|below| levels[below].as_str());
+        let mut queue = VecDeque::from([uid.clone()]);
+        while let Some(next) = queue.pop_front() {
+            let entity = tx.load(&next)?;
+            let class = &builder.sandbox.classes[entity["class"].as_str().unwrap()];
+            if next != *uid && class.hierarchy.iter().any(|c| c == class_name) {
+                return Ok(Some(next));
+            }
+            for (attr_name, attr) in class.attrs.iter() {
+                if attr.cmd.reference_kind() != Some(ReferenceKind::Child) {
+                    continue;
+                }
+                if let Some(children) = entity[attr_name].as_array() {
+                    queue.extend(
+                        children
+                            .iter()
+                            .filter_map(|child| child.as_str())
+                            .filter(|child| Some(*child) != searched)
+                            .map(String::from),
+                    );
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The level a descendant descends from, its depth below the level and the
+/// positions of the child attributes and children leading to it.
+type DescendantRank = (usize, usize, Vec<(usize, usize)>);
+
+/// Rank the indexed entities of the class by the nearest level they descend
+/// from, then by their position below it in breadth-first order, which is
+/// the order of the child attributes and children leading to them.
+fn indexed_descendant(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    levels: &[String],
+    class_name: &str,
+) -> Result<Option<String>> {
+    let candidates = tx.indexed(&IndexKey::Class(class_name.to_string()))?;
+    let mut nearest: Option<(DescendantRank, String)> = None;
+    for candidate in candidates {
+        if levels.contains(&candidate) {
+            continue;
+        }
+        let mut path = vec![];
+        let mut uid = candidate.clone();
+        let level = loop {
+            let parent = tx.load(&uid)?["$parent"].clone();
+            let (Some(parent_uid), Some(attr_name)) =
+                (parent["uid"].as_str(), parent["attr"].as_str())
+            else {
+                break None;
+            };
+            let parent = tx.load(parent_uid)?;
+            let class = &builder.sandbox.classes[parent["class"].as_str().unwrap()];
+            let Some(attr_index) = class.attrs.get_index_of(attr_name).filter(|index| {
+                class.attrs[*index].cmd.reference_kind() == Some(ReferenceKind::Child)
+            }) else {
+                break None;
+            };
+            let Some(child_index) = parent[attr_name]
+                .as_array()
+                .and_then(|children| children.iter().position(|child| *child == uid))
+            else {
+                break None;
+            };
+            path.push((attr_index, child_index));
+            if let Some(level) = levels.iter().position(|level| level == parent_uid) {
+                break Some(level);
+            }
+            uid = parent_uid.to_string();
+        };
+        let Some(level) = level else {
+            continue;
+        };
+        path.reverse();
+        let rank = (level, path.len(), path);
+        if nearest.as_ref().is_none_or(|(nearest, _)| rank < *nearest) {
+            nearest = Some((rank, candidate));
+        }
+    }
+    Ok(nearest.map(|(_, uid)| uid))
 }
 
 /// Roll an attribute value indirectly using a variable.
@@ -554,8 +870,10 @@ impl AttrCommand for AttrCommandRollFromVariable {
         tx: &mut ReadWriteTransaction,
         euid: &str,
     ) -> Result<()> {
-        let value = builder.sandbox.globals[&self.var]
-            .as_array()
+        let value = builder
+            .sandbox
+            .global(&self.var)
+            .and_then(|v| v.as_array())
             .ok_or(anyhow!("Unable to find {}", self.var))?;
         let entity = tx.load(euid)?;
         entity[&self.name] = builder.randomizer.choose(value).to_owned();
@@ -588,6 +906,8 @@ impl AttrCommand for AttrCommandRollFromVariable {
 ///
 /// * The class name can be a concrete or a base class
 /// * The used entity will be made unavailable for others until it gets recycled
+/// * A fallback can roll a fresh entity, try another class or fail generation
+///   when no collected entity is available, e.g. `attribute % ClassName else @ ClassName`
 ///
 /// Any selected entity can be 'injected' with new attributes or attribute overrides:
 ///
@@ -602,6 +922,8 @@ pub struct AttrCommandUseEntity {
     pub class_names: ClassNamesToRoll,
     pub min: CardinalityValue,
     pub max: CardinalityValue,
+    pub selection: Selection,
+    pub fallback: Fallback,
     injectors: Injectors,
 }
 
@@ -618,9 +940,19 @@ impl EntityAssigner for AttrCommandUseEntity {
             class_names,
             min,
             max,
+            selection: Selection::default(),
+            fallback: Fallback::default(),
             injectors,
         }
     }
+
+    fn with_selection(self, selection: Selection) -> Self {
+        AttrCommandUseEntity { selection, ..self }
+    }
+
+    fn with_fallback(self, fallback: Fallback) -> Self {
+        AttrCommandUseEntity { fallback, ..self }
+    }
 }
 
 impl AttrCommand for AttrCommandUseEntity {
@@ -650,11 +982,29 @@ impl AttrCommand for AttrCommandUseEntity {
         };
         for _ in 0..builder.randomizer.in_range(min, max) {
             let cls = builder.randomizer.choose::<String>(class_names);
-            if let Ok(Some(selected_uid)) = use_collected(builder, tx, euid, cls) {
+            let selected_uid = match use_collected(builder, tx, euid, cls, &self.selection) {
+                Ok(None) => apply_fallback(
+                    builder,
+                    tx,
+                    euid,
+                    &self.name,
+                    cls,
+                    &self.fallback,
+                    |tx, cls| use_collected(builder, tx, euid, cls, &self.selection),
+                )?,
+                selected => selected?,
+            };
+            if let Some(selected_uid) = selected_uid {
+                if is_fallback_roll(tx, euid, &self.name, &selected_uid)? {
+                    use_entity(tx, euid, &selected_uid)?;
+                }
                 for injector in self.injectors.appenders.as_slice() {
                     injector.inject(builder, tx, &selected_uid, euid)?;
                 }
-                add_user_to_entity(tx, &selected_uid, euid, &self.name)?;
+                if !self.injectors.appenders.is_empty() {
+                    recollect(builder, tx, &selected_uid)?;
+                }
+                add_picker_to_entity(tx, &selected_uid, euid, &self.name)?;
                 {
                     let entity = tx.load(euid)?;
                     let list = entity[&self.name].as_array_mut().unwrap();
@@ -692,12 +1042,27 @@ impl AttrCommand for AttrCommandUseEntity {
                     .unwrap()
                     .retain(|user| !(user["uid"] == euid && user["attr"] == self.name));
                 tx.save(uid_in_use)?;
-                recycle(tx, euid, uid_in_use, &entity_in_use_class_name)?;
+                if is_fallback_roll(tx, euid, &self.name, uid_in_use)? {
+                    unroll_entity(builder, tx, uid_in_use, None)?;
+                } else {
+                    recycle(tx, euid, uid_in_use, &entity_in_use_class_name)?;
+                    if !self.injectors.appenders.is_empty() {
+                        recollect(builder, tx, uid_in_use)?;
+                    }
+                }
             }
         }
         // entity.clear(&self.name); // This is likely redundant, but kept for clarity
         Ok(())
     }
+
+    fn reference_kind(&self) -> Option<ReferenceKind> {
+        Some(ReferenceKind::Use)
+    }
+
+    fn injected_attrs(&self) -> Vec<String> {
+        self.injectors.attr_names()
+    }
 }
 
 /// Pick a collected entity by class name:
@@ -714,6 +1079,8 @@ impl AttrCommand for AttrCommandUseEntity {
 /// * The class name can be a concrete or a base class
 /// * Picked entities can be selected again, unlike used entities.
 /// * If multiple entities are picked for an attribute, uniqueness is maintained.
+/// * A fallback can roll a fresh entity, try another class or fail generation
+///   when no collected entity is available, e.g. `attribute ? ClassName else fail`
 ///
 /// Any selected entity can be 'injected' with new attributes or attribute overrides:
 ///
@@ -728,6 +1095,8 @@ pub struct AttrCommandPickEntity {
     pub class_names: ClassNamesToRoll,
     pub min: CardinalityValue,
     pub max: CardinalityValue,
+    pub selection: Selection,
+    pub fallback: Fallback,
     injectors: Injectors,
 }
 
@@ -744,9 +1113,19 @@ impl EntityAssigner for AttrCommandPickEntity {
             class_names,
             min,
             max,
+            selection: Selection::default(),
+            fallback: Fallback::default(),
             injectors,
         }
     }
+
+    fn with_selection(self, selection: Selection) -> Self {
+        AttrCommandPickEntity { selection, ..self }
+    }
+
+    fn with_fallback(self, fallback: Fallback) -> Self {
+        AttrCommandPickEntity { fallback, ..self }
+    }
 }
 
 impl AttrCommand for AttrCommandPickEntity {
@@ -778,15 +1157,30 @@ impl AttrCommand for AttrCommandPickEntity {
         let mut uniqueness_check_set: HashSet<String> = HashSet::new();
         for _ in 0..builder.randomizer.in_range(min, max) {
             let cls = builder.randomizer.choose::<String>(class_names);
-            if let Ok(Some(selected_uid)) = pick_collected(builder, tx, euid, cls) {
+            let selected_uid = match pick_collected(builder, tx, euid, cls, &self.selection) {
+                Ok(None) => apply_fallback(
+                    builder,
+                    tx,
+                    euid,
+                    &self.name,
+                    cls,
+                    &self.fallback,
+                    |tx, cls| pick_collected(builder, tx, euid, cls, &self.selection),
+                )?,
+                selected => selected?,
+            };
+            if let Some(selected_uid) = selected_uid {
                 if !uniqueness_check_set.insert(selected_uid.clone()) {
                     continue;
                 }
                 for injector in self.injectors.appenders.as_slice() {
                     injector.inject(builder, tx, &selected_uid, euid)?;
                 }
+                if !self.injectors.appenders.is_empty() {
+                    recollect(builder, tx, &selected_uid)?;
+                }
 
-                add_user_to_entity(tx, &selected_uid, euid, &self.name)?;
+                add_picker_to_entity(tx, &selected_uid, euid, &self.name)?;
                 let entity = tx.load(euid)?;
                 let list = entity[&self.name].as_array_mut().unwrap();
                 list.push(serde_json::to_value(selected_uid)?);
@@ -820,10 +1214,23 @@ impl AttrCommand for AttrCommandPickEntity {
                     .unwrap()
                     .retain(|user| !(user["uid"] == euid && user["attr"] == self.name));
                 tx.save(uid_in_use)?;
+                if is_fallback_roll(tx, euid, &self.name, uid_in_use)? {
+                    unroll_entity(builder, tx, uid_in_use, None)?;
+                } else if !self.injectors.appenders.is_empty() {
+                    recollect(builder, tx, uid_in_use)?;
+                }
             }
         }
         Ok(())
     }
+
+    fn reference_kind(&self) -> Option<ReferenceKind> {
+        Some(ReferenceKind::Pick)
+    }
+
+    fn injected_attrs(&self) -> Vec<String> {
+        self.injectors.attr_names()
+    }
 }
 
 /// An attribute injection command that sets a simple value:
@@ -846,6 +1253,9 @@ impl ValueAssigner for InjectCommandSetValue {
 }
 
 impl InjectCommand for InjectCommandSetValue {
+    fn name(&self) -> &str {
+        &self.name
+    }
     fn inject(
         &self,
         _builder: &SandboxBuilder,
@@ -886,6 +1296,9 @@ pub struct InjectCommandDiceRoll {
 }
 
 impl InjectCommand for InjectCommandDiceRoll {
+    fn name(&self) -> &str {
+        &self.name
+    }
     fn inject(
         &self,
         builder: &SandboxBuilder,
@@ -932,6 +1345,9 @@ pub struct InjectCommandRollFromList {
 }
 
 impl InjectCommand for InjectCommandRollFromList {
+    fn name(&self) -> &str {
+        &self.name
+    }
     fn inject(
         &self,
         builder: &SandboxBuilder,
@@ -980,6 +1396,9 @@ impl RefInjectCommand for InjectCommandCopyValue {
 }
 
 impl InjectCommand for InjectCommandCopyValue {
+    fn name(&self) -> &str {
+        &self.name
+    }
     fn inject(
         &self,
         _builder: &SandboxBuilder,
@@ -1037,6 +1456,9 @@ impl RefInjectCommand for InjectCommandPtr {
 }
 
 impl InjectCommand for InjectCommandPtr {
+    fn name(&self) -> &str {
+        &self.name
+    }
     fn inject(
         &self,
         _builder: &SandboxBuilder,
@@ -1071,11 +1493,90 @@ impl InjectCommand for InjectCommandPtr {
         _caller: &str,
     ) -> Result<()> {
         let entity = tx.load(euid)?;
+        let pointer_uid = entity[&self.name]["spec"]["uid"].as_str().map(String::from);
         entity[&self.name] = serde_json::Value::from(false);
+        if let Some(pointer_uid) = pointer_uid {
+            if let Ok(pointed) = tx.load(&pointer_uid) {
+                if let Some(users) = pointed["$users"].as_array_mut() {
+                    users.retain(|user| !(user["uid"] == euid && user["attr"] == self.name));
+                    tx.save(&pointer_uid)?;
+                }
+            }
+        }
         Ok(())
     }
 }
 
+/// Apply the fallback of a use or pick command when no collected entity of
+/// the class is available, returning the uid of the entity to select.
+///
+/// Entities rolled as a fallback are children of the selecting entity and
+/// are recorded in its `$fallbacks` so they can be unrolled along with it.
+fn apply_fallback<F>(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    euid: &str,
+    attr_name: &str,
+    class_name: &str,
+    fallback: &Fallback,
+    select: F,
+) -> Result<Option<String>>
+where
+    F: Fn(&mut ReadWriteTransaction, &str) -> Result<Option<String>>,
+{
+    match fallback {
+        Fallback::Nothing => Ok(None),
+        Fallback::Select(other_class_name) => select(tx, other_class_name),
+        Fallback::Fail => {
+            let entity_class_name = tx.load(euid)?["class"].as_str().unwrap().to_string();
+            Err(anyhow!(
+                "No collected {} is available for {}.{}",
+                class_name,
+                entity_class_name,
+                attr_name
+            ))
+        }
+        Fallback::Roll(class_to_roll) => {
+            let rolled_uid = roll(builder, tx, class_to_roll, euid, None)?;
+            let rolled_class_name = {
+                let rolled = tx.load(&rolled_uid)?;
+                rolled["$parent"] = serde_json::json!({
+                    "uid": euid,
+                    "attr": attr_name,
+                });
+                rolled["class"].as_str().unwrap().to_string()
+            };
+            tx.save(&rolled_uid)?;
+            collect(builder, tx, euid, &rolled_uid, &rolled_class_name)?;
+            let entity = tx.load(euid)?;
+            if entity.is_missing("$fallbacks") {
+                entity["$fallbacks"] = serde_json::json!({});
+            }
+            if entity["$fallbacks"].is_missing(attr_name) {
+                entity["$fallbacks"][attr_name] = serde_json::json!([]);
+            }
+            entity["$fallbacks"][attr_name]
+                .as_array_mut()
+                .unwrap()
+                .push(serde_json::Value::from(rolled_uid.as_str()));
+            Ok(Some(rolled_uid))
+        }
+    }
+}
+
+/// Whether the entity selected by an attribute was rolled as its fallback.
+fn is_fallback_roll(
+    tx: &mut ReadWriteTransaction,
+    euid: &str,
+    attr_name: &str,
+    uid: &str,
+) -> Result<bool> {
+    let entity = tx.load(euid)?;
+    Ok(entity["$fallbacks"][attr_name]
+        .as_array()
+        .is_some_and(|uids| uids.iter().any(|v| v == uid)))
+}
+
 /// Adds a user to a used, picked or pointed-to entity.
 /// The user spec is added to a `$users` array attribute and is used to
 /// inform any users in case the entity in use is unrolled.
@@ -1094,6 +1595,42 @@ fn add_user_to_entity(
     uid: &str,
     user_uid: &str,
     user_attr: &str,
+) -> Result<()> {
+    add_user_spec(
+        tx,
+        uid,
+        serde_json::json!({
+            "uid": user_uid,
+            "attr": user_attr,
+        }),
+    )
+}
+
+/// Adds a user to a used or picked entity, like `add_user_to_entity`,
+/// recording the pick sequence number in the user spec for selections
+/// preferring the least recently picked entities.
+fn add_picker_to_entity(
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+    user_uid: &str,
+    user_attr: &str,
+) -> Result<()> {
+    let picked = next_pick(tx)?;
+    add_user_spec(
+        tx,
+        uid,
+        serde_json::json!({
+            "uid": user_uid,
+            "attr": user_attr,
+            "picked": picked,
+        }),
+    )
+}
+
+fn add_user_spec(
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+    user_spec: serde_json::Value,
 ) -> Result<()> {
     let selected_entity = tx.load(uid)?;
     if selected_entity.is_missing("$users") {
@@ -1102,10 +1639,7 @@ fn add_user_to_entity(
     selected_entity["$users"]
         .as_array_mut()
         .unwrap()
-        .push(serde_json::json!({
-            "uid": user_uid,
-            "attr": user_attr,
-        }));
+        .push(user_spec);
     tx.save(uid)?;
     Ok(())
 }
@@ -1151,7 +1685,7 @@ fn resolve_value(builder: &SandboxBuilder, cv: &CardinalityValue) -> i32 {
         CardinalityValue::Number(n) => *n,
         CardinalityValue::Variable(v) => {
             log::info!("{}", v);
-            builder.sandbox.globals.get(v).unwrap().as_i64().unwrap() as i32
+            builder.sandbox.global(v).unwrap().as_i64().unwrap() as i32
         }
         CardinalityValue::Undefined => 1,
     }
@@ -0,0 +1,260 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use anyhow::{anyhow, Result};
+
+use crate::instance::*;
+use crate::repository::*;
+use crate::semantics::*;
+
+/// The number of re-draws when `Limits::max_redraws` is unset.
+pub const DEFAULT_MAX_REDRAWS: usize = 32;
+
+/// Resolve the class to roll, re-drawing subclasses until the class meets
+/// its quota and class uniqueness constraints.
+pub fn resolve_class<'a, F>(
+    builder: &'a SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    parent_uid: &str,
+    class_name: &str,
+    resolve: F,
+) -> Result<&'a Class>
+where
+    F: Fn() -> Result<&'a Class>,
+{
+    let max_redraws = max_redraws(builder);
+    let mut redraws = 0;
+    loop {
+        let class = resolve()?;
+        let Some(violated) = violated_class_constraint(builder, tx, parent_uid, class)? else {
+            return Ok(class);
+        };
+        if redraws == max_redraws {
+            return Err(anyhow!(
+                "Unable to satisfy the {} when rolling {} after {} re-draws",
+                violated,
+                class_name,
+                redraws
+            ));
+        }
+        redraws += 1;
+    }
+}
+
+/// Re-draw the attributes of a rolled entity until their values meet the
+/// uniqueness constraints of its class.
+pub fn enforce_unique_attributes(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    parent_uid: &str,
+    class: &Class,
+    uid: &str,
+) -> Result<()> {
+    let max_redraws = max_redraws(builder);
+    for constraint in class.constraints.iter() {
+        let ConstraintKind::Unique(attr_name) = &constraint.kind else {
+            continue;
+        };
+        if attr_name == "class" {
+            continue;
+        }
+        let Some(scope_frame_uid) = scope_frame(builder, tx, parent_uid, &constraint.scope)? else {
+            continue;
+        };
+        let mut redraws = 0;
+        loop {
+            let value = tx.load(uid)?[attr_name].clone();
+            if !is_taken(tx, &scope_frame_uid, constraint, uid, &value)? {
+                break;
+            }
+            let attr = class.attrs.get(attr_name).filter(|_| redraws < max_redraws);
+            let Some(attr) = attr else {
+                return Err(anyhow!(
+                    "Unable to satisfy the {} when rolling {} after {} re-draws",
+                    constraint,
+                    class.name,
+                    redraws
+                ));
+            };
+            attr.cmd.revert(&mut Context::Unrolling, builder, tx, uid)?;
+            attr.cmd.apply(&mut Context::Rolling, builder, tx, uid)?;
+            redraws += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Register a rolled entity in the registries of its constraint scopes.
+///
+/// Registries are kept in the frame of the scope entity, under `$registry`,
+/// mapping the uids of constrained entities to their constrained values.
+pub fn register(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    parent_uid: &str,
+    class: &Class,
+    uid: &str,
+) -> Result<()> {
+    for constraint in class.constraints.iter() {
+        let Some(scope_frame_uid) = scope_frame(builder, tx, parent_uid, &constraint.scope)? else {
+            continue;
+        };
+        let value = constrained_value(tx, constraint, class, uid)?;
+        let frame = tx.load(&scope_frame_uid)?;
+        if !frame["$registry"].is_object() {
+            frame["$registry"] = serde_json::json!({});
+        }
+        let key = constraint.key();
+        if !frame["$registry"][&key].is_object() {
+            frame["$registry"][&key] = serde_json::json!({});
+        }
+        frame["$registry"][&key][uid] = value;
+        tx.save(&scope_frame_uid)?;
+    }
+    Ok(())
+}
+
+/// Release an unrolled entity from the registries of its constraint scopes.
+pub fn release(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    parent_uid: &str,
+    class: &Class,
+    uid: &str,
+) -> Result<()> {
+    for constraint in class.constraints.iter() {
+        let Some(scope_frame_uid) = scope_frame(builder, tx, parent_uid, &constraint.scope)? else {
+            continue;
+        };
+        let frame = tx.load(&scope_frame_uid)?;
+        if let Some(registry) = frame["$registry"][&constraint.key()].as_object_mut() {
+            registry.swap_remove(uid);
+            tx.save(&scope_frame_uid)?;
+        }
+    }
+    Ok(())
+}
+
+fn max_redraws(builder: &SandboxBuilder) -> usize {
+    builder
+        .options
+        .limits
+        .max_redraws
+        .unwrap_or(DEFAULT_MAX_REDRAWS)
+}
+
+/// The first quota or class uniqueness constraint a class to roll violates.
+fn violated_class_constraint<'a>(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    parent_uid: &str,
+    class: &'a Class,
+) -> Result<Option<&'a Constraint>> {
+    for constraint in class.constraints.iter() {
+        let violated = match &constraint.kind {
+            ConstraintKind::Unique(attr_name) if attr_name == "class" => {
+                let Some(scope_frame_uid) =
+                    scope_frame(builder, tx, parent_uid, &constraint.scope)?
+                else {
+                    continue;
+                };
+                let value = serde_json::Value::from(class.name.as_str());
+                is_taken(tx, &scope_frame_uid, constraint, "", &value)?
+            }
+            ConstraintKind::Quota(quota) => {
+                let Some(scope_frame_uid) =
+                    scope_frame(builder, tx, parent_uid, &constraint.scope)?
+                else {
+                    continue;
+                };
+                let frame = tx.load(&scope_frame_uid)?;
+                frame["$registry"][&constraint.key()]
+                    .as_object()
+                    .is_some_and(|registry| registry.len() >= *quota)
+            }
+            ConstraintKind::Unique(_) => false,
+        };
+        if violated {
+            return Ok(Some(constraint));
+        }
+    }
+    Ok(None)
+}
+
+/// The value registered for a constrained entity.
+fn constrained_value(
+    tx: &mut ReadWriteTransaction,
+    constraint: &Constraint,
+    class: &Class,
+    uid: &str,
+) -> Result<serde_json::Value> {
+    Ok(match &constraint.kind {
+        ConstraintKind::Unique(attr_name) if attr_name != "class" => {
+            tx.load(uid)?[attr_name].clone()
+        }
+        _ => serde_json::Value::from(class.name.as_str()),
+    })
+}
+
+/// Whether a value is registered for any entity other than the given one.
+fn is_taken(
+    tx: &mut ReadWriteTransaction,
+    scope_frame_uid: &str,
+    constraint: &Constraint,
+    uid: &str,
+    value: &serde_json::Value,
+) -> Result<bool> {
+    let frame = tx.load(scope_frame_uid)?;
+    Ok(frame["$registry"][&constraint.key()]
+        .as_object()
+        .is_some_and(|registry| {
+            registry
+                .iter()
+                .any(|(other_uid, other_value)| other_uid != uid && other_value == value)
+        }))
+}
+
+/// The frame uid of the nearest entity of the scope class, starting from
+/// the given entity and up its ancestors.
+fn scope_frame(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+    scope: &str,
+) -> Result<Option<String>> {
+    let mut uid = uid.to_string();
+    while uid != "root" {
+        let entity = tx.load(&uid)?;
+        let class_name = entity["class"].as_str().unwrap();
+        if builder.sandbox.classes[class_name]
+            .hierarchy
+            .iter()
+            .any(|c| c == scope)
+        {
+            return Ok(Some(format!("{}_frame", uid)));
+        }
+        uid = entity["parent_uid"].as_str().unwrap().to_string();
+    }
+    Ok(None)
+}
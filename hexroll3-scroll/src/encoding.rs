@@ -0,0 +1,215 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{anyhow, Result};
+
+/// The first byte of values stored with an encoding header, followed by a
+/// byte identifying the encoding. It is reserved in CBOR, so values stored
+/// before headers were introduced, which are plain CBOR, are told apart
+/// and read as they always were.
+const HEADER: u8 = 0xfc;
+
+const CBOR: u8 = 0;
+const ZSTD: u8 = 1;
+const ZSTD_DICTIONARY: u8 = 2;
+
+/// The zstd compression level, where 0 is zstd's default level.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 0;
+
+/// How values are encoded when stored in a repository. Values stored using
+/// any encoding can be read regardless of the encoding used for writing,
+/// although reading compressed values needs the `zstd` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueEncoding {
+    /// CBOR, uncompressed.
+    Cbor,
+    /// CBOR compressed using zstd.
+    Zstd,
+    /// CBOR compressed using zstd with a dictionary trained on the values of
+    /// a repository (see `Repository::train_dictionary`), identified by its
+    /// id. Dictionaries are stored in the repositories using them.
+    ZstdDictionary(u32),
+}
+
+impl Default for ValueEncoding {
+    /// Values are written as plain CBOR unless another encoding is chosen
+    /// (see `Repository::encode_with`), whether or not the `zstd` feature
+    /// is enabled, since enabling it for one crate of a workspace enables it
+    /// for every other crate as well.
+    fn default() -> Self {
+        ValueEncoding::Cbor
+    }
+}
+
+impl ValueEncoding {
+    /// Whether values can be written using this encoding, which needs the
+    /// `zstd` feature, and a registered dictionary when using one.
+    pub fn is_supported(&self) -> bool {
+        match self {
+            ValueEncoding::Cbor => true,
+            ValueEncoding::Zstd => cfg!(feature = "zstd"),
+            ValueEncoding::ZstdDictionary(id) => {
+                cfg!(feature = "zstd") && dictionary(*id).is_some()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ValueEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueEncoding::Cbor => write!(f, "cbor"),
+            ValueEncoding::Zstd => write!(f, "zstd"),
+            ValueEncoding::ZstdDictionary(id) => write!(f, "zstd with dictionary {:08x}", id),
+        }
+    }
+}
+
+/// A dictionary prepared for compressing and decompressing values.
+struct Dictionary {
+    bytes: Vec<u8>,
+    #[cfg(feature = "zstd")]
+    encoder: zstd::dict::EncoderDictionary<'static>,
+    #[cfg(feature = "zstd")]
+    decoder: zstd::dict::DecoderDictionary<'static>,
+}
+
+/// Dictionaries are shared by every repository of the process, since values
+/// are decoded without knowing the repository they were read from.
+fn dictionaries() -> &'static RwLock<HashMap<u32, Arc<Dictionary>>> {
+    static DICTIONARIES: OnceLock<RwLock<HashMap<u32, Arc<Dictionary>>>> = OnceLock::new();
+    DICTIONARIES.get_or_init(|| RwLock::new(HashMap::new()))
+}
+
+fn dictionary(id: u32) -> Option<Arc<Dictionary>> {
+    dictionaries().read().ok()?.get(&id).cloned()
+}
+
+/// The bytes of a registered dictionary.
+pub fn registered_dictionary(id: u32) -> Option<Vec<u8>> {
+    dictionary(id).map(|dictionary| dictionary.bytes.clone())
+}
+
+/// The id of a dictionary, an FNV-1a hash of its bytes.
+pub fn dictionary_id(dictionary: &[u8]) -> u32 {
+    dictionary.iter().fold(0x811c9dc5, |hash: u32, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Register a dictionary for encoding and decoding values, returning its id.
+pub fn register_dictionary(dictionary: &[u8]) -> u32 {
+    let id = dictionary_id(dictionary);
+    let prepared = Dictionary {
+        bytes: dictionary.to_vec(),
+        #[cfg(feature = "zstd")]
+        encoder: zstd::dict::EncoderDictionary::copy(dictionary, ZSTD_LEVEL),
+        #[cfg(feature = "zstd")]
+        decoder: zstd::dict::DecoderDictionary::copy(dictionary),
+    };
+    if let Ok(mut dictionaries) = dictionaries().write() {
+        dictionaries.entry(id).or_insert_with(|| Arc::new(prepared));
+    }
+    id
+}
+
+/// Train a dictionary of up to `max_size` bytes on sample values.
+#[cfg(feature = "zstd")]
+pub fn train_dictionary(samples: &[serde_json::Value], max_size: usize) -> Result<Vec<u8>> {
+    let samples = samples.iter().map(cbor).collect::<Result<Vec<Vec<u8>>>>()?;
+    zstd::dict::from_samples(&samples, max_size)
+        .map_err(|e| anyhow!("Failed to train a dictionary: {}", e))
+}
+
+fn cbor(value: &serde_json::Value) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// Encode a value, prefixed with the encoding header.
+pub fn encode(value: &serde_json::Value, encoding: ValueEncoding) -> Result<Vec<u8>> {
+    let bytes = cbor(value)?;
+    match encoding {
+        ValueEncoding::Cbor => Ok([&[HEADER, CBOR], bytes.as_slice()].concat()),
+        #[cfg(feature = "zstd")]
+        ValueEncoding::Zstd => {
+            let mut encoded = vec![HEADER, ZSTD];
+            zstd::stream::copy_encode(bytes.as_slice(), &mut encoded, ZSTD_LEVEL)?;
+            Ok(encoded)
+        }
+        #[cfg(feature = "zstd")]
+        ValueEncoding::ZstdDictionary(id) => {
+            use std::io::Write;
+            let dictionary =
+                dictionary(id).ok_or_else(|| anyhow!("Dictionary {:08x} is missing", id))?;
+            let mut encoded = vec![HEADER, ZSTD_DICTIONARY];
+            encoded.extend_from_slice(&id.to_le_bytes());
+            let mut encoder =
+                zstd::stream::Encoder::with_prepared_dictionary(encoded, &dictionary.encoder)?;
+            encoder.write_all(&bytes)?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(not(feature = "zstd"))]
+        encoding => Err(anyhow!(
+            "Encoding values using {} needs the zstd feature",
+            encoding
+        )),
+    }
+}
+
+/// Decode a value, returning the encoding it was stored with.
+pub fn decode(bytes: &[u8]) -> Result<(serde_json::Value, ValueEncoding)> {
+    let (cbor, encoding) = match bytes {
+        [HEADER, CBOR, cbor @ ..] => (cbor.to_vec(), ValueEncoding::Cbor),
+        #[cfg(feature = "zstd")]
+        [HEADER, ZSTD, compressed @ ..] => {
+            (zstd::stream::decode_all(compressed)?, ValueEncoding::Zstd)
+        }
+        #[cfg(feature = "zstd")]
+        [HEADER, ZSTD_DICTIONARY, a, b, c, d, compressed @ ..] => {
+            use std::io::Read;
+            let id = u32::from_le_bytes([*a, *b, *c, *d]);
+            let dictionary =
+                dictionary(id).ok_or_else(|| anyhow!("Dictionary {:08x} is missing", id))?;
+            let mut cbor = Vec::new();
+            zstd::stream::Decoder::with_prepared_dictionary(compressed, &dictionary.decoder)?
+                .read_to_end(&mut cbor)?;
+            (cbor, ValueEncoding::ZstdDictionary(id))
+        }
+        #[cfg(not(feature = "zstd"))]
+        [HEADER, ZSTD | ZSTD_DICTIONARY, ..] => {
+            return Err(anyhow!("Decoding compressed values needs the zstd feature"))
+        }
+        [HEADER, encoding, ..] => return Err(anyhow!("Unknown value encoding {}", encoding)),
+        // Values stored before encoding headers were introduced
+        cbor => (cbor.to_vec(), ValueEncoding::Cbor),
+    };
+    let value: ciborium::Value = ciborium::from_reader(cbor.as_slice())?;
+    Ok((serde_json::to_value(value)?, encoding))
+}
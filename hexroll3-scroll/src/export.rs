@@ -0,0 +1,166 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::io::{BufRead, Write};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::repository::*;
+
+/// The format name found in the header of every export.
+pub const EXPORT_FORMAT: &str = "hexroll3-sandbox";
+
+/// The version of the export format, bumped on incompatible changes.
+pub const EXPORT_FORMAT_VERSION: u64 = 1;
+
+/// The first line of an export, describing the sandbox exported.
+///
+/// An export is a JSON Lines file starting with this header, followed by
+/// one line for every entry of the repository, entities and frames alike,
+/// sorted by uid so exports of similar sandboxes can be diffed:
+///
+/// ```text
+/// {"format":"hexroll3-sandbox","format_version":1,"generator":"hexroll3-scroll 0.1.1","scroll_version":2,"seed":null,"sid":"Ab12Cd34","entries":2}
+/// {"uid":"Ab12Cd34","value":{"uid":"Ab12Cd34","class":"main","Version":2}}
+/// {"uid":"Ab12Cd34_frame","value":{"$collections":{}}}
+/// ```
+///
+/// The metadata of the sandbox is exported in the header, and restored when
+/// importing. Secondary and search indexes are not exported, as they are
+/// rebuilt when importing, and neither are snapshots.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExportHeader {
+    pub format: String,
+    pub format_version: u64,
+    /// The name and version of the crate that exported the sandbox.
+    pub generator: String,
+    /// The `Version` of the sandbox root entity, set by scrolls.
+    pub scroll_version: Option<serde_json::Value>,
+    /// The seed the sandbox was created with, if it was seeded.
+    pub seed: Option<u64>,
+    /// The uid of the sandbox root entity.
+    pub sid: Option<String>,
+    /// The number of entries following the header.
+    pub entries: usize,
+    /// The metadata rows of the sandbox (see `metadata::SandboxMetadata`),
+    /// other than the indexed attributes.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ExportEntry {
+    uid: String,
+    value: serde_json::Value,
+}
+
+/// Export every entry of a repository as JSON Lines (see `ExportHeader`).
+pub fn export<T: ReadOnlyLoader, W: Write>(tx: &T, mut writer: W) -> Result<ExportHeader> {
+    let mut uids = tx.uids()?;
+    uids.sort();
+    let sid = tx
+        .retrieve("root")
+        .ok()
+        .and_then(|root| root.value.as_str().map(str::to_string));
+    let mut metadata = tx.metadata()?;
+    metadata.remove(INDEXED_ATTRIBUTES);
+    let header = ExportHeader {
+        format: EXPORT_FORMAT.to_string(),
+        format_version: EXPORT_FORMAT_VERSION,
+        generator: crate::metadata::generator(),
+        scroll_version: sid
+            .as_ref()
+            .and_then(|sid| tx.retrieve(sid).ok())
+            .map(|root| root.value["Version"].clone())
+            .filter(|version| !version.is_null()),
+        seed: tx
+            .retrieve("$seed")
+            .ok()
+            .and_then(|seed| seed.value.as_u64()),
+        sid,
+        entries: uids.len(),
+        metadata,
+    };
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+    for uid in uids {
+        let value = tx.retrieve(&uid)?.value;
+        serde_json::to_writer(&mut writer, &ExportEntry { uid, value })?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(header)
+}
+
+/// Import an export into an empty repository, returning its header.
+///
+/// Every entry is stored as exported, so exporting the imported repository
+/// again results in the same export.
+pub fn import<R: BufRead>(tx: &mut ReadWriteTransaction, reader: R) -> Result<ExportHeader> {
+    if !tx.uids()?.is_empty() {
+        return Err(anyhow!(
+            "Sandboxes can only be imported into an empty repository"
+        ));
+    }
+    let mut lines = reader.lines();
+    let header: ExportHeader = match lines.next() {
+        Some(line) => {
+            serde_json::from_str(&line?).map_err(|e| anyhow!("Invalid export header: {}", e))?
+        }
+        None => return Err(anyhow!("Export is empty")),
+    };
+    if header.format != EXPORT_FORMAT {
+        return Err(anyhow!("Unknown export format {}", header.format));
+    }
+    if header.format_version > EXPORT_FORMAT_VERSION {
+        return Err(anyhow!(
+            "Export format version {} is newer than the supported version {}",
+            header.format_version,
+            EXPORT_FORMAT_VERSION
+        ));
+    }
+    let mut entries = 0;
+    for (number, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: ExportEntry = serde_json::from_str(&line)
+            .map_err(|e| anyhow!("Invalid export entry on line {}: {}", number + 2, e))?;
+        tx.store(&entry.uid, &entry.value)?;
+        entries += 1;
+    }
+    if entries != header.entries {
+        return Err(anyhow!(
+            "Export is incomplete, holding {} of {} entries",
+            entries,
+            header.entries
+        ));
+    }
+    for (key, value) in header.metadata.iter() {
+        tx.store_metadata(key, value)?;
+    }
+    Ok(header)
+}
@@ -22,9 +22,13 @@
 // license. Please contact ithai at pendicepaper.com
 // for more information about commercial licensing terms.
 */
+use std::cmp::Ordering;
+
 use crate::instance::*;
 use crate::repository::*;
-use crate::semantics::Class;
+use crate::semantics::{
+    Class, CollectionSpecifier, FilterOperator, Selection, SelectionFilter, SelectionStrategy,
+};
 use anyhow::{Ok, Result};
 
 /// Creates a new entity frame and subscribes it to the classes it collects.
@@ -47,7 +51,7 @@ pub fn create_entity_frame(
 ) -> Result<()> {
     let mut frame = Frame::init(tx, uid, parent_uid)?;
     for spec in class.collects.iter() {
-        subscribe(&mut frame, &spec.class_name);
+        subscribe(&mut frame, spec);
     }
     let frame_uid = frame.uid;
     tx.save(&frame_uid)
@@ -104,31 +108,190 @@ pub fn collect(
     uid: &str,
     class_name: &str,
 ) -> anyhow::Result<()> {
+    let hierarchy = &instance.sandbox.classes[class_name].hierarchy;
     let mut frame_owner_uid: String = parent_uid.to_string();
     while frame_owner_uid != "root" {
+        let frame_uid = format!("{}_frame", frame_owner_uid);
+        let keys = matching_collections(tx, &frame_uid, uid, hierarchy)?;
         let parent_owner_uid = {
-            let frame = tx
-                .load(&format!("{}_frame", frame_owner_uid))
+            let frame = tx.load(&frame_uid).unwrap().as_frame();
+            for key in keys {
+                frame.obj["$collections"]["$unused"][&key]
+                    .as_array_mut()
+                    .unwrap()
+                    .push(serde_json::to_value(uid).unwrap());
+            }
+            frame.obj["$parent"].clone()
+        };
+        tx.save(&frame_uid)?;
+        frame_owner_uid = parent_owner_uid.as_str().unwrap().to_string();
+    }
+    Ok(())
+}
+
+/// Re-evaluate the membership of a collected entity in the filtered
+/// collections of its frames hierarchy.
+///
+/// Entities that no longer match the criteria of a collection are removed
+/// from it, and entities that now match are added to it, as long as the
+/// collection is not full.
+///
+/// Used when the attributes of a collected entity change after it was
+/// collected, for example when it is injected with new attribute values.
+///
+/// # Arguments
+///
+/// * `instance` - A reference to the `SandboxBuilder` instance.
+/// * `tx` - A mutable read/write transaction to load and save frames.
+/// * `uid` - Uid of the collected entity.
+pub fn recollect(
+    instance: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+) -> anyhow::Result<()> {
+    let (class_name, parent_uid) = {
+        let entity = tx.retrieve(uid)?;
+        (
+            entity.value["class"].as_str().unwrap().to_string(),
+            entity.value["parent_uid"].as_str().unwrap().to_string(),
+        )
+    };
+    let hierarchy = &instance.sandbox.classes[&class_name].hierarchy;
+    let collected = serde_json::Value::from(uid);
+    let mut frame_owner_uid = parent_uid;
+    while frame_owner_uid != "root" {
+        let frame_uid = format!("{}_frame", frame_owner_uid);
+        let criteria = tx.load(&frame_uid)?["$collections"]["$criteria"].clone();
+        let mut changes = vec![];
+        for (key, criterion) in criteria.as_object().into_iter().flatten() {
+            if !hierarchy.iter().any(|c| criterion["class"] == *c) {
+                continue;
+            }
+            let (is_unused, is_used, size) = {
+                let collections = &tx.load(&frame_uid)?["$collections"];
+                let unused = collections["$unused"][key].as_array().unwrap();
+                let used = collections["$used"][key].as_array().unwrap();
+                (
+                    unused.contains(&collected),
+                    used.contains(&collected),
+                    unused.len() + used.len(),
+                )
+            };
+            let matches = criterion_matches(tx, criterion, uid)?;
+            if (is_unused || is_used) && !matches {
+                changes.push((key.clone(), false));
+            } else if !(is_unused || is_used)
+                && matches
+                && criterion["limit"]
+                    .as_u64()
+                    .is_none_or(|limit| (size as u64) < limit)
+            {
+                changes.push((key.clone(), true));
+            }
+        }
+        if changes.is_empty() {
+            frame_owner_uid = tx.load(&frame_uid)?["$parent"]
+                .as_str()
                 .unwrap()
-                .as_frame();
-            for parent in instance.sandbox.classes[class_name].hierarchy.iter() {
-                let unused = &mut frame.obj["$collections"]["$unused"];
-                if unused.as_object().unwrap().contains_key(parent) {
-                    unused[parent]
+                .to_string();
+            continue;
+        }
+        let parent_owner_uid = {
+            let frame = tx.load(&frame_uid)?.as_frame();
+            for (key, add) in changes {
+                if add {
+                    frame.obj["$collections"]["$unused"][&key]
                         .as_array_mut()
                         .unwrap()
-                        .push(serde_json::to_value(uid).unwrap());
-                    break;
+                        .push(collected.clone());
+                } else {
+                    for state in ["$unused", "$used"] {
+                        frame.obj["$collections"][state][&key]
+                            .as_array_mut()
+                            .unwrap()
+                            .retain(|v| *v != collected);
+                    }
                 }
             }
             frame.obj["$parent"].clone()
         };
-        tx.save(&format!("{}_frame", frame_owner_uid))?;
+        tx.save(&frame_uid)?;
         frame_owner_uid = parent_owner_uid.as_str().unwrap().to_string();
     }
     Ok(())
 }
 
+/// The keys of the collections in a frame an entity should be collected
+/// into: the first collection matching the entity class hierarchy and any
+/// filtered collection by attribute whose criteria the entity meets.
+fn matching_collections(
+    tx: &mut ReadWriteTransaction,
+    frame_uid: &str,
+    uid: &str,
+    hierarchy: &[String],
+) -> Result<Vec<String>> {
+    let frame = tx.load(frame_uid)?;
+    if frame["$collections"]["$criteria"].is_null() {
+        let unused = frame["$collections"]["$unused"].as_object().unwrap();
+        return Ok(hierarchy
+            .iter()
+            .find(|class_name| unused.contains_key(*class_name))
+            .cloned()
+            .into_iter()
+            .collect());
+    }
+    let collections = frame["$collections"].clone();
+    let criteria = &collections["$criteria"];
+    let is_full = |key: &str| {
+        criteria[key]["limit"].as_u64().is_some_and(|limit| {
+            let size = collections["$unused"][key].as_array().unwrap().len()
+                + collections["$used"][key].as_array().unwrap().len();
+            size as u64 >= limit
+        })
+    };
+    let mut keys = vec![];
+    for class_name in hierarchy.iter() {
+        if collections["$unused"].get(class_name).is_none() {
+            continue;
+        }
+        let criterion = &criteria[class_name];
+        if criterion.is_null() || (!is_full(class_name) && criterion_matches(tx, criterion, uid)?) {
+            keys.push(class_name.clone());
+            break;
+        }
+    }
+    for (key, criterion) in criteria.as_object().into_iter().flatten() {
+        if criterion["class"] == *key || !hierarchy.iter().any(|c| criterion["class"] == *c) {
+            continue;
+        }
+        if !is_full(key) && criterion_matches(tx, criterion, uid)? {
+            keys.push(key.clone());
+        }
+    }
+    Ok(keys)
+}
+
+/// Whether an entity meets the filters of a collection criterion.
+///
+/// Criteria can depend on attributes changed after an entity was collected,
+/// such as rerolled attributes or attributes of other entities in paths like
+/// `owner.alive`, so collected entities are checked again when selected or
+/// rendered.
+pub fn criterion_matches<T: ReadOnlyLoader>(
+    tx: &T,
+    criterion: &serde_json::Value,
+    uid: &str,
+) -> Result<bool> {
+    if criterion.is_null() {
+        return Ok(true);
+    }
+    let filters: Vec<SelectionFilter> = serde_json::from_value(criterion["filters"].clone())?;
+    let entity = tx.retrieve(uid)?.value;
+    Ok(filters
+        .iter()
+        .all(|filter| filter_matches(tx, filter, &entity)))
+}
+
 /// Remove an entity from any collections in its frames hierarchy.
 ///
 /// This function traverses the frames hierarchy of an entity to remove
@@ -154,6 +317,7 @@ pub fn withdraw(
     origin_owner_uid: &str,
     class_name: &str,
 ) -> anyhow::Result<()> {
+    let hierarchy = &instance.sandbox.classes[class_name].hierarchy;
     let mut frame_owner_uid: String = origin_owner_uid.to_string();
     while frame_owner_uid != "root" {
         let parent_owner_uid = {
@@ -161,22 +325,27 @@ pub fn withdraw(
                 .load(&format!("{}_frame", frame_owner_uid))
                 .unwrap()
                 .as_frame();
-            for parent in instance.sandbox.classes[class_name].hierarchy.iter() {
-                let unused = &mut frame.obj["$collections"]["$unused"];
-                if unused.as_object().unwrap().contains_key(parent) {
-                    unused[parent]
-                        .as_array_mut()
-                        .unwrap()
-                        .retain(|v| v != origin_owner_uid);
+            let mut keys: Vec<String> = hierarchy.clone();
+            for (key, criterion) in frame.obj["$collections"]["$criteria"]
+                .as_object()
+                .into_iter()
+                .flatten()
+            {
+                if hierarchy.iter().any(|c| criterion["class"] == *c) {
+                    keys.push(key.clone());
                 }
             }
-            for parent in instance.sandbox.classes[class_name].hierarchy.iter() {
-                let used = &mut frame.obj["$collections"]["$unused"];
-                if used.as_object().unwrap().contains_key(parent) {
-                    used[parent]
-                        .as_array_mut()
-                        .unwrap()
-                        .retain(|v| v != origin_owner_uid);
+            // Used entities are withdrawn too, or frames would keep the uids
+            // of unrolled entities their users hold on to
+            for state in ["$unused", "$used"] {
+                let collection = &mut frame.obj["$collections"][state];
+                for key in keys.iter() {
+                    if collection.as_object().unwrap().contains_key(key) {
+                        collection[key]
+                            .as_array_mut()
+                            .unwrap()
+                            .retain(|v| v != origin_owner_uid);
+                    }
                 }
             }
             frame.obj["$parent"].clone()
@@ -210,43 +379,69 @@ pub fn use_collected(
     tx: &mut ReadWriteTransaction,
     origin_owner_uid: &str,
     class_name: &str,
+    selection: &Selection,
 ) -> Result<Option<String>> {
-    let mut ret: Option<String> = None;
+    let Some((frame_uid, candidates)) = nearest_collection(tx, origin_owner_uid, class_name)?
+    else {
+        return Ok(None);
+    };
+    let Some(selected_uid) =
+        select_collected(instance, tx, origin_owner_uid, candidates, selection)?
+    else {
+        return Ok(None);
+    };
+    {
+        let frame = tx.load(&frame_uid)?.as_frame();
+        let selected = serde_json::Value::from(selected_uid.as_str());
+        frame.obj["$collections"]["$unused"][class_name]
+            .as_array_mut()
+            .unwrap()
+            .retain(|uid| *uid != selected);
+        frame.obj["$collections"]["$used"][class_name]
+            .as_array_mut()
+            .unwrap()
+            .push(selected);
+    }
+    tx.save(&frame_uid)?;
+    Ok(Some(selected_uid))
+}
 
+/// Mark a specific collected entity as used, in the nearest frame of the
+/// given owner's frame hierarchy that holds it as unused.
+///
+/// Used when an entity was rolled as a fallback for a use command, to make
+/// it unavailable for other users as if it was selected by `use_collected`.
+pub fn use_entity(
+    tx: &mut ReadWriteTransaction,
+    origin_owner_uid: &str,
+    uid_to_use: &str,
+) -> Result<()> {
     let mut frame_owner_uid: String = origin_owner_uid.to_string();
-    while frame_owner_uid != "root" && ret.is_none() {
-        let parent_owner_uid = {
-            let frame = tx
-                .load(&format!("{}_frame", frame_owner_uid))
+    while frame_owner_uid != "root" {
+        let frame_uid = format!("{}_frame", frame_owner_uid);
+        let frame = tx.load(&frame_uid)?.as_frame();
+        let to_use = serde_json::Value::from(uid_to_use);
+        let class_name = frame.obj["$collections"]["$unused"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .find(|(_, uids)| uids.as_array().unwrap().contains(&to_use))
+            .map(|(class_name, _)| class_name.clone());
+        if let Some(class_name) = class_name {
+            frame.obj["$collections"]["$unused"][&class_name]
+                .as_array_mut()
                 .unwrap()
-                .as_frame();
-            let unused = &mut frame.obj["$collections"]["$unused"];
-            if unused.as_object().unwrap().contains_key(class_name) {
-                let unused_list = unused[class_name].as_array().unwrap();
-                if unused_list.is_empty() {
-                    return Ok(None);
-                }
-                let selected = instance
-                    .randomizer
-                    .in_range(0, unused_list.len() as i32 - 1);
-
-                let selected_uid = unused_list[selected as usize].clone();
-                unused[class_name]
-                    .as_array_mut()
-                    .unwrap()
-                    .remove(selected as usize);
-                frame.obj["$collections"]["$used"][class_name]
-                    .as_array_mut()
-                    .unwrap()
-                    .push(selected_uid.clone());
-                ret = Some(selected_uid.as_str().unwrap().to_string());
-            }
-            frame.obj["$parent"].clone()
-        };
-        tx.save(&format!("{}_frame", frame_owner_uid))?;
-        frame_owner_uid = parent_owner_uid.as_str().unwrap().to_string();
+                .retain(|uid| *uid != to_use);
+            frame.obj["$collections"]["$used"][&class_name]
+                .as_array_mut()
+                .unwrap()
+                .push(to_use);
+            tx.save(&frame_uid)?;
+            break;
+        }
+        frame_owner_uid = frame.obj["$parent"].as_str().unwrap().to_string();
     }
-    Ok(ret)
+    Ok(())
 }
 
 /// Recycle a used entity and make it available again.
@@ -271,10 +466,7 @@ pub fn recycle(
     let mut frame_owner_uid: String = origin_owner_uid.to_string();
     while frame_owner_uid != "root" {
         let parent_owner_uid = {
-            let frame = tx
-                .load(&format!("{}_frame", frame_owner_uid))
-                .unwrap()
-                .as_frame();
+            let frame = tx.load(&format!("{}_frame", frame_owner_uid))?.as_frame();
             let unused = &mut frame.obj["$collections"]["$unused"];
             if unused.as_object().unwrap().contains_key(class_name) {
                 unused[class_name]
@@ -315,36 +507,229 @@ pub fn pick_collected(
     tx: &mut ReadWriteTransaction,
     origin_owner_uid: &str,
     class_name: &str,
+    selection: &Selection,
 ) -> Result<Option<String>> {
-    let mut ret: Option<String> = None;
+    let Some((_, candidates)) = nearest_collection(tx, origin_owner_uid, class_name)? else {
+        return Ok(None);
+    };
+    select_collected(instance, tx, origin_owner_uid, candidates, selection)
+}
 
+/// Find the nearest frame in the frame hierarchy of the given owner that
+/// subscribes to the class, returning its uid and the uids of the unused
+/// entities it collected that still meet its criterion.
+fn nearest_collection(
+    tx: &mut ReadWriteTransaction,
+    origin_owner_uid: &str,
+    class_name: &str,
+) -> Result<Option<(String, Vec<String>)>> {
     let mut frame_owner_uid: String = origin_owner_uid.to_string();
     while frame_owner_uid != "root" {
-        let parent_owner_uid = {
-            let frame = tx
-                .load(&format!("{}_frame", frame_owner_uid))
-                .unwrap()
-                .as_frame();
-            let unused = &mut frame.obj["$collections"]["$unused"];
-            if unused.as_object().unwrap().contains_key(class_name) {
-                let unused_list = unused[class_name].as_array().unwrap();
-                if unused_list.is_empty() {
-                    return Ok(None);
+        let frame_uid = format!("{}_frame", frame_owner_uid);
+        let frame = tx.load(&frame_uid)?.as_frame();
+        if let Some(unused) = frame.obj["$collections"]["$unused"][class_name].as_array() {
+            let unused: Vec<String> = unused
+                .iter()
+                .map(|uid| uid.as_str().unwrap().to_string())
+                .collect();
+            let criterion = frame.obj["$collections"]["$criteria"][class_name].clone();
+            let mut candidates = vec![];
+            for uid in unused {
+                if criterion_matches(tx, &criterion, &uid)? {
+                    candidates.push(uid);
                 }
-                let selected = instance
-                    .randomizer
-                    .in_range(0, unused_list.len() as i32 - 1);
+            }
+            return Ok(Some((frame_uid, candidates)));
+        }
+        frame_owner_uid = frame.obj["$parent"].as_str().unwrap().to_string();
+    }
+    Ok(None)
+}
 
-                let selected_uid = unused_list[selected as usize].clone();
-                ret = Some(selected_uid.as_str().unwrap().to_string());
-                break;
+/// Select one of the candidate entities collected in a frame, using the
+/// selection strategy, after discarding candidates not matching its filters.
+fn select_collected(
+    instance: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    selector_uid: &str,
+    candidates: Vec<String>,
+    selection: &Selection,
+) -> Result<Option<String>> {
+    let mut candidates = candidates;
+    if !selection.filters.is_empty() {
+        candidates.retain(|uid| {
+            tx.retrieve(uid).is_ok_and(|candidate| {
+                selection
+                    .filters
+                    .iter()
+                    .all(|filter| filter_matches(tx, filter, &candidate.value))
+            })
+        });
+    }
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+    let choose_any = |candidates: &[String]| {
+        let selected = instance.randomizer.in_range(0, candidates.len() as i32 - 1);
+        candidates[selected as usize].clone()
+    };
+    let selected = match &selection.strategy {
+        SelectionStrategy::Random => choose_any(&candidates),
+        SelectionStrategy::Nearest => {
+            let selector_lineage = lineage(tx, selector_uid)?;
+            let distances = candidates
+                .iter()
+                .map(|uid| Ok(distance(&selector_lineage, &lineage(tx, uid)?)))
+                .collect::<Result<Vec<usize>>>()?;
+            choose_any(&with_least(&candidates, &distances))
+        }
+        SelectionStrategy::LeastPicked => {
+            let picks = last_picks(tx, &candidates)?;
+            choose_any(&with_least(&candidates, &picks))
+        }
+        SelectionStrategy::RoundRobin => {
+            // Candidates are kept in the order they were collected
+            let picks = last_picks(tx, &candidates)?;
+            with_least(&candidates, &picks)[0].clone()
+        }
+        SelectionStrategy::Weighted(attr) => {
+            let weights = candidates
+                .iter()
+                .map(|uid| Ok(tx.retrieve(uid)?.value[attr].as_f64().unwrap_or(0.0)))
+                .collect::<Result<Vec<f64>>>()?;
+            match instance.randomizer.weighted(&weights) {
+                Some(selected) => candidates[selected].clone(),
+                None => choose_any(&candidates),
             }
-            frame.obj["$parent"].clone()
+        }
+    };
+    Ok(Some(selected))
+}
+
+/// Whether an entity meets a filter, such as a selection filter, a
+/// collection criterion or a class predicate.
+pub fn filter_matches<T: ReadOnlyLoader>(
+    tx: &T,
+    filter: &SelectionFilter,
+    candidate: &serde_json::Value,
+) -> bool {
+    let value = &filter_value(tx, candidate, &filter.attr);
+    // Arrays of child or selected entities are compared by their length
+    let measure = match value {
+        serde_json::Value::Array(items) => Some(items.len() as f64),
+        value => value.as_f64(),
+    };
+    let compare = || match (measure, filter.value.as_f64()) {
+        (Some(a), Some(b)) => a.partial_cmp(&b),
+        _ => None,
+    };
+    match filter.operator {
+        FilterOperator::Truthy => match value {
+            serde_json::Value::Null => false,
+            serde_json::Value::Bool(b) => *b,
+            serde_json::Value::Number(n) => n.as_f64() != Some(0.0),
+            serde_json::Value::String(s) => !s.is_empty(),
+            serde_json::Value::Array(a) => !a.is_empty(),
+            serde_json::Value::Object(_) => true,
+        },
+        FilterOperator::Equal => *value == filter.value || compare() == Some(Ordering::Equal),
+        FilterOperator::NotEqual => !(*value == filter.value || compare() == Some(Ordering::Equal)),
+        FilterOperator::Greater => compare() == Some(Ordering::Greater),
+        FilterOperator::GreaterOrEqual => {
+            matches!(compare(), Some(Ordering::Greater | Ordering::Equal))
+        }
+        FilterOperator::Less => compare() == Some(Ordering::Less),
+        FilterOperator::LessOrEqual => matches!(compare(), Some(Ordering::Less | Ordering::Equal)),
+    }
+}
+
+/// The value of a filter attribute path, following child or referenced
+/// entity uids for every path part but the last.
+pub fn filter_value<T: ReadOnlyLoader>(
+    tx: &T,
+    candidate: &serde_json::Value,
+    attr: &str,
+) -> serde_json::Value {
+    let mut parts = attr.split('.');
+    let mut value = candidate[parts.next().unwrap()].clone();
+    for part in parts {
+        let uid = match &value {
+            serde_json::Value::Array(uids) => uids.first().and_then(|uid| uid.as_str()),
+            other => other.as_str(),
         };
-        tx.save(&format!("{}_frame", frame_owner_uid))?;
-        frame_owner_uid = parent_owner_uid.as_str().unwrap().to_string();
+        value = match uid.and_then(|uid| tx.retrieve(uid).ok()) {
+            Some(entity) => entity.value[part].clone(),
+            None => serde_json::Value::Null,
+        };
+    }
+    value
+}
+
+/// The uids of an entity and all of its ancestors, from the entity up.
+fn lineage(tx: &mut ReadWriteTransaction, uid: &str) -> Result<Vec<String>> {
+    let mut lineage = vec![uid.to_string()];
+    let mut parent_uid = tx.retrieve(uid)?.value["parent_uid"].clone();
+    while let Some(uid) = parent_uid.as_str().filter(|uid| *uid != "root") {
+        lineage.push(uid.to_string());
+        parent_uid = tx.retrieve(uid)?.value["parent_uid"].clone();
     }
-    Ok(ret)
+    Ok(lineage)
+}
+
+/// The number of steps between two entities in the entity hierarchy.
+fn distance(a: &[String], b: &[String]) -> usize {
+    a.iter()
+        .enumerate()
+        .find_map(|(steps_a, uid)| {
+            b.iter()
+                .position(|other| other == uid)
+                .map(|steps_b| steps_a + steps_b)
+        })
+        .unwrap_or(a.len() + b.len())
+}
+
+/// The sequence number of the last pick of every candidate, or 0 for
+/// candidates never picked.
+///
+/// Picks are recorded in the `$users` specs of picked entities (see
+/// `next_pick`), so unrolling a user also forgets its pick.
+fn last_picks(tx: &mut ReadWriteTransaction, candidates: &[String]) -> Result<Vec<u64>> {
+    candidates
+        .iter()
+        .map(|uid| {
+            Ok(tx.retrieve(uid)?.value["$users"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|user| user["picked"].as_u64())
+                .max()
+                .unwrap_or(0))
+        })
+        .collect()
+}
+
+/// Take the next sequence number for recording an entity was used or
+/// picked, to let selections prefer the least recently picked entities.
+pub fn next_pick(tx: &mut ReadWriteTransaction) -> Result<u64> {
+    let last = tx
+        .retrieve("$picks")
+        .map_or(0, |picks| picks.value.as_u64().unwrap_or(0));
+    let next = last + 1;
+    tx.store("$picks", &serde_json::json!(next))?;
+    Ok(next)
+}
+
+/// The candidates having the least of the given measures.
+fn with_least<T: Ord + Copy>(candidates: &[String], measures: &[T]) -> Vec<String> {
+    let Some(least) = measures.iter().min().copied() else {
+        return vec![];
+    };
+    candidates
+        .iter()
+        .zip(measures)
+        .filter(|(_, measure)| **measure == least)
+        .map(|(uid, _)| uid.clone())
+        .collect()
 }
 
 /// Every entity has a Frame record stored in the format of:
@@ -394,9 +779,21 @@ impl<'b> FrameConvertor<'b> for serde_json::Value {
     }
 }
 
-/// Subscribe the frame to a class
-fn subscribe(frame: &mut Frame, class_name: &str) {
+/// Subscribe the frame to a class, keeping the criteria of filtered
+/// collections in the frame `$criteria`.
+fn subscribe(frame: &mut Frame, spec: &CollectionSpecifier) {
+    let key = spec.key();
     let collections = &mut frame.obj["$collections"];
-    collections["$unused"][class_name] = serde_json::json!([]);
-    collections["$used"][class_name] = serde_json::json!([]);
+    collections["$unused"][&key] = serde_json::json!([]);
+    collections["$used"][&key] = serde_json::json!([]);
+    if spec.is_filtered() {
+        if !collections["$criteria"].is_object() {
+            collections["$criteria"] = serde_json::json!({});
+        }
+        collections["$criteria"][&key] = serde_json::json!({
+            "class": spec.class_name,
+            "filters": spec.filters,
+            "limit": spec.limit,
+        });
+    }
 }
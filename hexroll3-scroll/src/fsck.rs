@@ -0,0 +1,337 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::frame::create_entity_frame;
+use crate::instance::*;
+use crate::references::{references_from, ReferenceKind};
+use crate::repository::*;
+
+/// An inconsistency found in a sandbox repository.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Issue {
+    /// The `root` entry names a missing entity.
+    MissingRoot(String),
+    /// An entity attribute, or its `parent_uid`, `$parent` or `$users`
+    /// entries, names a missing entity.
+    DanglingReference {
+        uid: String,
+        attr: String,
+        missing: String,
+    },
+    /// An entity has no frame.
+    MissingFrame(String),
+    /// A frame belongs to a missing entity.
+    OrphanFrame(String),
+    /// A frame collection, context or constraint registry entry names a
+    /// missing entity.
+    StaleFrameEntry {
+        frame: String,
+        entry: String,
+        missing: String,
+    },
+    /// An entity cannot be reached from the root entity by following the
+    /// references of entities, other than collections.
+    Unreachable(String),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingRoot(uid) => write!(f, "root entity {} is missing", uid),
+            Issue::DanglingReference { uid, attr, missing } => {
+                write!(f, "{}.{} references missing entity {}", uid, attr, missing)
+            }
+            Issue::MissingFrame(uid) => write!(f, "entity {} has no frame", uid),
+            Issue::OrphanFrame(frame) => write!(f, "frame {} has no entity", frame),
+            Issue::StaleFrameEntry {
+                frame,
+                entry,
+                missing,
+            } => write!(f, "{} {} holds missing entity {}", frame, entry, missing),
+            Issue::Unreachable(uid) => write!(f, "entity {} is unreachable from root", uid),
+        }
+    }
+}
+
+/// The result of checking, and possibly repairing, a sandbox repository.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The number of entities checked.
+    pub entities: usize,
+    /// The number of frames checked.
+    pub frames: usize,
+    /// Issues found, and left unrepaired when repairing.
+    pub issues: Vec<Issue>,
+    /// Issues fixed when repairing.
+    pub repaired: Vec<Issue>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Walk the whole repository and report every inconsistency between
+/// entities, their frames and the references they hold.
+///
+/// Entities are checked using the classes of the instance, so the scrolls
+/// used to create the sandbox should be loaded.
+pub fn check<T: ReadOnlyLoader>(instance: &SandboxInstance, tx: &T) -> Result<Report> {
+    let mut entities = vec![];
+    let mut frames = HashSet::new();
+    for uid in tx.uids()? {
+        if let Some(owner) = uid.strip_suffix("_frame") {
+            frames.insert(owner.to_string());
+        } else if uid != "root" && !uid.starts_with('$') {
+            entities.push(uid);
+        }
+    }
+    entities.sort();
+    let existing: HashSet<&str> = entities.iter().map(|uid| uid.as_str()).collect();
+    let mut report = Report {
+        entities: entities.len(),
+        frames: frames.len(),
+        ..Default::default()
+    };
+
+    let mut referenced: HashMap<&str, Vec<String>> = HashMap::new();
+    for uid in entities.iter() {
+        let entity = tx.retrieve(uid)?.value;
+        let mut dangling = |attr: &str, missing: &str| {
+            report.issues.push(Issue::DanglingReference {
+                uid: uid.clone(),
+                attr: attr.to_string(),
+                missing: missing.to_string(),
+            })
+        };
+        if let Some(parent_uid) = entity["parent_uid"].as_str() {
+            if parent_uid != "root" && !existing.contains(parent_uid) {
+                dangling("parent_uid", parent_uid);
+            }
+        }
+        if let Some(parent_uid) = entity["$parent"]["uid"].as_str() {
+            if !existing.contains(parent_uid) {
+                dangling("$parent", parent_uid);
+            }
+        }
+        for user in entity["$users"].as_array().into_iter().flatten() {
+            if let Some(user_uid) = user["uid"].as_str() {
+                if !existing.contains(user_uid) {
+                    dangling("$users", user_uid);
+                }
+            }
+        }
+        for reference in references_from(instance, tx, uid)? {
+            if reference.kind == ReferenceKind::Collection {
+                continue;
+            }
+            if !existing.contains(reference.to.as_str()) {
+                dangling(&reference.attr, &reference.to);
+            } else {
+                referenced.entry(uid).or_default().push(reference.to);
+            }
+        }
+        if !frames.contains(uid) {
+            report.issues.push(Issue::MissingFrame(uid.clone()));
+        }
+    }
+
+    let mut owners: Vec<&String> = frames.iter().collect();
+    owners.sort();
+    for owner in owners {
+        let frame_uid = format!("{}_frame", owner);
+        if !existing.contains(owner.as_str()) {
+            report.issues.push(Issue::OrphanFrame(frame_uid));
+            continue;
+        }
+        let frame = tx.retrieve(&frame_uid)?.value;
+        let mut entries: Vec<(String, &str)> = vec![];
+        for state in ["$unused", "$used"] {
+            for (key, uids) in frame["$collections"][state]
+                .as_object()
+                .into_iter()
+                .flatten()
+            {
+                for uid in uids.as_array().into_iter().flatten() {
+                    entries.extend(
+                        uid.as_str()
+                            .map(|uid| (format!("$collections.{}.{}", state, key), uid)),
+                    );
+                }
+            }
+        }
+        for context in frame["$contexts"].as_array().into_iter().flatten() {
+            entries.extend(
+                context["uid"]
+                    .as_str()
+                    .map(|uid| ("$contexts".to_string(), uid)),
+            );
+        }
+        for (key, taken) in frame["$registry"].as_object().into_iter().flatten() {
+            for uid in taken.as_object().into_iter().flatten().map(|(uid, _)| uid) {
+                entries.push((format!("$registry.{}", key), uid));
+            }
+        }
+        for (entry, uid) in entries {
+            if !existing.contains(uid) {
+                report.issues.push(Issue::StaleFrameEntry {
+                    frame: frame_uid.clone(),
+                    entry,
+                    missing: uid.to_string(),
+                });
+            }
+        }
+    }
+
+    // Sandboxes created without a root entry, for example ones rolled
+    // directly using `roll`, have no root to reach entities from.
+    if let Some(sid) = tx
+        .retrieve("root")
+        .ok()
+        .and_then(|root| root.value.as_str().map(String::from))
+    {
+        if existing.contains(sid.as_str()) {
+            let mut reachable = HashSet::from([sid.clone()]);
+            let mut queue = VecDeque::from([sid]);
+            while let Some(uid) = queue.pop_front() {
+                for next in referenced.get(uid.as_str()).into_iter().flatten() {
+                    if reachable.insert(next.clone()) {
+                        queue.push_back(next.clone());
+                    }
+                }
+            }
+            for uid in entities.iter().filter(|uid| !reachable.contains(*uid)) {
+                report.issues.push(Issue::Unreachable(uid.clone()));
+            }
+        } else {
+            report.issues.push(Issue::MissingRoot(sid));
+        }
+    }
+    Ok(report)
+}
+
+/// Check the repository and fix the issues that can be fixed safely:
+/// dangling references and stale frame entries are removed, missing frames
+/// are recreated, with empty collections, and orphan frames are removed.
+///
+/// Unreachable entities are left for garbage collection, and entities
+/// with a missing parent, or a missing root, are only reported.
+pub fn repair(instance: &SandboxInstance, tx: &mut ReadWriteTransaction) -> Result<Report> {
+    let mut report = check(instance, tx)?;
+    for issue in std::mem::take(&mut report.issues) {
+        if repair_issue(instance, tx, &issue)? {
+            report.repaired.push(issue);
+        } else {
+            report.issues.push(issue);
+        }
+    }
+    Ok(report)
+}
+
+fn repair_issue(
+    instance: &SandboxInstance,
+    tx: &mut ReadWriteTransaction,
+    issue: &Issue,
+) -> Result<bool> {
+    match issue {
+        Issue::DanglingReference { uid, attr, missing } => {
+            if attr == "parent_uid" || attr == "$parent" {
+                return Ok(false);
+            }
+            let entity = tx.load(uid)?;
+            let missing = serde_json::Value::from(missing.as_str());
+            if attr == "$users" {
+                if let Some(users) = entity["$users"].as_array_mut() {
+                    users.retain(|user| user["uid"] != missing);
+                }
+            } else if let Some(uids) = entity[attr].as_array_mut() {
+                uids.retain(|uid| *uid != missing);
+                if let Some(fallbacks) = entity
+                    .get_mut("$fallbacks")
+                    .and_then(|fallbacks| fallbacks.get_mut(attr))
+                    .and_then(|fallbacks| fallbacks.as_array_mut())
+                {
+                    fallbacks.retain(|uid| *uid != missing);
+                }
+            } else if entity[attr]["type"] == "pointer" {
+                entity[attr] = serde_json::Value::from(false);
+            } else if let Some(spec) = entity[attr]["spec"].as_object_mut() {
+                spec.swap_remove("uid");
+            }
+            tx.save(uid)?;
+            Ok(true)
+        }
+        Issue::MissingFrame(uid) => {
+            let entity = tx.load(uid)?;
+            let parent_uid = entity["parent_uid"].as_str().unwrap_or("root").to_string();
+            let Some(class) = entity["class"]
+                .as_str()
+                .and_then(|class_name| instance.classes.get(class_name))
+            else {
+                return Ok(false);
+            };
+            create_entity_frame(tx, &parent_uid, uid, class)?;
+            Ok(true)
+        }
+        Issue::OrphanFrame(frame_uid) => {
+            tx.remove(frame_uid)?;
+            Ok(true)
+        }
+        Issue::StaleFrameEntry {
+            frame: frame_uid,
+            entry,
+            missing,
+        } => {
+            let frame = tx.load(frame_uid)?;
+            let missing_uid = serde_json::Value::from(missing.as_str());
+            if let Some(key) = entry.strip_prefix("$registry.") {
+                if let Some(taken) = frame["$registry"][key].as_object_mut() {
+                    taken.swap_remove(missing);
+                }
+            } else if entry == "$contexts" {
+                if let Some(contexts) = frame["$contexts"].as_array_mut() {
+                    contexts.retain(|context| context["uid"] != missing_uid);
+                }
+            } else if let Some((state, key)) = entry
+                .strip_prefix("$collections.")
+                .and_then(|path| path.split_once('.'))
+            {
+                if let Some(uids) = frame["$collections"][state][key].as_array_mut() {
+                    uids.retain(|uid| *uid != missing_uid);
+                }
+            } else {
+                return Ok(false);
+            }
+            tx.save(frame_uid)?;
+            Ok(true)
+        }
+        Issue::MissingRoot(_) | Issue::Unreachable(_) => Ok(false),
+    }
+}
@@ -0,0 +1,131 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::HashSet;
+
+use anyhow::Result;
+
+use crate::frame::recycle;
+use crate::fsck::{self, Issue};
+use crate::instance::*;
+use crate::references::{references_from, ReferenceKind};
+use crate::repository::*;
+
+/// The result of collecting garbage in a sandbox repository.
+#[derive(Clone, Debug, Default)]
+pub struct GarbageReport {
+    /// Unreachable entities deleted, along with their frames.
+    pub entities: Vec<String>,
+    /// Frames deleted because their entities were missing.
+    pub frames: Vec<String>,
+    /// References to deleted entities removed from the remaining entities
+    /// and frames.
+    pub references: usize,
+    /// Whether the repository file was compacted.
+    pub compacted: bool,
+    /// How much smaller the repository file became.
+    pub bytes_reclaimed: u64,
+}
+
+/// Delete every entity that cannot be reached from the root entity, as
+/// well as frames with no entity, and remove any reference left to them.
+///
+/// Entities used by deleted entities are recycled into the collections
+/// they were taken from, as unrolling the deleted entities would do, so
+/// they can be used again.
+///
+/// Repositories with no root entry, or a missing root entity, are left
+/// untouched, since everything in them would be considered garbage.
+pub fn collect_garbage(
+    instance: &SandboxInstance,
+    tx: &mut ReadWriteTransaction,
+) -> Result<GarbageReport> {
+    let mut report = GarbageReport::default();
+    let issues = fsck::check(instance, tx)?.issues;
+    if tx.retrieve("root").is_err() || issues.iter().any(|i| matches!(i, Issue::MissingRoot(_))) {
+        return Ok(report);
+    }
+    let unreachable: Vec<&str> = issues
+        .iter()
+        .filter_map(|issue| match issue {
+            Issue::Unreachable(uid) => Some(uid.as_str()),
+            _ => None,
+        })
+        .collect();
+    let deleted: HashSet<&str> = unreachable.iter().copied().collect();
+    for uid in unreachable {
+        recycle_used(instance, tx, uid, &deleted)?;
+    }
+    for issue in issues {
+        match issue {
+            Issue::Unreachable(uid) => {
+                tx.remove(&uid)?;
+                tx.remove(&format!("{}_frame", uid))?;
+                report.entities.push(uid);
+            }
+            Issue::OrphanFrame(frame_uid) => {
+                tx.remove(&frame_uid)?;
+                report.frames.push(frame_uid);
+            }
+            _ => {}
+        }
+    }
+    report.references = fsck::repair(instance, tx)?
+        .repaired
+        .iter()
+        .filter(|issue| {
+            matches!(
+                issue,
+                Issue::DanglingReference { .. } | Issue::StaleFrameEntry { .. }
+            )
+        })
+        .count();
+    Ok(report)
+}
+
+/// Recycle the entities used by an entity about to be deleted, unless they
+/// are deleted as well.
+fn recycle_used(
+    instance: &SandboxInstance,
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+    deleted: &HashSet<&str>,
+) -> Result<()> {
+    // Recycling walks up the frames of the user's ancestors
+    if tx.retrieve(&format!("{}_frame", uid)).is_err() {
+        return Ok(());
+    }
+    for reference in references_from(instance, tx, uid)? {
+        if reference.kind != ReferenceKind::Use || deleted.contains(reference.to.as_str()) {
+            continue;
+        }
+        let Ok(used) = tx.retrieve(&reference.to) else {
+            continue;
+        };
+        if let Some(class_name) = used.value["class"].as_str() {
+            recycle(tx, uid, &reference.to, class_name)?;
+        }
+    }
+    Ok(())
+}
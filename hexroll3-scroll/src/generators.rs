@@ -24,9 +24,12 @@
 */
 use anyhow::{anyhow, Result};
 
+use crate::constraints::*;
 use crate::frame::*;
 use crate::instance::*;
+use crate::renderer::*;
 use crate::repository::*;
+use crate::search;
 use crate::semantics::*;
 
 /// Rolls an entity within the given parent, creating a new entity with a unique identifier,
@@ -59,9 +62,101 @@ pub fn roll(
     parent_uid: &str,
     injectors: Option<&Injectors>,
 ) -> Result<String> {
-    let class = resolve_actual_class_to_roll(builder, class_name)?;
+    roll_with(builder, tx, class_name, parent_uid, injectors, |_, _| {
+        Ok(true)
+    })
+}
+
+/// Roll an entity until it meets a predicate, as well as the `until`
+/// predicates of its class:
+///
+/// ```text
+/// Dungeon {
+///     [0..6 traps] @ Trap
+///     until traps >= 3
+/// }
+/// ```
+///
+/// Entities failing to meet the predicates are unrolled within the same
+/// transaction and rolled again, up to `Limits::max_redraws` times, after
+/// which an error is returned.
+///
+/// # Arguments
+///
+/// * `builder` - A reference to the sandbox builder holding the sandbox instance
+/// * `tx` - a read/write transaction.
+/// * `class_name` - The class name of the entity to roll.
+/// * `parent_uid` - The parent uid of the entity to roll.
+/// * `injectors` - Optional injectors that add attributes or override attributes in the entity.
+/// * `predicate` - A predicate given the transaction and the uid of the rolled entity.
+///
+/// # Returns
+///
+/// A `Result` containing the unique identifier of the newly created entity, or an error if the
+/// predicates could not be met.
+pub fn roll_with<P>(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    class_name: &str,
+    parent_uid: &str,
+    injectors: Option<&Injectors>,
+    predicate: P,
+) -> Result<String>
+where
+    P: Fn(&mut ReadWriteTransaction, &str) -> Result<bool>,
+{
+    let max_redraws = builder
+        .options
+        .limits
+        .max_redraws
+        .unwrap_or(DEFAULT_MAX_REDRAWS);
+    let mut redraws = 0;
+    loop {
+        let uid = roll_once(builder, tx, class_name, parent_uid, injectors)?;
+        let entity = tx.retrieve(&uid)?.value;
+        let class = &builder.sandbox.classes[entity["class"].as_str().unwrap()];
+        let unmet = class
+            .predicates
+            .iter()
+            .find(|filter| !filter_matches(tx, filter, &entity));
+        let unmet = match unmet {
+            Some(filter) => Some(filter.to_string()),
+            None if !predicate(tx, &uid)? => Some("the predicate".to_string()),
+            None => None,
+        };
+        let Some(unmet) = unmet else {
+            return Ok(uid);
+        };
+        unroll_entity(builder, tx, &uid, injectors)?;
+        if redraws == max_redraws {
+            return Err(anyhow!(
+                "Unable to roll {} meeting {} after {} re-draws",
+                class_name,
+                unmet,
+                redraws
+            ));
+        }
+        redraws += 1;
+    }
+}
+
+fn roll_once(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    class_name: &str,
+    parent_uid: &str,
+    injectors: Option<&Injectors>,
+) -> Result<String> {
+    let class = resolve_class(builder, tx, parent_uid, class_name, || {
+        resolve_actual_class_to_roll(builder, class_name)
+    })?;
+
+    builder
+        .progress
+        .begin_roll(&class.name, &builder.class_chain.borrow())?;
 
     let uid = builder.randomizer.uid();
+    let class_chain = builder.enter_class(&class.name);
 
     // Create the entity frame and subscribe to potential child entities
     create_entity_frame(tx, parent_uid, &uid, class)?;
@@ -77,11 +172,13 @@ pub fn roll(
     // Run the entity commands for injectors and attributes
     if let Some(prependers) = injectors {
         for injector in prependers.prependers.as_slice() {
+            builder.progress.check_cancelled()?;
             injector.inject(builder, tx, &uid, parent_uid)?;
         }
     }
 
     for (_, attr) in class.attrs.as_slice() {
+        builder.progress.check_cancelled()?;
         attr.cmd.apply(&mut Context::Rolling, builder, tx, &uid)?;
     }
 
@@ -91,8 +188,14 @@ pub fn roll(
         }
     }
 
+    enforce_unique_attributes(builder, tx, parent_uid, class, &uid)?;
+    register(builder, tx, parent_uid, class, &uid)?;
+
     // Save and return the uid
     tx.save(&uid)?;
+    drop(class_chain);
+    let depth = builder.class_chain.borrow().len();
+    builder.progress.rolled(&class.name, depth);
     Ok(uid)
 }
 
@@ -105,6 +208,7 @@ pub fn roll(
 /// - Clearing the entity's parent reference.
 /// - Deleting the entity and its associated frame from the transaction.
 /// - Re-applying entity modification commands for all users of the entity.
+/// - Updating the search documents of the entities affected.
 ///
 /// # Arguments
 /// * `builder` - A reference to the sandbox builder holding the sandbox instance
@@ -120,20 +224,35 @@ pub fn unroll(
     tx: &mut ReadWriteTransaction,
     uid: &str,
     injectors: Option<&Injectors>,
+) -> Result<String> {
+    let parent_uid = unroll_entity(builder, tx, uid, injectors)?;
+    search::update(builder.sandbox, tx)?;
+    Ok(parent_uid)
+}
+
+/// Unroll an entity as `unroll` does, leaving the search index to be
+/// updated once the whole operation unrolling it is done.
+pub(crate) fn unroll_entity(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+    injectors: Option<&Injectors>,
 ) -> Result<String> {
     let entity = tx.load(uid)?;
     let parent_spec = entity["$parent"].clone();
     let class_name = entity["class"].as_str().unwrap().to_string();
+    let entity_parent_uid = entity["parent_uid"].as_str().unwrap_or("root").to_string();
     let users = entity["$users"]
         .as_array()
         .cloned()
         .unwrap_or_else(Vec::new);
 
-    // Remove entity references from all frames
+    // Remove entity references from all frames and constraint registries
     withdraw(builder, tx, uid, &class_name)?;
+    let class = builder.sandbox.classes.get(&class_name).unwrap();
+    release(builder, tx, &entity_parent_uid, class, uid)?;
 
     // Undo injectors and attributes
-    let class = builder.sandbox.classes.get(&class_name).unwrap();
     if let Some(injs) = injectors {
         for injector in injs.appenders.as_slice() {
             injector.eject(builder, tx, uid, "")?;
@@ -148,7 +267,8 @@ pub fn unroll(
         }
     }
 
-    // Clear the $parent reference
+    // Clear the $parent reference, and the $fallbacks entry of entities
+    // rolled as a fallback for using or picking
     let parent_uid = if !parent_spec.is_null() {
         let parent_uid = parent_spec["uid"].as_str().unwrap();
         let parent_attr = parent_spec["attr"].as_str().unwrap();
@@ -157,6 +277,13 @@ pub fn unroll(
             .as_array_mut()
             .unwrap()
             .retain(|v| v != uid);
+        if let Some(fallbacks) = parent
+            .get_mut("$fallbacks")
+            .and_then(|fallbacks| fallbacks.get_mut(parent_attr))
+            .and_then(|fallbacks| fallbacks.as_array_mut())
+        {
+            fallbacks.retain(|v| v != uid);
+        }
         tx.save(parent_uid)?;
         parent_uid
     } else {
@@ -180,6 +307,12 @@ pub fn unroll(
                 .classes
                 .get(user["class"].as_str().unwrap())
                 .unwrap();
+            if !user_class.attrs.contains_key(user_attr) {
+                // Injected pointers have no command to re-apply
+                user[user_attr] = serde_json::Value::from(false);
+                tx.save(user_uid)?;
+                continue;
+            }
             match user[user_attr] {
                 serde_json::Value::Object(_) => {
                     user_class.attrs[user_attr].cmd.revert(
@@ -266,7 +399,8 @@ pub fn reroll(
         .cmd
         .apply(&mut ctx, builder, tx, &parent_uid)?;
 
-    unroll(builder, tx, uid, None)?;
+    unroll_entity(builder, tx, uid, None)?;
+    search::update(builder.sandbox, tx)?;
 
     if let Context::Rerolling(payload) = ctx {
         if let Some(new_uid) = payload.new_uid {
@@ -319,6 +453,7 @@ pub fn append(
     if let Context::Appending(payload) = ctx {
         if let Some(added_uid) = payload.appended_uid {
             tx.save(parent_uid)?;
+            search::update(builder.sandbox, tx)?;
             Ok(added_uid)
         } else {
             Err(anyhow!(
@@ -336,6 +471,218 @@ pub fn append(
     }
 }
 
+/// Roll several entities into a parent attribute concurrently, each on
+/// its own worker thread.
+///
+/// Every entity is rolled and collected in a detached transaction, using a
+/// randomizer seeded from the builder's randomizer. The detached transactions
+/// are then merged back in order, so seeded generation stays deterministic.
+/// An entity whose transaction conflicts with those merged before it, for
+/// example by using the same collected entity, or by taking the same
+/// `$picks` number when picking any entity, is rolled again in `tx`.
+///
+/// This is only suitable for classes whose entities do not depend on their
+/// siblings (see `BuilderOptions::concurrent_classes`), since every worker
+/// sees the sandbox as it was before any of them started.
+///
+/// # Arguments
+///
+/// * `builder` - A reference to the sandbox builder holding the sandbox instance
+/// * `tx` - A read/write transaction.
+/// * `class_names` - The class names of the entities to roll, one per entity.
+/// * `parent_uid` - The parent uid of the entities to roll.
+/// * `parent_attr` - The parent attribute that will hold the entities.
+/// * `injectors` - Optional injectors that add attributes or override attributes in the entities.
+///
+/// # Returns
+///
+/// A `Result` containing the uids of the rolled entities, in the order of `class_names`.
+pub fn roll_concurrently(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    class_names: &[&str],
+    parent_uid: &str,
+    parent_attr: &str,
+    injectors: Option<&Injectors>,
+) -> Result<Vec<String>> {
+    let detached = tx.detach()?;
+    let workers: Vec<SandboxBuilder> = class_names.iter().map(|_| builder.fork()).collect();
+
+    let results: Vec<Result<(String, DetachedTransaction)>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = class_names
+            .iter()
+            .zip(workers)
+            .map(|(class_name, worker)| {
+                let detached = detached.clone();
+                scope.spawn(move || {
+                    let mut worker_tx = ReadWriteTransaction::from_detached(detached);
+                    let uid = roll_child(
+                        &worker,
+                        &mut worker_tx,
+                        class_name,
+                        parent_uid,
+                        parent_attr,
+                        injectors,
+                    )?;
+                    Ok((uid, worker_tx.into_detached()?))
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("A worker thread has panicked while rolling"))?
+            })
+            .collect()
+    });
+
+    let mut uids = Vec::new();
+    for (class_name, result) in class_names.iter().zip(results) {
+        let (uid, detached) = result?;
+        if tx.can_merge(&detached)? {
+            tx.merge(detached)?;
+            uids.push(uid);
+        } else {
+            uids.push(roll_child(
+                builder,
+                tx,
+                class_name,
+                parent_uid,
+                parent_attr,
+                injectors,
+            )?);
+        }
+    }
+    Ok(uids)
+}
+
+/// Roll an entity held by a parent attribute and collect it.
+fn roll_child(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    class_name: &str,
+    parent_uid: &str,
+    parent_attr: &str,
+    injectors: Option<&Injectors>,
+) -> Result<String> {
+    let uid = roll(builder, tx, class_name, parent_uid, injectors)?;
+    {
+        let entity = tx.load(&uid)?;
+        entity["$parent"] = serde_json::json!({
+            "uid": parent_uid,
+            "attr": parent_attr,
+        });
+        tx.save(&uid)?;
+    }
+    collect(builder, tx, parent_uid, &uid, class_name)?;
+    Ok(uid)
+}
+
+/// An entity rolled by `oracle`, which is not stored in the sandbox.
+#[derive(Clone)]
+pub struct OracleRoll {
+    pub uid: String,
+    pub parent_uid: String,
+    /// The rendered entity, as returned by `render_entity`.
+    pub rendered: serde_json::Value,
+    /// The rendered entity HTML header and body.
+    pub html: (String, String),
+    /// Everything written when rolling the entity, used by `promote`.
+    pub writes: DetachedTransaction,
+}
+
+/// Roll an entity for a quick answer, for example an NPC name or a random
+/// encounter, without storing anything in the sandbox.
+///
+/// The entity is rolled in an ephemeral transaction as if it was a child of
+/// `parent_uid`, so it can use the context of its would-be ancestors, and
+/// is rendered right away. It can later be stored using `promote`.
+///
+/// # Arguments
+///
+/// * `builder` - A reference to the sandbox builder holding the sandbox instance
+/// * `class_name` - The class name of the entity to roll.
+/// * `parent_uid` - The uid of the entity to roll the entity as if in.
+///
+/// # Returns
+///
+/// A `Result` containing the rolled and rendered entity.
+pub fn oracle(builder: &SandboxBuilder, class_name: &str, parent_uid: &str) -> Result<OracleRoll> {
+    let mut tx = builder.sandbox.repo.ephemeral()?;
+    let uid = roll(builder, &mut tx, class_name, parent_uid, None)?;
+    let entity = tx.retrieve(&uid)?.value;
+    let rendered = render_entity(builder.sandbox, &tx, &entity, true)?;
+    let html = render_entity_html(builder.sandbox, &tx, &entity)?;
+    Ok(OracleRoll {
+        uid,
+        parent_uid: parent_uid.to_string(),
+        rendered,
+        html,
+        writes: tx.into_detached()?,
+    })
+}
+
+/// Store an entity rolled by `oracle` in the sandbox, appending it to an
+/// attribute of the entity it was rolled in.
+///
+/// # Arguments
+///
+/// * `builder` - A reference to the sandbox builder holding the sandbox instance
+/// * `tx` - A read/write transaction.
+/// * `oracle_roll` - The entity rolled by `oracle`.
+/// * `attr_name` - The parent attribute to append the entity to.
+///
+/// # Returns
+///
+/// A `Result` containing the uid of the promoted entity.
+pub fn promote(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    oracle_roll: OracleRoll,
+    attr_name: &str,
+) -> Result<String> {
+    let OracleRoll {
+        uid,
+        parent_uid,
+        writes,
+        ..
+    } = oracle_roll;
+    {
+        let parent = tx.load(&parent_uid)?;
+        if !parent[attr_name].is_array() {
+            if !parent.is_missing(attr_name) {
+                return Err(anyhow!(
+                    "Unable to promote {} into {} in {}",
+                    uid,
+                    attr_name,
+                    parent_uid
+                ));
+            }
+            parent[attr_name] = serde_json::json!([]);
+        }
+        parent[attr_name]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::Value::from(uid.as_str()));
+    }
+    tx.save(&parent_uid)?;
+    tx.merge(writes)?;
+    let class_name = {
+        let entity = tx.load(&uid)?;
+        entity["$parent"] = serde_json::json!({
+            "uid": &parent_uid,
+            "attr": attr_name,
+        });
+        entity["class"].as_str().unwrap().to_string()
+    };
+    tx.save(&uid)?;
+    collect(builder, tx, &parent_uid, &uid, &class_name)?;
+    search::update(builder.sandbox, tx)?;
+    Ok(uid)
+}
+
 /// Resolve a concrete class to roll using the specified class in a scroll.
 /// The specified class could be a parent class, a variable pointing to a class List
 /// or already a concrete class.
@@ -353,8 +700,10 @@ fn resolve_actual_class_to_roll<'a>(
         class_to_resolve = match &class_to_resolve.subclasses {
             SubclassesSpecifier::Var(variable_symbol) => {
                 let variable_name = &variable_symbol[1..]; // removing the $ sign
-                let class_list = builder.sandbox.globals[variable_name]
-                    .as_array()
+                let class_list = builder
+                    .sandbox
+                    .global(variable_name)
+                    .and_then(|v| v.as_array())
                     .ok_or(anyhow!("Unable to find {}", variable_symbol))?;
                 let rolled_class_name = builder.randomizer.choose(class_list).as_str().unwrap();
                 builder
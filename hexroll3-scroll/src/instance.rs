@@ -23,24 +23,35 @@
 // for more information about commercial licensing terms.
 */
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::path::PathBuf; // Trait that provides the `choose` method
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf}; // Trait that provides the `choose` method
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Result;
 use minijinja::Environment;
-use rand::distributions::Alphanumeric;
-use rand::rngs::ThreadRng;
+use rand::distributions::{Alphanumeric, Distribution, WeightedIndex};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
 use rand::Rng;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 
+use crate::export::{self, ExportHeader};
+use crate::fsck::{self, Report};
+use crate::gc::{self, GarbageReport};
 use crate::generators::roll;
-use crate::parser::parse_buffer;
-use crate::parser::parse_file;
+use crate::metadata::{self, SandboxMetadata};
+use crate::migrations::{self, MigrationReport, Migrations};
+use crate::parser::{parse_buffer, parse_file, parse_sources, ScrollSources};
+use crate::progress::*;
+use crate::query::{self, Query, QueryResult};
+use crate::references::{self, Reference};
 use crate::renderer_env::prepare_renderer;
 use crate::repository::*;
+use crate::search::{self, SearchHit};
 use crate::semantics::*;
+use crate::storage::IN_MEMORY;
 
 /// SandboxBuilder is a wrapper for sandbox instances, providing the
 /// additional facilities required to generate content.
@@ -48,16 +59,144 @@ pub struct SandboxBuilder<'a> {
     pub sandbox: &'a SandboxInstance,
     pub randomizer: Randomizer,
     pub templating_env: Environment<'a>,
+    pub options: BuilderOptions,
+    pub progress: Arc<ProgressTracker>,
+    /// The classes of the entities being rolled, from the outermost one,
+    /// whose length is the depth of the entity being rolled.
+    pub class_chain: RefCell<Vec<String>>,
 }
 
 impl<'a> SandboxBuilder<'a> {
     pub fn from_instance(instance: &'a SandboxInstance) -> Self {
+        Self::with_options(instance, BuilderOptions::default())
+    }
+
+    pub fn with_options(instance: &'a SandboxInstance, options: BuilderOptions) -> Self {
         let mut env = Environment::new();
         prepare_renderer(&mut env, instance);
         SandboxBuilder {
             sandbox: instance,
-            randomizer: Randomizer::new(),
+            randomizer: options
+                .seed
+                .map_or_else(Randomizer::new, Randomizer::seeded),
             templating_env: env,
+            progress: Arc::new(ProgressTracker::new(&options)),
+            class_chain: RefCell::new(Vec::new()),
+            options,
+        }
+    }
+
+    /// A builder for a worker thread, seeded from this builder's randomizer
+    /// and sharing its progress tracking and cancellation.
+    pub fn fork(&self) -> Self {
+        let options = BuilderOptions {
+            seed: Some(self.randomizer.next_seed()),
+            ..self.options.clone()
+        };
+        let mut env = Environment::new();
+        prepare_renderer(&mut env, self.sandbox);
+        SandboxBuilder {
+            sandbox: self.sandbox,
+            randomizer: Randomizer::seeded(options.seed.unwrap()),
+            templating_env: env,
+            progress: self.progress.clone(),
+            class_chain: self.class_chain.clone(),
+            options,
+        }
+    }
+
+    /// Push a class onto the class chain, until the returned guard is
+    /// dropped, so the chain is popped on errors as well.
+    pub fn enter_class(&self, class_name: &str) -> ClassChainGuard<'_> {
+        self.class_chain.borrow_mut().push(class_name.to_string());
+        ClassChainGuard(&self.class_chain)
+    }
+}
+
+/// Pops the class chain of a builder when dropped (see
+/// `SandboxBuilder::enter_class`).
+pub struct ClassChainGuard<'a>(&'a RefCell<Vec<String>>);
+
+impl Drop for ClassChainGuard<'_> {
+    fn drop(&mut self) {
+        self.0.borrow_mut().pop();
+    }
+}
+
+/// BuilderOptions control how a SandboxBuilder generates content.
+#[derive(Clone, Default)]
+pub struct BuilderOptions {
+    /// Seeds the builder's randomizer, making generation deterministic.
+    pub seed: Option<u64>,
+    /// Classes whose entities do not depend on their siblings, and so can
+    /// be rolled concurrently on worker threads when rolled as an array,
+    /// for example the regions of a realm. Class constraints are not
+    /// enforced between entities rolled concurrently.
+    pub concurrent_classes: HashSet<String>,
+    /// Called with the generation progress every time an entity is rolled.
+    pub on_progress: Option<ProgressCallback>,
+    /// Cancels the generation when cancelled from another thread.
+    pub cancellation: CancellationToken,
+    /// Limits guarding against runaway generations.
+    pub limits: Limits,
+    /// The class of the sandbox root entity, `main` when unset. Any class
+    /// can be rolled as a standalone sandbox, for example a single dungeon.
+    pub root_class: Option<String>,
+    /// Values for context references with no matching ancestor, keyed by
+    /// class and then by attribute, for example:
+    ///
+    /// ```json
+    /// { "Realm": { "Title": "The Kingdom of Ash" } }
+    /// ```
+    ///
+    /// Context references missing here render as `false`.
+    pub stub_context: serde_json::Value,
+    /// Overrides for global variables, including those used as
+    /// cardinalities, for the created sandbox only (see
+    /// `SandboxInstance::parameters`).
+    pub parameters: HashMap<String, serde_json::Value>,
+    /// Embeds the scroll sources in the created sandbox, so it can be
+    /// opened using `SandboxInstance::open_self_contained`.
+    pub embed_scrolls: bool,
+}
+
+/// The default maximum depth of an entity, far deeper than any scroll needs
+/// while keeping recursive scrolls from overflowing the stack.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// The default maximum number of entities rolled in total.
+pub const DEFAULT_MAX_ENTITIES: usize = 250_000;
+
+/// Limits guarding against runaway generations, for example a class
+/// recursively rolling itself or a cardinality set too high in a scroll.
+///
+/// Exceeding any of these fails the generation with an error naming the
+/// chain of classes being rolled. By default, only the depth and the total
+/// number of entities are limited, to `DEFAULT_MAX_DEPTH` and
+/// `DEFAULT_MAX_ENTITIES`.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// The maximum depth of an entity in the entity hierarchy.
+    pub max_depth: Option<usize>,
+    /// The maximum number of entities rolled for any single class.
+    pub max_entities_per_class: Option<usize>,
+    /// The maximum number of entities rolled in total.
+    pub max_entities: Option<usize>,
+    /// The maximum time a generation can take.
+    pub max_duration: Option<Duration>,
+    /// The maximum number of re-draws when enforcing class constraints,
+    /// `DEFAULT_MAX_REDRAWS` when unset.
+    pub max_redraws: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+            max_entities_per_class: None,
+            max_entities: Some(DEFAULT_MAX_ENTITIES),
+            max_duration: None,
+            max_redraws: None,
         }
     }
 }
@@ -69,6 +208,14 @@ pub struct SandboxInstance {
     pub classes: HashMap<String, Class>,
     pub repo: Repository,
     pub globals: HashMap<String, serde_json::Value>,
+    /// Overrides for `globals` this sandbox was created with, stored in
+    /// its repository so every later change to it uses them as well.
+    pub parameters: HashMap<String, serde_json::Value>,
+    /// A hash of the scroll sources parsed so far, recorded in the
+    /// metadata of sandboxes created using them.
+    pub scroll_hash: Option<u64>,
+    /// The sources of the scrolls parsed so far.
+    pub scroll_sources: ScrollSources,
 }
 
 impl SandboxInstance {
@@ -78,6 +225,9 @@ impl SandboxInstance {
             classes: HashMap::new(),
             repo: Repository::new(),
             globals: HashMap::new(),
+            parameters: HashMap::new(),
+            scroll_hash: None,
+            scroll_sources: ScrollSources::default(),
         }
     }
 
@@ -88,42 +238,449 @@ impl SandboxInstance {
 
     pub fn open(&mut self, filepath: &str) -> Result<&mut Self> {
         self.repo.open(filepath)?;
-        let root = self.repo.inspect(|tx| tx.load("root"))?;
+        self.repo.index_attributes(self.indexed_attributes());
+        // Opening before loading any scroll keeps the indexes, which would
+        // otherwise be rebuilt without the attributes scrolls index
+        if !self.repo.is_indexed()?
+            || (!self.classes.is_empty() && self.repo.has_stale_indexes()?)
+        {
+            self.repo.reindex()?;
+        }
+        // Documents left to update, for example by reindexing, are updated
+        // now, so searching never has to write
+        if !self.classes.is_empty() {
+            self.update_search_index()?;
+        }
+        let (root, parameters, classes, model) = self.repo.inspect(|tx| {
+            Ok((
+                tx.load("root")?,
+                tx.load("$parameters").ok(),
+                tx.indexed_classes()?,
+                migrations::stored_model(tx)?,
+            ))
+        })?;
+        self.parameters = match parameters {
+            Some(parameters) => serde_json::from_value(parameters.value)?,
+            None => HashMap::new(),
+        };
+        let metadata = SandboxMetadata::read(&self.repo)?;
+        match &metadata {
+            Some(metadata) => {
+                let warnings = metadata
+                    .check_compatibility(self, &classes)
+                    .map_err(|e| e.context(format!("Unable to open {}", filepath)))?;
+                for warning in warnings {
+                    log::warn!("Sandbox {} {}", filepath, warning);
+                }
+            }
+            None => log::warn!(
+                "Sandbox {} has no metadata, so its scrolls cannot be checked",
+                filepath
+            ),
+        }
         if let Some(sid) = root.value.as_str() {
             self.sid = Some(sid.to_string());
+            // Checking needs the classes of the sandbox entities, so it is
+            // skipped when opening before loading any scroll.
+            if !self.classes.is_empty() {
+                let changed = match &model {
+                    Some(model) => migrations::changed_classes(self, &classes, model),
+                    None => vec![],
+                };
+                if !changed.is_empty() {
+                    log::warn!(
+                        "Sandbox {} has entities of classes changed by the scrolls, \
+                         and should be migrated: {}",
+                        filepath,
+                        changed.join(", ")
+                    );
+                }
+                // Checking every entity is slow for large sandboxes, so it
+                // is only done until this version finds the sandbox
+                // consistent.
+                let generator = metadata::generator();
+                let checked = metadata
+                    .as_ref()
+                    .is_some_and(|metadata| metadata.checked_by.as_ref() == Some(&generator));
+                if !checked {
+                    let report = self.check()?;
+                    for issue in &report.issues {
+                        log::warn!("Sandbox {} is inconsistent: {}", filepath, issue);
+                    }
+                    if report.is_consistent() && metadata.is_some() {
+                        self.repo.mutate(|tx| {
+                            tx.store_metadata("checked_by", &serde_json::json!(generator))
+                        })?;
+                    }
+                }
+            }
             Ok(self)
         } else {
             Err(anyhow!("Unable to find root entity in {}", filepath))
         }
     }
 
+    /// Whether a sandbox was created with its scrolls embedded (see
+    /// `BuilderOptions::embed_scrolls`).
+    pub fn has_embedded_scrolls(filepath: &str) -> Result<bool> {
+        let mut repo = Repository::new();
+        repo.open(filepath)?;
+        repo.contains("$scrolls")
+    }
+
+    /// Open a sandbox created with its scrolls embedded (see
+    /// `BuilderOptions::embed_scrolls`), using them instead of any scrolls
+    /// parsed before.
+    pub fn open_self_contained(&mut self, filepath: &str) -> Result<&mut Self> {
+        let sources = {
+            let mut repo = Repository::new();
+            repo.open(filepath)?;
+            repo.load("$scrolls").map_err(|_| {
+                anyhow!(
+                    "Sandbox {} was created without embedding its scrolls",
+                    filepath
+                )
+            })?
+        };
+        let sources: ScrollSources = serde_json::from_value(sources)?;
+        self.classes.clear();
+        self.globals.clear();
+        self.scroll_hash = None;
+        self.scroll_sources = ScrollSources::default();
+        parse_sources(self, &sources)?;
+        self.open(filepath)
+    }
+
+    /// Embed the sources of the scrolls parsed by this instance in its
+    /// sandbox, replacing any embedded before.
+    pub fn embed_scrolls(&self) -> Result<()> {
+        self.repo
+            .mutate(|tx| tx.store("$scrolls", &serde_json::to_value(&self.scroll_sources)?))
+    }
+
     pub fn create(&mut self, filepath: &str) -> Result<&mut Self> {
+        self.create_with_options(filepath, BuilderOptions::default())
+    }
+
+    pub fn create_with_options(
+        &mut self,
+        filepath: &str,
+        options: BuilderOptions,
+    ) -> Result<&mut Self> {
+        let existed = Path::new(filepath).exists();
         self.repo.create(filepath)?;
+        self.repo
+            .index_attributes(self.indexed_attributes())
+            .reindex()?;
+        self.parameters = options.parameters.clone();
 
-        if let Ok(sid) = self.repo.mutate(|tx| {
-            let builder = SandboxBuilder::from_instance(self);
-            let ret = roll(&builder, tx, "main", "root", None);
-            tx.store("root", &serde_json::json!(ret.as_ref().unwrap()))?;
-            ret
-        }) {
-            self.sid = Some(sid.to_string());
-            Ok(self)
-        } else {
-            Err(anyhow!(
-                "Was unable to create a new sandbox in {}",
-                filepath
-            ))
-        }
+        let sid = self
+            .repo
+            .mutate(|tx| {
+                let builder = SandboxBuilder::with_options(self, options.clone());
+                let root_class = options.root_class.as_deref().unwrap_or("main");
+                if builder.options.on_progress.is_some() {
+                    builder
+                        .progress
+                        .set_estimated_total(estimate_entities(&builder, root_class));
+                }
+                if !options.stub_context.is_null() {
+                    tx.store("$context", &options.stub_context)?;
+                }
+                if !options.parameters.is_empty() {
+                    tx.store("$parameters", &serde_json::json!(options.parameters))?;
+                }
+                if let Some(seed) = options.seed {
+                    tx.store("$seed", &serde_json::json!(seed))?;
+                }
+                if options.embed_scrolls {
+                    tx.store("$scrolls", &serde_json::to_value(&self.scroll_sources)?)?;
+                }
+                let ret = roll(&builder, tx, root_class, "root", None)?;
+                tx.store("root", &serde_json::json!(ret))?;
+                search::update(self, tx)?;
+                SandboxMetadata::describe(self, tx)?.store(tx)?;
+                migrations::store_model(self, tx)?;
+                Ok(ret)
+            })
+            .map_err(|e| {
+                self.repo.close();
+                remove_created_file(filepath, existed);
+                e.context(format!(
+                    "Was unable to create a new sandbox in {}",
+                    filepath
+                ))
+            })?;
+        self.sid = Some(sid);
+        Ok(self)
+    }
+
+    /// Create several sandboxes concurrently, one for each filepath, all
+    /// using the classes and globals of this instance.
+    ///
+    /// When `options` is seeded, each sandbox is seeded with its own seed
+    /// drawn from it, so the whole batch is deterministic.
+    pub fn create_many(
+        &self,
+        filepaths: &[&str],
+        options: &BuilderOptions,
+    ) -> Result<Vec<SandboxInstance>> {
+        let seeder = options.seed.map(Randomizer::seeded);
+        let options: Vec<BuilderOptions> = filepaths
+            .iter()
+            .map(|_| BuilderOptions {
+                seed: seeder.as_ref().map(|seeder| seeder.next_seed()),
+                ..options.clone()
+            })
+            .collect();
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = filepaths
+                .iter()
+                .zip(options)
+                .map(|(filepath, options)| {
+                    scope.spawn(move || -> Result<SandboxInstance> {
+                        let mut instance = self.clone_model();
+                        instance.create_with_options(filepath, options)?;
+                        Ok(instance)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .map_err(|_| anyhow!("A sandbox creation thread has panicked"))?
+                })
+                .collect()
+        })
+    }
+
+    /// Export the sandbox to a JSON Lines file (see `export::ExportHeader`).
+    pub fn export(&self, filepath: &str) -> Result<ExportHeader> {
+        let existed = Path::new(filepath).exists();
+        let file = std::fs::File::create(filepath)?;
+        self.repo
+            .inspect(|tx| export::export(tx, std::io::BufWriter::new(&file)))
+            .inspect_err(|_| remove_created_file(filepath, existed))
+    }
+
+    /// Create a sandbox in `filepath` from an export, rebuilding its indexes.
+    pub fn import(&mut self, filepath: &str, export_filepath: &str) -> Result<ExportHeader> {
+        let file = std::fs::File::open(export_filepath)?;
+        let existed = Path::new(filepath).exists();
+        self.repo.create(filepath)?;
+        self.repo
+            .index_attributes(self.indexed_attributes())
+            .reindex()?;
+        let header = self
+            .repo
+            .mutate(|tx| {
+                let header = export::import(tx, std::io::BufReader::new(&file))?;
+                search::update(self, tx)?;
+                // Exports made before metadata was exported have none
+                let described = SandboxMetadata::describe(self, tx)?;
+                match SandboxMetadata::from_rows(tx.metadata()?)? {
+                    Some(metadata) => SandboxMetadata {
+                        entities: described.entities,
+                        ..metadata
+                    },
+                    None => described,
+                }
+                .store(tx)?;
+                if migrations::stored_model(tx)?.is_none() {
+                    migrations::store_model(self, tx)?;
+                }
+                Ok(header)
+            })
+            .map_err(|e| {
+                self.repo.close();
+                remove_created_file(filepath, existed);
+                e.context(format!("Was unable to import {}", export_filepath))
+            })?;
+        self.sid = self
+            .repo
+            .load("root")
+            .ok()
+            .and_then(|root| root.as_str().map(str::to_string));
+        self.parameters = match self.repo.load("$parameters") {
+            Ok(parameters) => serde_json::from_value(parameters)?,
+            Err(_) => HashMap::new(),
+        };
+        Ok(header)
     }
 
     pub fn sid(&self) -> Option<String> {
         self.sid.clone()
     }
 
+    /// The metadata of the sandbox, unless created before metadata was
+    /// introduced.
+    pub fn metadata(&self) -> Result<Option<SandboxMetadata>> {
+        SandboxMetadata::read(&self.repo)
+    }
+
+    /// Update the entity counts and scroll hash in the sandbox metadata,
+    /// keeping when and how it was created.
+    pub fn update_metadata(&self) -> Result<SandboxMetadata> {
+        let stored = self.metadata()?;
+        self.repo.mutate(|tx| {
+            let mut metadata = SandboxMetadata::describe(self, tx)?;
+            if let Some(stored) = &stored {
+                metadata.created = stored.created;
+                metadata.seed = stored.seed;
+            }
+            metadata.store(tx)?;
+            Ok(metadata)
+        })
+    }
+
+    /// Migrate the sandbox entities to the scrolls of this instance, running
+    /// the given steps (see `migrations::migrate`), then update the sandbox
+    /// metadata.
+    pub fn migrate(&self, migrations: &Migrations) -> Result<MigrationReport> {
+        let report = self.repo.mutate(|tx| {
+            let builder = SandboxBuilder::from_instance(self);
+            let report = migrations::migrate(&builder, tx, migrations)?;
+            search::update(self, tx)?;
+            Ok(report)
+        })?;
+        self.update_metadata()?;
+        Ok(report)
+    }
+
     pub fn parse_buffer(&mut self, buffer: &str) -> &mut Self {
         parse_buffer(self, buffer, None, None).unwrap();
         self
     }
+
+    /// A new instance with the same model (classes and globals) as this one
+    /// and no repository.
+    fn clone_model(&self) -> SandboxInstance {
+        SandboxInstance {
+            sid: None,
+            classes: self.classes.clone(),
+            repo: Repository::new(),
+            globals: self.globals.clone(),
+            parameters: HashMap::new(),
+            scroll_hash: self.scroll_hash,
+            scroll_sources: self.scroll_sources.clone(),
+        }
+    }
+
+    /// The value of a global variable, as overridden by `parameters`.
+    pub fn global(&self, name: &str) -> Option<&serde_json::Value> {
+        self.parameters.get(name).or_else(|| self.globals.get(name))
+    }
+
+    /// Find all the references to an entity, for example where an NPC is
+    /// mentioned.
+    pub fn references_to(&self, uid: &str) -> Result<Vec<Reference>> {
+        self.repo
+            .inspect(|tx| references::references_to(self, tx, uid))
+    }
+
+    /// Find all the references from an entity to other entities.
+    pub fn references_from(&self, uid: &str) -> Result<Vec<Reference>> {
+        self.repo
+            .inspect(|tx| references::references_from(self, tx, uid))
+    }
+
+    /// Query the entities of the sandbox (see `query::Query`).
+    pub fn query(&self, query: &Query) -> Result<QueryResult> {
+        self.repo.inspect(|tx| query::query(self, tx, query))
+    }
+
+    /// Search the sandbox for entities declaring an `IndexedEntity`, returning
+    /// up to `limit` hits ranked by relevance (see `search::search`).
+    pub fn search(&self, text: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        self.repo.inspect(|tx| search::search(tx, text, limit))
+    }
+
+    /// Update the search index with the entities stored or removed since it
+    /// was last updated, returning the number of documents updated.
+    ///
+    /// Only needed after changing entities other than through generators,
+    /// which update the index as they go (see `search::update`).
+    pub fn update_search_index(&self) -> Result<usize> {
+        if self
+            .repo
+            .inspect(|tx| Ok(tx.indexed(&IndexKey::Changed)?.is_empty()))?
+        {
+            return Ok(0);
+        }
+        self.repo.mutate(|tx| search::update(self, tx))
+    }
+
+    /// The attributes declared as indexed in scrolls, and the attributes
+    /// referencing other entities, by class.
+    fn indexed_attributes(&self) -> IndexedAttributes {
+        IndexedAttributes {
+            hierarchies: self
+                .classes
+                .iter()
+                .map(|(name, class)| (name.clone(), class.hierarchy.clone()))
+                .collect(),
+            values: self
+                .classes
+                .iter()
+                .filter(|(_, class)| !class.indexed.is_empty())
+                .map(|(name, class)| (name.clone(), class.indexed.clone()))
+                .collect(),
+            references: self
+                .classes
+                .iter()
+                .map(|(name, class)| {
+                    let attrs: Vec<String> = class
+                        .attrs
+                        .iter()
+                        .filter(|(_, attr)| attr.cmd.reference_kind().is_some())
+                        .map(|(attr_name, _)| attr_name.clone())
+                        .collect();
+                    (name.clone(), attrs)
+                })
+                .filter(|(_, attrs)| !attrs.is_empty())
+                .collect(),
+        }
+    }
+
+    /// Check the consistency of the sandbox repository (see `fsck::check`).
+    pub fn check(&self) -> Result<Report> {
+        self.repo.inspect(|tx| fsck::check(self, tx))
+    }
+
+    /// Repair the sandbox repository, fixing whatever can be safely fixed
+    /// (see `fsck::repair`).
+    pub fn repair(&self) -> Result<Report> {
+        self.repo.mutate(|tx| {
+            let report = fsck::repair(self, tx)?;
+            search::update(self, tx)?;
+            Ok(report)
+        })
+    }
+
+    /// Delete unreachable entities and stale frames, then compact the
+    /// sandbox file (see `gc::collect_garbage` and `Repository::compact`).
+    ///
+    /// Refuses to run while the repository is shared with another handle.
+    /// Other processes cannot open the file at all while it is open here.
+    pub fn collect_garbage(&mut self) -> Result<GarbageReport> {
+        if self.repo.is_shared() {
+            return Err(anyhow!(
+                "Unable to collect garbage while the sandbox is open elsewhere"
+            ));
+        }
+        let size = self.repo.file_size()?;
+        let mut report = self.repo.mutate(|tx| {
+            let report = gc::collect_garbage(self, tx)?;
+            search::update(self, tx)?;
+            Ok(report)
+        })?;
+        self.update_metadata()?;
+        report.compacted = self.repo.compact()?;
+        report.bytes_reclaimed = size.saturating_sub(self.repo.file_size()?);
+        Ok(report)
+    }
 }
 
 impl Default for SandboxInstance {
@@ -132,18 +689,41 @@ impl Default for SandboxInstance {
     }
 }
 
+/// Remove a file left behind by a failed create, import or export, unless
+/// it existed before.
+fn remove_created_file(filepath: &str, existed: bool) {
+    if existed || filepath == IN_MEMORY {
+        return;
+    }
+    if let Err(e) = std::fs::remove_file(filepath) {
+        log::warn!("Unable to remove {}: {}", filepath, e);
+    }
+}
+
 pub struct Randomizer {
-    rng: RefCell<ThreadRng>, // Use RefCell for interior mutability
+    rng: RefCell<ChaCha8Rng>, // Use RefCell for interior mutability
 }
 
 impl Randomizer {
     pub fn new() -> Self {
         Randomizer {
-            rng: RefCell::new(thread_rng()),
+            rng: RefCell::new(ChaCha8Rng::from_entropy()),
+        }
+    }
+
+    pub fn seeded(seed: u64) -> Self {
+        Randomizer {
+            rng: RefCell::new(ChaCha8Rng::seed_from_u64(seed)),
         }
     }
 
-    pub fn choose<'a, T>(&self, v: &'a Vec<T>) -> &'a T {
+    /// Draw a seed for another randomizer, for example one used by a
+    /// worker thread, keeping seeded generation deterministic.
+    pub fn next_seed(&self) -> u64 {
+        self.rng.borrow_mut().gen()
+    }
+
+    pub fn choose<'a, T>(&self, v: &'a [T]) -> &'a T {
         match v.choose(&mut *self.rng.borrow_mut()) {
             Some(item) => item,
             None => panic!("List is empty"),
@@ -155,6 +735,14 @@ impl Randomizer {
         (0..8).map(|_| rng.sample(Alphanumeric) as char).collect()
     }
 
+    /// Choose an index with a probability proportional to its weight, or
+    /// `None` when no weight is positive.
+    pub fn weighted(&self, weights: &[f64]) -> Option<usize> {
+        let weights = weights.iter().map(|w| w.max(0.0));
+        let distribution = WeightedIndex::new(weights).ok()?;
+        Some(distribution.sample(&mut *self.rng.borrow_mut()))
+    }
+
     pub fn in_range(&self, min: i32, max: i32) -> i32 {
         let mut rng = self.rng.borrow_mut();
         rng.gen_range(min..max + 1)
@@ -2,11 +2,25 @@
 extern crate pest_derive;
 
 pub mod commands;
+pub mod constraints;
+pub mod encoding;
+pub mod export;
 pub mod frame;
+pub mod fsck;
+pub mod gc;
 pub mod generators;
 pub mod instance;
+pub mod memory_storage;
+pub mod metadata;
+pub mod migrations;
 pub mod parser;
+pub mod progress;
+pub mod query;
+pub mod redb_storage;
+pub mod references;
 pub mod renderer;
 pub mod renderer_env;
 pub mod repository;
+pub mod search;
 pub mod semantics;
+pub mod storage;
@@ -0,0 +1,473 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use crate::{
+    encoding::ValueEncoding,
+    repository::JsonValue,
+    storage::{BoxedStorageReader, Savepoint, Storage, StorageReader, StorageWriter},
+};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard, RwLock},
+};
+
+/// A storage backend keeping a repository in memory, gone once dropped.
+///
+/// Readers share the tables as they were when reading began. Write
+/// transactions record their writes on top of the tables and apply them
+/// when committed, copying the tables only while readers still share them.
+#[derive(Default)]
+pub struct MemoryStorage {
+    tables: RwLock<Arc<Tables>>,
+    /// Held by the write transaction in progress, so writes are serialized.
+    writing: Mutex<()>,
+    snapshots: Mutex<Snapshots>,
+}
+
+/// Named snapshots of the tables, from the oldest to the newest.
+type Snapshots = Vec<(String, Arc<Tables>)>;
+
+#[derive(Clone, Default)]
+struct Tables {
+    entities: BTreeMap<String, serde_json::Value>,
+    index: BTreeMap<String, BTreeSet<String>>,
+    documents: BTreeMap<String, serde_json::Value>,
+    metadata: BTreeMap<String, serde_json::Value>,
+    dictionaries: BTreeMap<u32, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tables(&self) -> Result<Arc<Tables>> {
+        Ok(self
+            .tables
+            .read()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .clone())
+    }
+
+    fn replace_tables(&self, tables: Arc<Tables>) -> Result<()> {
+        *self
+            .tables
+            .write()
+            .map_err(|_| anyhow!("Failed to acquire lock"))? = tables;
+        Ok(())
+    }
+
+    fn lock_snapshots(&self) -> Result<MutexGuard<'_, Snapshots>> {
+        self.snapshots
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn reader(&self) -> Result<BoxedStorageReader> {
+        Ok(Box::new(MemoryReader(self.tables()?)))
+    }
+
+    fn write(
+        &self,
+        _encoding: ValueEncoding,
+        f: &mut dyn FnMut(&mut dyn StorageWriter) -> Result<()>,
+    ) -> Result<()> {
+        let _writing = self
+            .writing
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        let mut writer = MemoryWriter {
+            base: self.tables()?,
+            entities: HashMap::new(),
+            index: HashMap::new(),
+            documents: HashMap::new(),
+            metadata: HashMap::new(),
+            cleared: false,
+        };
+        f(&mut writer)?;
+        let mut tables = self
+            .tables
+            .write()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        writer.apply(Arc::make_mut(&mut tables));
+        Ok(())
+    }
+
+    fn savepoint(&self) -> Result<Savepoint> {
+        Ok(Savepoint::new(self.tables()?))
+    }
+
+    fn restore(&self, savepoint: &Savepoint) -> Result<()> {
+        let tables = savepoint.downcast_ref::<Arc<Tables>>()?.clone();
+        self.replace_tables(tables)
+    }
+
+    fn snapshot(&self, name: &str) -> Result<()> {
+        let mut snapshots = self.lock_snapshots()?;
+        if snapshots
+            .iter()
+            .any(|(snapshot_name, _)| snapshot_name == name)
+        {
+            return Err(anyhow!("Snapshot {} already exists", name));
+        }
+        snapshots.push((name.to_string(), self.tables()?));
+        Ok(())
+    }
+
+    fn snapshots(&self) -> Result<Vec<String>> {
+        Ok(self
+            .lock_snapshots()?
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    fn restore_snapshot(&self, name: &str) -> Result<()> {
+        let mut snapshots = self.lock_snapshots()?;
+        let position = snapshot_position(&snapshots, name)?;
+        self.replace_tables(snapshots[position].1.clone())?;
+        snapshots.truncate(position + 1);
+        Ok(())
+    }
+
+    fn delete_snapshot(&self, name: &str) -> Result<()> {
+        let mut snapshots = self.lock_snapshots()?;
+        let position = snapshot_position(&snapshots, name)?;
+        snapshots.remove(position);
+        Ok(())
+    }
+
+    fn read_snapshot(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(&dyn StorageReader) -> Result<()>,
+    ) -> Result<()> {
+        let tables = {
+            let snapshots = self.lock_snapshots()?;
+            snapshots[snapshot_position(&snapshots, name)?].1.clone()
+        };
+        f(&MemoryReader(tables))
+    }
+
+    fn is_indexed(&self) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn dictionaries(&self) -> Result<Vec<Vec<u8>>> {
+        Ok(self.tables()?.dictionaries.values().cloned().collect())
+    }
+
+    fn add_dictionary(&self, id: u32, dictionary: &[u8]) -> Result<()> {
+        let _writing = self
+            .writing
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        let mut tables = self
+            .tables
+            .write()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        Arc::make_mut(&mut tables)
+            .dictionaries
+            .insert(id, dictionary.to_vec());
+        Ok(())
+    }
+
+    /// The size of the stored entities and search documents as JSON.
+    fn size(&self) -> Result<u64> {
+        let tables = self.tables()?;
+        let mut size = 0;
+        for value in tables.entities.values().chain(tables.documents.values()) {
+            size += serde_json::to_vec(value)?.len() as u64;
+        }
+        Ok(size)
+    }
+
+    /// Memory is never compacted, so this always returns `false`.
+    fn compact(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+fn snapshot_position(snapshots: &Snapshots, name: &str) -> Result<usize> {
+    snapshots
+        .iter()
+        .position(|(snapshot_name, _)| snapshot_name == name)
+        .ok_or_else(|| anyhow!("Snapshot {} not found", name))
+}
+
+struct MemoryReader(Arc<Tables>);
+
+impl StorageReader for MemoryReader {
+    fn get(&self, uid: &str) -> Result<Option<JsonValue>> {
+        Ok(self.0.entities.get(uid).cloned().map(|value| JsonValue {
+            value,
+            encoding: ValueEncoding::default(),
+        }))
+    }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        Ok(self.0.entities.keys().cloned().collect())
+    }
+
+    fn indexed(&self, key: &str) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .index
+            .get(key)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect())
+    }
+
+    fn index_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        Ok(prefixed_keys(&self.0.index, prefix).collect())
+    }
+
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        Ok(self.0.documents.get(uid).cloned())
+    }
+
+    fn document_uids(&self) -> Result<Vec<String>> {
+        Ok(self.0.documents.keys().cloned().collect())
+    }
+
+    fn documents_count(&self) -> Result<u64> {
+        Ok(self.0.documents.len() as u64)
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        Ok(self
+            .0
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect())
+    }
+}
+
+/// Records the writes of a transaction on top of the tables it began with.
+struct MemoryWriter {
+    base: Arc<Tables>,
+    /// Written entities, or `None` when removed.
+    entities: HashMap<String, Option<serde_json::Value>>,
+    /// The complete uids of every written index key.
+    index: HashMap<String, BTreeSet<String>>,
+    /// Written search documents, or `None` when removed.
+    documents: HashMap<String, Option<serde_json::Value>>,
+    metadata: HashMap<String, serde_json::Value>,
+    /// Whether the index and search documents of `base` were cleared.
+    cleared: bool,
+}
+
+impl MemoryWriter {
+    fn index_entry(&mut self, key: &str) -> &mut BTreeSet<String> {
+        let base = if self.cleared {
+            None
+        } else {
+            self.base.index.get(key)
+        };
+        self.index
+            .entry(key.to_string())
+            .or_insert_with(|| base.cloned().unwrap_or_default())
+    }
+
+    fn apply(self, tables: &mut Tables) {
+        if self.cleared {
+            tables.index.clear();
+            tables.documents.clear();
+        }
+        for (uid, value) in self.entities {
+            match value {
+                Some(value) => tables.entities.insert(uid, value),
+                None => tables.entities.remove(&uid),
+            };
+        }
+        for (key, uids) in self.index {
+            if uids.is_empty() {
+                tables.index.remove(&key);
+            } else {
+                tables.index.insert(key, uids);
+            }
+        }
+        for (uid, document) in self.documents {
+            match document {
+                Some(document) => tables.documents.insert(uid, document),
+                None => tables.documents.remove(&uid),
+            };
+        }
+        tables.metadata.extend(self.metadata);
+    }
+}
+
+/// The keys of an index starting with a prefix that index any uid.
+fn prefixed_keys<'a>(
+    index: &'a BTreeMap<String, BTreeSet<String>>,
+    prefix: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    index
+        .range(prefix.to_string()..)
+        .take_while(move |(key, _)| key.starts_with(prefix))
+        .filter(|(_, uids)| !uids.is_empty())
+        .map(|(key, _)| key.clone())
+}
+
+/// The keys of `base` overlaid with the keys written on top of it, in order.
+fn overlay_keys<V>(
+    base: Option<&BTreeMap<String, V>>,
+    written: &HashMap<String, Option<V>>,
+) -> Vec<String> {
+    let mut keys: BTreeSet<String> = base
+        .into_iter()
+        .flat_map(|base| base.keys())
+        .filter(|key| !written.contains_key(*key))
+        .cloned()
+        .collect();
+    keys.extend(
+        written
+            .iter()
+            .filter(|(_, value)| value.is_some())
+            .map(|(key, _)| key.clone()),
+    );
+    keys.into_iter().collect()
+}
+
+impl StorageReader for MemoryWriter {
+    fn get(&self, uid: &str) -> Result<Option<JsonValue>> {
+        let value = match self.entities.get(uid) {
+            Some(written) => written.clone(),
+            None => self.base.entities.get(uid).cloned(),
+        };
+        Ok(value.map(|value| JsonValue {
+            value,
+            encoding: ValueEncoding::default(),
+        }))
+    }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        Ok(overlay_keys(Some(&self.base.entities), &self.entities))
+    }
+
+    fn indexed(&self, key: &str) -> Result<Vec<String>> {
+        let uids = match self.index.get(key) {
+            Some(uids) => Some(uids),
+            None if self.cleared => None,
+            None => self.base.index.get(key),
+        };
+        Ok(uids.into_iter().flatten().cloned().collect())
+    }
+
+    fn index_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys: BTreeSet<String> = match self.cleared {
+            true => BTreeSet::new(),
+            false => prefixed_keys(&self.base.index, prefix).collect(),
+        };
+        for (key, uids) in self.index.iter() {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+            if uids.is_empty() {
+                keys.remove(key);
+            } else {
+                keys.insert(key.clone());
+            }
+        }
+        Ok(keys.into_iter().collect())
+    }
+
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        Ok(match self.documents.get(uid) {
+            Some(written) => written.clone(),
+            None if self.cleared => None,
+            None => self.base.documents.get(uid).cloned(),
+        })
+    }
+
+    fn document_uids(&self) -> Result<Vec<String>> {
+        let base = (!self.cleared).then_some(&self.base.documents);
+        Ok(overlay_keys(base, &self.documents))
+    }
+
+    fn documents_count(&self) -> Result<u64> {
+        Ok(self.document_uids()?.len() as u64)
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        let mut metadata = self.base.metadata.clone();
+        metadata.extend(self.metadata.clone());
+        Ok(metadata.into_iter().collect())
+    }
+}
+
+impl StorageWriter for MemoryWriter {
+    fn insert(&mut self, uid: &str, value: &serde_json::Value) -> Result<()> {
+        self.entities.insert(uid.to_string(), Some(value.clone()));
+        Ok(())
+    }
+
+    fn remove(&mut self, uid: &str) -> Result<()> {
+        self.entities.insert(uid.to_string(), None);
+        Ok(())
+    }
+
+    fn index(&mut self, key: &str, uid: &str) -> Result<()> {
+        self.index_entry(key).insert(uid.to_string());
+        Ok(())
+    }
+
+    fn unindex(&mut self, key: &str, uid: &str) -> Result<()> {
+        self.index_entry(key).remove(uid);
+        Ok(())
+    }
+
+    fn take_indexed(&mut self, key: &str) -> Result<Vec<String>> {
+        Ok(std::mem::take(self.index_entry(key)).into_iter().collect())
+    }
+
+    fn insert_document(&mut self, uid: &str, document: &serde_json::Value) -> Result<()> {
+        self.documents
+            .insert(uid.to_string(), Some(document.clone()));
+        Ok(())
+    }
+
+    fn remove_document(&mut self, uid: &str) -> Result<()> {
+        self.documents.insert(uid.to_string(), None);
+        Ok(())
+    }
+
+    fn clear_indexes(&mut self) -> Result<()> {
+        self.index.clear();
+        self.documents.clear();
+        self.cleared = true;
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &serde_json::Value) -> Result<()> {
+        self.metadata.insert(key.to_string(), value.clone());
+        Ok(())
+    }
+}
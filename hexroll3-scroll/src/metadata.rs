@@ -0,0 +1,207 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::instance::SandboxInstance;
+use crate::repository::*;
+
+/// The version of the sandbox file format, bumped on incompatible changes.
+/// Sandboxes written using a newer format are refused when opened.
+pub const FORMAT_VERSION: u64 = 1;
+
+/// Describes a sandbox and the scrolls that generated it, stored in the
+/// metadata table of its repository, one row per field.
+///
+/// Sandboxes created before metadata was introduced have none.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SandboxMetadata {
+    pub format_version: u64,
+    /// The name and version of the crate that created the sandbox.
+    pub generator: String,
+    /// The `Version` of the sandbox root entity, set by scrolls.
+    pub scroll_version: Option<serde_json::Value>,
+    /// A hash of the scroll sources that generated the sandbox.
+    pub scroll_hash: Option<String>,
+    /// The seed the sandbox was created with, if it was seeded.
+    pub seed: Option<u64>,
+    /// When the sandbox was created, in seconds since the Unix epoch.
+    pub created: Option<u64>,
+    /// The uid of the sandbox root entity.
+    pub sid: Option<String>,
+    /// The number of entities of each class, including entities of its
+    /// subclasses, as of the last update of the metadata, which creating,
+    /// importing, migrating and collecting garbage do.
+    pub entities: BTreeMap<String, u64>,
+    /// The name and version of the crate that last found the sandbox
+    /// consistent when opening it, cleared whenever the metadata is updated.
+    pub checked_by: Option<String>,
+}
+
+impl SandboxMetadata {
+    /// Describe the sandbox held by a transaction, as generated by the
+    /// scrolls of `instance`. Entity counts are taken from the class index.
+    pub fn describe<T: ReadOnlyLoader>(instance: &SandboxInstance, tx: &T) -> Result<Self> {
+        let sid = tx
+            .retrieve("root")
+            .ok()
+            .and_then(|root| root.value.as_str().map(str::to_string));
+        let mut entities = BTreeMap::new();
+        for class_name in tx.indexed_classes()? {
+            let count = tx.indexed(&IndexKey::Class(class_name.clone()))?.len();
+            entities.insert(class_name, count as u64);
+        }
+        Ok(SandboxMetadata {
+            format_version: FORMAT_VERSION,
+            generator: generator(),
+            scroll_version: sid
+                .as_ref()
+                .and_then(|sid| tx.retrieve(sid).ok())
+                .map(|root| root.value["Version"].clone())
+                .filter(|version| !version.is_null()),
+            scroll_hash: instance.scroll_hash.map(|hash| format!("{:016x}", hash)),
+            seed: tx
+                .retrieve("$seed")
+                .ok()
+                .and_then(|seed| seed.value.as_u64()),
+            created: now(),
+            sid,
+            entities,
+            checked_by: None,
+        })
+    }
+
+    /// Read the metadata of a repository, if it has any.
+    pub fn read(repo: &Repository) -> Result<Option<Self>> {
+        Self::from_rows(repo.metadata()?)
+    }
+
+    /// The metadata stored in metadata rows, if any.
+    pub fn from_rows(mut rows: serde_json::Map<String, serde_json::Value>) -> Result<Option<Self>> {
+        // The row recording the indexed attributes belongs to the repository
+        rows.remove(INDEXED_ATTRIBUTES);
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(serde_json::Value::Object(
+            rows.into_iter().collect(),
+        ))?))
+    }
+
+    /// Store the metadata, replacing any stored before.
+    pub fn store(&self, tx: &mut ReadWriteTransaction) -> Result<()> {
+        let serde_json::Value::Object(rows) = serde_json::to_value(self)? else {
+            return Err(anyhow!("Metadata is not an object"));
+        };
+        for (key, value) in rows {
+            tx.store_metadata(&key, &value)?;
+        }
+        Ok(())
+    }
+
+    /// Check that the sandbox can be used with the scrolls of `instance`,
+    /// given the classes having entities in it (see
+    /// `ReadOnlyLoader::indexed_classes`).
+    ///
+    /// Fails when the sandbox was written using a newer format, or when it
+    /// holds entities of classes the scrolls lack, which could not be
+    /// rendered. Returns warnings about lesser differences, such as scrolls
+    /// changed since the sandbox was created. Scrolls are only checked when
+    /// `instance` has any classes.
+    ///
+    /// Classes are found using the class index rather than the entity
+    /// counts, which are not updated by every change.
+    pub fn check_compatibility(
+        &self,
+        instance: &SandboxInstance,
+        classes: &[String],
+    ) -> Result<Vec<String>> {
+        if self.format_version > FORMAT_VERSION {
+            return Err(anyhow!(
+                "The sandbox was written using format version {} by {}, \
+                 while only versions up to {} are supported",
+                self.format_version,
+                self.generator,
+                FORMAT_VERSION
+            ));
+        }
+        let mut warnings = Vec::new();
+        if instance.classes.is_empty() {
+            return Ok(warnings);
+        }
+        let missing: Vec<&str> = classes
+            .iter()
+            .filter(|class_name| !instance.classes.contains_key(*class_name))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "The sandbox was created using other scrolls, which define \
+                 classes missing from the loaded scrolls: {}",
+                missing.join(", ")
+            ));
+        }
+        let scroll_hash = instance.scroll_hash.map(|hash| format!("{:016x}", hash));
+        if self.scroll_hash.is_some() && self.scroll_hash != scroll_hash {
+            warnings.push(format!(
+                "was created using scrolls that have changed since (version {})",
+                self.scroll_version
+                    .as_ref()
+                    .map(|version| version.to_string())
+                    .unwrap_or_else(|| "unknown".to_string())
+            ));
+        }
+        Ok(warnings)
+    }
+}
+
+/// The name and version of this crate, recorded in sandbox metadata.
+pub fn generator() -> String {
+    format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+}
+
+/// Continue an FNV-1a hash of scroll sources with another source, or start
+/// one when `hash` is `None`.
+pub fn hash_source(hash: Option<u64>, source: &str) -> u64 {
+    source
+        .bytes()
+        .fold(hash.unwrap_or(0xcbf29ce484222325), |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn now() -> Option<u64> {
+    // The system clock is not available to wasm builds
+    if cfg!(target_arch = "wasm32") {
+        return None;
+    }
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .ok()
+        .map(|elapsed| elapsed.as_secs())
+}
@@ -0,0 +1,390 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::generators::unroll_entity;
+use crate::instance::*;
+use crate::repository::*;
+use crate::semantics::*;
+
+/// The attribute names declared by each class of the scrolls a sandbox was
+/// last generated or migrated with, stored in its `$model` entry.
+pub type ScrollModel = BTreeMap<String, Vec<String>>;
+
+/// A user-declared migration step, modifying an entity of a class from
+/// one version to the next one.
+pub type MigrationStep =
+    Arc<dyn Fn(&SandboxBuilder, &mut ReadWriteTransaction, &str) -> Result<()> + Send + Sync>;
+
+/// The migration steps to run when migrating a sandbox, keyed by class name
+/// and the class `Version` they migrate entities from.
+#[derive(Clone, Default)]
+pub struct Migrations {
+    steps: BTreeMap<(String, u64), MigrationStep>,
+}
+
+impl Migrations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare a step migrating entities of `class_name`, or of any of its
+    /// subclasses, from version `from` to version `from + 1`.
+    pub fn step<F>(&mut self, class_name: &str, from: u64, step: F) -> &mut Self
+    where
+        F: Fn(&SandboxBuilder, &mut ReadWriteTransaction, &str) -> Result<()>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.steps
+            .insert((class_name.to_string(), from), Arc::new(step));
+        self
+    }
+}
+
+/// An entity changed when migrating a sandbox.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigratedEntity {
+    pub uid: String,
+    pub class: String,
+    /// Attributes added to the class, applied to the entity.
+    pub added: Vec<String>,
+    /// Attributes removed from the class, dropped from the entity.
+    pub removed: Vec<String>,
+    /// The `Version` of the entity before and after migrating it, when its
+    /// class declares one.
+    pub versions: Option<(u64, u64)>,
+    /// The steps run, as the class names and versions they were declared
+    /// for.
+    pub steps: Vec<(String, u64)>,
+}
+
+impl fmt::Display for MigratedEntity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.class, self.uid)?;
+        if let Some((from, to)) = self.versions {
+            write!(f, " version {} to {}", from, to)?;
+        }
+        if !self.added.is_empty() {
+            write!(f, " added {}", self.added.join(", "))?;
+        }
+        if !self.removed.is_empty() {
+            write!(f, " removed {}", self.removed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of migrating a sandbox.
+#[derive(Clone, Debug, Default)]
+pub struct MigrationReport {
+    /// Every entity migrated.
+    pub entities: Vec<MigratedEntity>,
+    /// Whether the sandbox had no stored model, so the attributes of each
+    /// entity were compared with those of its class instead.
+    pub baseline: bool,
+}
+
+/// The model of the scrolls loaded by an instance.
+pub fn scroll_model(instance: &SandboxInstance) -> ScrollModel {
+    instance
+        .classes
+        .iter()
+        .map(|(name, class)| (name.clone(), class.attrs.keys().cloned().collect()))
+        .collect()
+}
+
+/// Read the model stored in a sandbox, unless created before models were
+/// stored.
+pub fn stored_model<T: ReadOnlyLoader>(tx: &T) -> Result<Option<ScrollModel>> {
+    match tx.retrieve("$model") {
+        Ok(model) => Ok(Some(serde_json::from_value(model.value)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Store the model of the scrolls loaded by an instance in its sandbox.
+pub fn store_model(instance: &SandboxInstance, tx: &mut ReadWriteTransaction) -> Result<()> {
+    tx.store("$model", &serde_json::to_value(scroll_model(instance))?)
+}
+
+/// The classes of the stored model having attributes added or removed by
+/// the scrolls loaded by an instance, out of the classes having entities in
+/// the sandbox, including entities of their subclasses (see
+/// `ReadOnlyLoader::indexed_classes`).
+pub fn changed_classes(
+    instance: &SandboxInstance,
+    classes: &[String],
+    model: &ScrollModel,
+) -> Vec<String> {
+    classes
+        .iter()
+        .filter(
+            |class_name| match (instance.classes.get(*class_name), model.get(*class_name)) {
+                (Some(class), Some(attrs)) => class.attrs.keys().ne(attrs.iter()),
+                _ => false,
+            },
+        )
+        .cloned()
+        .collect()
+}
+
+/// The uids of the entities of a class, not including entities of its
+/// subclasses, which are migrated using their own classes.
+fn class_entities<T: ReadOnlyLoader>(tx: &T, class_name: &str) -> Result<Vec<String>> {
+    let mut uids = tx.indexed(&IndexKey::Class(class_name.to_string()))?;
+    uids.retain(|uid| {
+        tx.retrieve(uid)
+            .is_ok_and(|entity| entity.value["class"] == class_name)
+    });
+    Ok(uids)
+}
+
+/// Migrate the entities of a sandbox to the scrolls loaded by the builder
+/// instance, reporting every entity migrated.
+///
+/// Entities whose class `Version` is older than the one declared by the
+/// scrolls are first migrated by running the steps declared for their
+/// class hierarchy, one version at a time. Then, attributes added to their
+/// class since the stored model are applied, unless already set by a step,
+/// and attributes removed from it are dropped, unrolling the entities
+/// rolled into them. Finally, the current model is stored.
+///
+/// Sandboxes without a stored model have the attributes each entity holds
+/// compared with those of its class instead, ignoring `$` entries,
+/// bookkeeping and the attributes injected by the scrolls.
+///
+/// Only attributes are compared, so changes to the commands of existing
+/// attributes, or to class constraints, need a step and a `Version` bump.
+pub fn migrate(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    migrations: &Migrations,
+) -> Result<MigrationReport> {
+    let instance = builder.sandbox;
+    let model = stored_model(tx)?;
+    let mut report = MigrationReport {
+        baseline: model.is_none(),
+        ..Default::default()
+    };
+    let injected = match model {
+        Some(_) => HashSet::new(),
+        None => injected_attrs(instance),
+    };
+    let mut class_names: Vec<&String> = instance.classes.keys().collect();
+    class_names.sort();
+    for class_name in class_names {
+        let class = &instance.classes[class_name];
+        let (added, removed) = match model.as_ref().and_then(|model| model.get(class_name)) {
+            Some(attrs) => (
+                class
+                    .attrs
+                    .keys()
+                    .filter(|attr| !attrs.contains(attr))
+                    .cloned()
+                    .collect(),
+                attrs
+                    .iter()
+                    .filter(|attr| !class.attrs.contains_key(*attr))
+                    .cloned()
+                    .collect(),
+            ),
+            None => (vec![], vec![]),
+        };
+        let version = class_version(class);
+        if model.is_some() && added.is_empty() && removed.is_empty() && version.is_none() {
+            continue;
+        }
+        let mut uids = class_entities(tx, class_name)?;
+        uids.sort();
+        for uid in uids {
+            let (added, removed) = match model {
+                Some(_) => (added.clone(), removed.clone()),
+                None => entity_changes(class, tx.load(&uid)?, &injected),
+            };
+            let migrated = migrate_entity(builder, tx, migrations, class, &uid, &added, &removed)?;
+            if let Some(migrated) = migrated {
+                report.entities.push(migrated);
+            }
+        }
+    }
+    store_model(instance, tx)?;
+    Ok(report)
+}
+
+/// The attributes injected into entities by any command of the scrolls
+/// loaded by an instance, which entities hold without their classes
+/// declaring them.
+fn injected_attrs(instance: &SandboxInstance) -> HashSet<String> {
+    instance
+        .classes
+        .values()
+        .flat_map(|class| class.attrs.values())
+        .flat_map(|attr| attr.cmd.injected_attrs())
+        .collect()
+}
+
+/// The attributes declared by a class but missing from an entity, and the
+/// attributes the entity holds that its class does not declare.
+fn entity_changes(
+    class: &Class,
+    entity: &serde_json::Value,
+    injected: &HashSet<String>,
+) -> (Vec<String>, Vec<String>) {
+    let added = class
+        .attrs
+        .keys()
+        .filter(|attr| entity.is_missing(attr))
+        .cloned()
+        .collect();
+    let removed = entity
+        .as_object()
+        .map(|values| {
+            values
+                .keys()
+                .filter(|attr| {
+                    !attr.starts_with('$')
+                        && !["uid", "uuid", "class", "parent_uid"].contains(&attr.as_str())
+                        && !class.attrs.contains_key(*attr)
+                        && !injected.contains(*attr)
+                })
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+    (added, removed)
+}
+
+fn migrate_entity(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    migrations: &Migrations,
+    class: &Class,
+    uid: &str,
+    added: &[String],
+    removed: &[String],
+) -> Result<Option<MigratedEntity>> {
+    let mut migrated = MigratedEntity {
+        uid: uid.to_string(),
+        class: class.name.clone(),
+        ..Default::default()
+    };
+
+    if let Some(version) = class_version(class) {
+        let entity_version = version_of(&tx.load(uid)?["Version"]).unwrap_or(0);
+        if entity_version < version {
+            for from in entity_version..version {
+                for class_name in class.hierarchy.iter() {
+                    if let Some(step) = migrations.steps.get(&(class_name.clone(), from)) {
+                        step(builder, tx, uid)?;
+                        migrated.steps.push((class_name.clone(), from));
+                    }
+                }
+            }
+            tx.load(uid)?["Version"] = serde_json::Value::from(version);
+            migrated.versions = Some((entity_version, version));
+        }
+    }
+
+    let class_chain = builder.enter_class(&class.name);
+    for attr_name in added {
+        if tx.load(uid)?.is_missing(attr_name) {
+            class.attrs[attr_name]
+                .cmd
+                .apply(&mut Context::Rolling, builder, tx, uid)?;
+            migrated.added.push(attr_name.clone());
+        }
+    }
+    drop(class_chain);
+
+    for attr_name in removed {
+        if !tx.load(uid)?.is_missing(attr_name) {
+            drop_attr(builder, tx, uid, attr_name)?;
+            migrated.removed.push(attr_name.clone());
+        }
+    }
+
+    if migrated.versions.is_none() && migrated.added.is_empty() && migrated.removed.is_empty() {
+        return Ok(None);
+    }
+    tx.save(uid)?;
+    Ok(Some(migrated))
+}
+
+/// Drop an attribute no longer declared by the class of an entity, along
+/// with the entities rolled into it and the uses of entities it holds.
+fn drop_attr(
+    builder: &SandboxBuilder,
+    tx: &mut ReadWriteTransaction,
+    uid: &str,
+    attr_name: &str,
+) -> Result<()> {
+    let value = tx.load(uid)?[attr_name].clone();
+    let referenced: HashSet<&str> = match &value {
+        serde_json::Value::String(other) => HashSet::from([other.as_str()]),
+        serde_json::Value::Array(others) => others.iter().filter_map(|v| v.as_str()).collect(),
+        _ => HashSet::new(),
+    };
+    for other in referenced {
+        let Ok(other_entity) = tx.load(other) else {
+            continue;
+        };
+        if other_entity["$parent"]["uid"] == uid && other_entity["$parent"]["attr"] == attr_name {
+            unroll_entity(builder, tx, other, None)?;
+        } else if let Some(users) = other_entity["$users"].as_array_mut() {
+            users.retain(|user| !(user["uid"] == uid && user["attr"] == attr_name));
+            tx.save(other)?;
+        }
+    }
+    let entity = tx.load(uid)?;
+    entity.clear(attr_name);
+    if let Some(fallbacks) = entity
+        .get_mut("$fallbacks")
+        .and_then(|fallbacks| fallbacks.as_object_mut())
+    {
+        fallbacks.swap_remove(attr_name);
+    }
+    Ok(())
+}
+
+/// The `Version` declared by a class, if any.
+fn class_version(class: &Class) -> Option<u64> {
+    class
+        .attrs
+        .get("Version")
+        .and_then(|attr| attr.cmd.value())
+        .and_then(|version| version.parse().ok())
+}
+
+fn version_of(value: &serde_json::Value) -> Option<u64> {
+    value
+        .as_u64()
+        .or_else(|| value.as_str().and_then(|version| version.parse().ok()))
+}
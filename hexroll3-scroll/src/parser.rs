@@ -23,8 +23,10 @@
 // for more information about commercial licensing terms.
 */
 use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
 use std::borrow::BorrowMut;
 use std::cell::{RefCell, RefMut};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -34,6 +36,7 @@ use pest::Parser;
 
 use crate::commands::*;
 use crate::instance::*;
+use crate::metadata::hash_source;
 use crate::semantics::*;
 
 #[derive(Parser)]
@@ -66,13 +69,69 @@ pub fn parse_buffer(
     filepath: Option<&str>,
     filename: Option<&str>,
 ) -> Result<()> {
+    let name = match filename {
+        Some(filename) => filename
+            .strip_prefix(filepath.unwrap_or(""))
+            .unwrap_or(filename)
+            .trim_start_matches('/')
+            .to_string(),
+        None => format!("buffer{}", instance.scroll_sources.roots.len() + 1),
+    };
+    instance.scroll_sources.roots.push(name.clone());
+    parse_source(
+        instance,
+        buffer,
+        filepath.unwrap_or(""),
+        &name,
+        filename.unwrap_or("buffer"),
+        None,
+    )
+}
+
+/// The sources of the scrolls parsed by an instance, which can be embedded
+/// in the sandboxes it creates and parsed again with no scroll directory.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScrollSources {
+    /// The names of the scrolls parsed directly, in the order parsed.
+    pub roots: Vec<String>,
+    /// The source of every scroll parsed, including those included by
+    /// other scrolls, keyed by its path relative to the scroll directory.
+    pub files: BTreeMap<String, String>,
+}
+
+/// Parse scroll sources, resolving includes from the sources themselves
+/// rather than from a scroll directory.
+pub fn parse_sources(instance: &mut SandboxInstance, sources: &ScrollSources) -> Result<()> {
+    for name in sources.roots.iter() {
+        let buffer = sources
+            .files
+            .get(name)
+            .ok_or_else(|| anyhow!("Scroll {} is missing from the sources", name))?;
+        instance.scroll_sources.roots.push(name.clone());
+        parse_source(instance, buffer, "", name, name, Some(sources))?;
+    }
+    Ok(())
+}
+
+/// Parse the source of a scroll named `name`, recording it in the scroll
+/// sources of the instance. Includes are resolved from `sources` when
+/// given, and from `include_path` otherwise.
+fn parse_source(
+    instance: &mut SandboxInstance,
+    buffer: &str,
+    include_path: &str,
+    name: &str,
+    filename: &str,
+    sources: Option<&ScrollSources>,
+) -> Result<()> {
+    instance.scroll_hash = Some(hash_source(instance.scroll_hash, buffer));
+    instance
+        .scroll_sources
+        .files
+        .insert(name.to_string(), buffer.to_string());
     match ScrollParser::parse(Rule::file, buffer) {
-        Ok(pairs) => parse_scroll(instance, pairs, filepath.unwrap_or("")),
-        Err(e) => Err(anyhow!(
-            "Parsing {} failed! {:#}",
-            filename.unwrap_or("buffer"),
-            e.to_string()
-        )),
+        Ok(pairs) => parse_scroll(instance, pairs, include_path, sources),
+        Err(e) => Err(anyhow!("Parsing {} failed! {:#}", filename, e.to_string())),
     }
 }
 
@@ -321,6 +380,8 @@ fn parse_entity_attribute<CMD: AttrCommand + Send + Sync + EntityAssigner + 'sta
         prependers: Vec::new(),
         appenders: Vec::new(),
     };
+    let mut selection = Selection::default();
+    let mut fallback = Fallback::default();
 
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
@@ -363,6 +424,12 @@ fn parse_entity_attribute<CMD: AttrCommand + Send + Sync + EntityAssigner + 'sta
             Rule::injections => {
                 injectors = parse_injections(inner_pair).unwrap();
             }
+            Rule::selection => {
+                selection = parse_selection(inner_pair);
+            }
+            Rule::fallback => {
+                fallback = parse_fallback(inner_pair);
+            }
             _ => unreachable!(),
         }
     }
@@ -375,7 +442,11 @@ fn parse_entity_attribute<CMD: AttrCommand + Send + Sync + EntityAssigner + 'sta
         class.add_attr(
             attr.to_string(),
             Attr {
-                cmd: std::sync::Arc::new(CMD::new(attr.to_string(), value, min, max, injectors)),
+                cmd: std::sync::Arc::new(
+                    CMD::new(attr.to_string(), value, min, max, injectors)
+                        .with_selection(selection)
+                        .with_fallback(fallback),
+                ),
                 is_public,
                 is_optional,
                 is_array,
@@ -386,15 +457,100 @@ fn parse_entity_attribute<CMD: AttrCommand + Send + Sync + EntityAssigner + 'sta
     }
 }
 
+fn parse_selection(pair: Pair<Rule>) -> Selection {
+    let mut selection = Selection::default();
+    for option in pair.into_inner() {
+        match option.as_rule() {
+            Rule::selection_strategy => {
+                selection.strategy = match option.as_str() {
+                    "nearest" => SelectionStrategy::Nearest,
+                    "least_picked" => SelectionStrategy::LeastPicked,
+                    "round_robin" => SelectionStrategy::RoundRobin,
+                    _ => SelectionStrategy::Random,
+                }
+            }
+            Rule::selection_weighted => {
+                let attr = option.into_inner().next().unwrap().as_str();
+                selection.strategy = SelectionStrategy::Weighted(attr.to_string());
+            }
+            Rule::selection_filter => selection.filters.push(parse_selection_filter(option)),
+            _ => unreachable!(),
+        }
+    }
+    selection
+}
+
+fn parse_selection_filter(pair: Pair<Rule>) -> SelectionFilter {
+    let mut inner = pair.into_inner();
+    let attr = inner.next().unwrap().as_str().to_string();
+    let operator = match inner.next().map(|op| op.as_str()) {
+        None => FilterOperator::Truthy,
+        Some("==") | Some("=") => FilterOperator::Equal,
+        Some("!=") => FilterOperator::NotEqual,
+        Some(">") => FilterOperator::Greater,
+        Some(">=") => FilterOperator::GreaterOrEqual,
+        Some("<") => FilterOperator::Less,
+        Some("<=") => FilterOperator::LessOrEqual,
+        Some(_) => unreachable!(),
+    };
+    let value = inner
+        .next()
+        .map(|value| {
+            let value = value.as_str().trim();
+            if value.eq_ignore_ascii_case("true") || value.eq_ignore_ascii_case("false") {
+                upcast_string(&value.to_ascii_lowercase())
+            } else {
+                upcast_string(value)
+            }
+        })
+        .unwrap_or(serde_json::Value::Null);
+    SelectionFilter {
+        attr,
+        operator,
+        value,
+    }
+}
+
+fn parse_fallback(pair: Pair<Rule>) -> Fallback {
+    let option = pair
+        .into_inner()
+        .find(|p| p.as_rule() != Rule::fallback_keyword)
+        .unwrap();
+    match option.as_rule() {
+        Rule::fallback_fail => Fallback::Fail,
+        Rule::fallback_roll => {
+            Fallback::Roll(option.into_inner().next().unwrap().as_str().to_string())
+        }
+        Rule::fallback_select => {
+            Fallback::Select(option.into_inner().next().unwrap().as_str().to_string())
+        }
+        _ => unreachable!(),
+    }
+}
+
 fn parse_collection(pair: Pair<Rule>, mut class: RefMut<ClassBuilder>) {
     let mut class_name: Option<String> = None;
     let mut named_collection: Option<String> = None;
     let mut is_optional = false;
     let is_array = true;
     let mut is_public = false;
+    let mut filters = vec![];
+    let mut limit = None;
     for inner_pair in pair.into_inner() {
         match inner_pair.as_rule() {
             Rule::entity_name => class_name = Some(inner_pair.as_str().to_string()),
+            Rule::collection_criteria => {
+                for option in inner_pair.into_inner() {
+                    match option.as_rule() {
+                        Rule::collection_limit => {
+                            let number = option.into_inner().next().unwrap();
+                            limit = Some(number.as_str().parse::<usize>().unwrap());
+                        }
+                        Rule::selection_filter => filters.push(parse_selection_filter(option)),
+                        _ => unreachable!(),
+                    }
+                }
+            }
             Rule::property => {
                 for property_pair in inner_pair.into_inner() {
                     match property_pair.as_rule() {
@@ -421,10 +577,25 @@ fn parse_collection(pair: Pair<Rule>, mut class: RefMut<ClassBuilder>) {
         class.collect(CollectionSpecifier {
             class_name,
             virtual_attribute,
+            filters,
+            limit,
         });
     }
 }
 
+fn parse_constraint(pair: Pair<Rule>, mut class: RefMut<ClassBuilder>) {
+    let rule = pair.as_rule();
+    let mut inner = pair.into_inner().skip(1);
+    let value = inner.next().unwrap().as_str();
+    let scope = inner.next().unwrap().as_str();
+    let kind = if rule == Rule::unique_constraint {
+        ConstraintKind::Unique(value.to_string())
+    } else {
+        ConstraintKind::Quota(value.parse::<usize>().unwrap())
+    };
+    class.constrain(kind, scope);
+}
+
 fn parse_roll_from_list(name: String, pair: Pairs<Rule>) -> Arc<dyn AttrCommand + Send + Sync> {
     let mut value: Vec<serde_json::Value> = vec![];
 
@@ -457,10 +628,30 @@ fn parse_context(name: String, mut pair: Pairs<Rule>) -> Arc<dyn AttrCommand + S
     match next.as_rule() {
         Rule::context | Rule::context_ptr => {
             let mut context_rule = next.into_inner();
+            let target = context_rule.next().unwrap();
+            let (relation, context_parent) = match target.as_rule() {
+                Rule::context_sibling => (
+                    ContextRelation::Sibling,
+                    target.into_inner().next().unwrap().as_str().to_string(),
+                ),
+                Rule::context_descendant => {
+                    let mut scope = None;
+                    let mut context_parent = String::new();
+                    for inner in target.into_inner() {
+                        match inner.as_rule() {
+                            Rule::context_scope => scope = Some(inner.as_str().to_string()),
+                            _ => context_parent = inner.as_str().to_string(),
+                        }
+                    }
+                    (ContextRelation::Descendant(scope), context_parent)
+                }
+                _ => (ContextRelation::Ancestor, target.as_str().to_string()),
+            };
             Arc::new(AttrCommandContext {
                 name,
-                context_parent: context_rule.next().unwrap().as_str().to_string(),
+                context_parent,
                 context_attr: context_rule.next().unwrap().as_str().to_string(),
+                relation,
             })
         }
         _ => unreachable!(),
@@ -645,6 +836,21 @@ fn parse_entity(instance: &mut SandboxInstance, pair: Pair<Rule>) -> Result<Clas
             Rule::collect => {
                 parse_collection(inner_pair, class_builder.borrow_mut());
             }
+            Rule::unique_constraint | Rule::quota_constraint => {
+                parse_constraint(inner_pair, class_builder.borrow_mut());
+            }
+            Rule::predicate => {
+                for filter in inner_pair.into_inner().skip(1) {
+                    class_builder
+                        .borrow_mut()
+                        .predicate(parse_selection_filter(filter));
+                }
+            }
+            Rule::index_declaration => {
+                for attr in inner_pair.into_inner().skip(1) {
+                    class_builder.borrow_mut().index(attr.as_str());
+                }
+            }
             Rule::tags => {
                 parse_entity_tags(inner_pair.into_inner(), class_builder.borrow_mut());
             }
@@ -666,6 +872,7 @@ fn parse_scroll(
     instance: &mut SandboxInstance,
     pairs: Pairs<Rule>,
     include_path: &str,
+    sources: Option<&ScrollSources>,
 ) -> Result<()> {
     for pair in pairs {
         match pair.as_rule() {
@@ -707,10 +914,26 @@ fn parse_scroll(
             Rule::include_stmt => {
                 let mut ip = pair.into_inner();
                 let what = ip.next().unwrap().as_str();
-                let path = String::from_str(include_path).unwrap() + what + ".scroll";
-                log::info!("importing {}", path);
-                let unparsed_file = std::fs::read_to_string(path.clone())?;
-                parse_buffer(instance, &unparsed_file, Some(include_path), Some(&path))?;
+                let name = format!("{}.scroll", what.trim_start_matches('/'));
+                let (unparsed_file, path) = match sources {
+                    Some(sources) => match sources.files.get(&name) {
+                        Some(source) => (source.clone(), name.clone()),
+                        None => return Err(anyhow!("Scroll {} is missing from the sources", name)),
+                    },
+                    None => {
+                        let path = String::from_str(include_path).unwrap() + what + ".scroll";
+                        log::info!("importing {}", path);
+                        (std::fs::read_to_string(path.clone())?, path)
+                    }
+                };
+                parse_source(
+                    instance,
+                    &unparsed_file,
+                    include_path,
+                    &name,
+                    &path,
+                    sources,
+                )?;
             }
             Rule::EOI => {}
             _ => unreachable!(),
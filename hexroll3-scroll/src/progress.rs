@@ -0,0 +1,252 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, Result};
+
+use crate::instance::*;
+use crate::semantics::*;
+
+/// Progress of a generation, reported to the `BuilderOptions::on_progress`
+/// callback every time an entity is rolled.
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    /// The number of entities rolled so far.
+    pub rolled: usize,
+    /// The number of entities rolled so far for each class.
+    pub rolled_per_class: HashMap<String, usize>,
+    /// The depth, in the entity hierarchy, of the entity just rolled.
+    pub depth: usize,
+    /// A rough estimate of the total number of entities this generation
+    /// will roll, or 0 when unknown.
+    pub estimated_total: usize,
+}
+
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// A token used to cancel a generation from another thread.
+///
+/// Cancellation is checked between commands, and a cancelled generation
+/// fails, leaving the transaction it ran in uncommitted.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// ProgressTracker accumulates the progress of a generation, and is shared
+/// by a builder and all the worker builders forked from it.
+pub struct ProgressTracker {
+    progress: Mutex<Progress>,
+    callback: Option<ProgressCallback>,
+    cancellation: CancellationToken,
+    limits: Limits,
+    started: Option<Instant>,
+    // Entities whose roll has begun, counted against the limits
+    begun: Mutex<(usize, HashMap<String, usize>)>,
+}
+
+impl ProgressTracker {
+    pub fn new(options: &BuilderOptions) -> Self {
+        ProgressTracker {
+            progress: Mutex::new(Progress::default()),
+            callback: options.on_progress.clone(),
+            cancellation: options.cancellation.clone(),
+            limits: options.limits.clone(),
+            // Only read the clock when needed, as it is unavailable in wasm
+            started: options.limits.max_duration.map(|_| Instant::now()),
+            begun: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.progress.lock().unwrap().clone()
+    }
+
+    pub fn set_estimated_total(&self, estimated_total: usize) {
+        self.progress.lock().unwrap().estimated_total = estimated_total;
+    }
+
+    /// Record a rolled entity and report the progress to the callback.
+    pub fn rolled(&self, class_name: &str, depth: usize) {
+        let progress = {
+            let mut progress = self.progress.lock().unwrap();
+            progress.rolled += 1;
+            *progress
+                .rolled_per_class
+                .entry(class_name.to_string())
+                .or_default() += 1;
+            progress.depth = depth;
+            self.callback.as_ref().map(|_| progress.clone())
+        };
+        if let (Some(callback), Some(progress)) = (&self.callback, progress) {
+            callback(&progress);
+        }
+    }
+
+    /// Record the beginning of rolling an entity of the given class, failing
+    /// if it would exceed any of the generation limits.
+    pub fn begin_roll(&self, class_name: &str, class_chain: &[String]) -> Result<()> {
+        let limits = &self.limits;
+        let exceeded = |what: String| {
+            Err(anyhow!(
+                "Exceeded the {} when rolling {}",
+                what,
+                class_chain
+                    .iter()
+                    .map(String::as_str)
+                    .chain([class_name])
+                    .collect::<Vec<_>>()
+                    .join(" > ")
+            ))
+        };
+        if let Some(max_depth) = limits.max_depth {
+            if class_chain.len() >= max_depth {
+                return exceeded(format!("maximum depth of {}", max_depth));
+            }
+        }
+        if let (Some(max_duration), Some(started)) = (limits.max_duration, self.started) {
+            if started.elapsed() > max_duration {
+                return exceeded(format!("maximum duration of {:?}", max_duration));
+            }
+        }
+        let mut begun = self.begun.lock().unwrap();
+        let (total, per_class) = &mut *begun;
+        let class_total = per_class.entry(class_name.to_string()).or_default();
+        if let Some(max_entities) = limits.max_entities {
+            if *total >= max_entities {
+                return exceeded(format!("maximum of {} entities", max_entities));
+            }
+        }
+        if let Some(max_entities_per_class) = limits.max_entities_per_class {
+            if *class_total >= max_entities_per_class {
+                return exceeded(format!(
+                    "maximum of {} {} entities",
+                    max_entities_per_class, class_name
+                ));
+            }
+        }
+        *total += 1;
+        *class_total += 1;
+        Ok(())
+    }
+
+    /// Fail if the generation was cancelled.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.cancellation.is_cancelled() {
+            Err(anyhow!("Generation was cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Estimate the number of entities rolling an entity of the given class
+/// would roll, including itself.
+///
+/// The estimate assumes every roll yields the average of its cardinality,
+/// every subclass is equally likely, and ignores recursive classes.
+pub fn estimate_entities(builder: &SandboxBuilder, class_name: &str) -> usize {
+    let mut memo = HashMap::new();
+    estimate_class(builder, class_name, &mut memo, &mut HashSet::new()).round() as usize
+}
+
+fn estimate_class(
+    builder: &SandboxBuilder,
+    class_name: &str,
+    memo: &mut HashMap<String, f64>,
+    visiting: &mut HashSet<String>,
+) -> f64 {
+    if let Some(estimate) = memo.get(class_name) {
+        return *estimate;
+    }
+    let Some(class) = builder.sandbox.classes.get(class_name) else {
+        return 0.0;
+    };
+    if !visiting.insert(class_name.to_string()) {
+        return 0.0;
+    }
+    let subclasses: Vec<String> = match &class.subclasses {
+        SubclassesSpecifier::List(list) => list.clone(),
+        SubclassesSpecifier::Var(variable_symbol) => builder
+            .sandbox
+            .global(&variable_symbol[1..])
+            .and_then(|v| v.as_array())
+            .map(|list| {
+                list.iter()
+                    .filter_map(|v| v.as_str().map(|s| s.trim().to_string()))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        SubclassesSpecifier::Empty() => vec![],
+    };
+    let estimate = if subclasses.is_empty() {
+        1.0 + class
+            .attrs
+            .values()
+            .filter_map(|attr| attr.cmd.expected_rolls(builder))
+            .map(|(class_names, count)| {
+                count
+                    * average(
+                        class_names
+                            .iter()
+                            .map(|class_name| estimate_class(builder, class_name, memo, visiting)),
+                    )
+            })
+            .sum::<f64>()
+    } else {
+        average(
+            subclasses
+                .iter()
+                .map(|subclass| estimate_class(builder, subclass, memo, visiting)),
+        )
+    };
+    visiting.remove(class_name);
+    memo.insert(class_name.to_string(), estimate);
+    estimate
+}
+
+fn average(values: impl Iterator<Item = f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        sum / count as f64
+    }
+}
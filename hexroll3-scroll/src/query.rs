@@ -0,0 +1,238 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+
+use crate::frame::{filter_matches, filter_value};
+use crate::instance::*;
+use crate::repository::*;
+use crate::semantics::*;
+
+/// A query over the entities stored in a sandbox, for example every
+/// `Dungeon` in a realm, sorted by name:
+///
+/// ```ignore
+/// let dungeons = instance.query(&Query {
+///     class: Some("Dungeon".to_string()),
+///     within: Some(realm_uid),
+///     sort_by: Some("Name".to_string()),
+///     limit: Some(20),
+///     ..Default::default()
+/// })?;
+/// ```
+///
+/// Candidates are found using the secondary indexes maintained for
+/// entities, by class, by parent and by the attributes declared as indexed
+/// in scrolls, and are then filtered, sorted and paginated.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// Only entities of this class, including entities of its subclasses.
+    pub class: Option<String>,
+    /// Only the child entities of this entity.
+    pub parent: Option<String>,
+    /// Only the descendants of this entity.
+    pub within: Option<String>,
+    /// Filters entities have to meet, where attributes can be paths into
+    /// child or referenced entities (see `SelectionFilter`).
+    pub filters: Vec<SelectionFilter>,
+    /// The attribute path to sort entities by, in uid order when unset.
+    /// Entities missing the attribute are sorted last.
+    pub sort_by: Option<String>,
+    pub descending: bool,
+    /// The number of matching entities to skip.
+    pub offset: usize,
+    /// The maximum number of entities to return.
+    pub limit: Option<usize>,
+}
+
+/// A page of entities matching a query.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryResult {
+    /// The number of entities matching the query, on all pages.
+    pub total: usize,
+    /// The uids of the entities on the requested page.
+    pub uids: Vec<String>,
+}
+
+pub fn query<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    query: &Query,
+) -> Result<QueryResult> {
+    let mut within = HashMap::new();
+    let mut matches = vec![];
+    for uid in candidates(instance, tx, query)? {
+        let entity = tx.retrieve(&uid)?.value;
+        let Some(class) = entity["class"]
+            .as_str()
+            .and_then(|class| instance.classes.get(class))
+        else {
+            continue;
+        };
+        if query
+            .class
+            .as_ref()
+            .is_some_and(|name| !class.hierarchy.contains(name))
+            || query
+                .parent
+                .as_ref()
+                .is_some_and(|parent_uid| entity["parent_uid"] != parent_uid.as_str())
+            || !query.filters.iter().all(|f| filter_matches(tx, f, &entity))
+        {
+            continue;
+        }
+        if let Some(ancestor_uid) = &query.within {
+            if !is_within(tx, &entity, ancestor_uid, &mut within)? {
+                continue;
+            }
+        }
+        let sort_value = match &query.sort_by {
+            Some(attr) => filter_value(tx, &entity, attr),
+            None => serde_json::Value::Null,
+        };
+        matches.push((uid, sort_value));
+    }
+    if query.sort_by.is_some() {
+        matches.sort_by(|(_, a), (_, b)| match (a.is_null(), b.is_null()) {
+            (false, false) if query.descending => compare_values(b, a),
+            (false, false) => compare_values(a, b),
+            (a_null, b_null) => a_null.cmp(&b_null),
+        });
+    }
+    Ok(QueryResult {
+        total: matches.len(),
+        uids: matches
+            .into_iter()
+            .skip(query.offset)
+            .take(query.limit.unwrap_or(usize::MAX))
+            .map(|(uid, _)| uid)
+            .collect(),
+    })
+}
+
+/// The uids of the entities that might match a query, in uid order, found
+/// by intersecting every index the query can use, or all the entities when
+/// it can use none.
+fn candidates<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    query: &Query,
+) -> Result<Vec<String>> {
+    let mut indexed: Vec<HashSet<String>> = vec![];
+    if let Some(parent_uid) = &query.parent {
+        indexed.push(HashSet::from_iter(
+            tx.indexed(&IndexKey::Parent(parent_uid.clone()))?,
+        ));
+    }
+    if let Some(class_name) = &query.class {
+        let classes: Vec<&Class> = instance
+            .classes
+            .values()
+            .filter(|class| class.hierarchy.contains(class_name))
+            .collect();
+        indexed.push(HashSet::from_iter(
+            tx.indexed(&IndexKey::Class(class_name.clone()))?,
+        ));
+        // Attributes can only be looked up when indexed for every class
+        // the query matches.
+        for filter in query.filters.iter() {
+            if filter.operator == FilterOperator::Equal
+                && classes
+                    .iter()
+                    .all(|class| class.indexed.contains(&filter.attr))
+            {
+                indexed.push(HashSet::from_iter(tx.indexed(&IndexKey::Attribute(
+                    filter.attr.clone(),
+                    filter.value.clone(),
+                ))?));
+            }
+        }
+    }
+    indexed.sort_by_key(|uids| uids.len());
+    let mut uids: Vec<String> = match indexed.split_first() {
+        Some((smallest, others)) => smallest
+            .iter()
+            .filter(|uid| others.iter().all(|uids| uids.contains(*uid)))
+            .cloned()
+            .collect(),
+        None => tx
+            .uids()?
+            .into_iter()
+            .filter(|uid| !uid.ends_with("_frame") && uid != "root" && !uid.starts_with('$'))
+            .collect(),
+    };
+    uids.sort();
+    Ok(uids)
+}
+
+/// Whether an entity is a descendant of an ancestor, remembering the
+/// answer for every entity on the way up.
+fn is_within<T: ReadOnlyLoader>(
+    tx: &T,
+    entity: &serde_json::Value,
+    ancestor_uid: &str,
+    within: &mut HashMap<String, bool>,
+) -> Result<bool> {
+    let mut visited = vec![];
+    let mut uid = entity["parent_uid"].as_str().unwrap_or("root").to_string();
+    let answer = loop {
+        if uid == ancestor_uid {
+            break true;
+        }
+        if uid == "root" {
+            break false;
+        }
+        if let Some(answer) = within.get(&uid) {
+            break *answer;
+        }
+        visited.push(uid.clone());
+        uid = tx.retrieve(&uid)?.value["parent_uid"]
+            .as_str()
+            .unwrap_or("root")
+            .to_string();
+    };
+    for uid in visited {
+        within.insert(uid, answer);
+    }
+    Ok(answer)
+}
+
+/// Compare attribute values for sorting: numbers numerically, arrays of
+/// entities by their length and anything else by its text.
+fn compare_values(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+    let measure = |value: &serde_json::Value| match value {
+        serde_json::Value::Array(items) => Some(items.len() as f64),
+        value => value.as_f64(),
+    };
+    match (measure(a), measure(b)) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => match (a.as_str(), b.as_str()) {
+            (Some(a), Some(b)) => a.cmp(b),
+            _ => a.to_string().cmp(&b.to_string()),
+        },
+    }
+}
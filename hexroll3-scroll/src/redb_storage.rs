@@ -0,0 +1,569 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use crate::{
+    encoding::*,
+    repository::JsonValue,
+    storage::{BoxedStorageReader, Savepoint, Storage, StorageReader, StorageWriter},
+};
+use anyhow::{anyhow, Result};
+use redb::{ReadableMultimapTable, ReadableTable, ReadableTableMetadata};
+use std::path::{Path, PathBuf};
+
+/// The main table, holding all entities and their frames keyed by uid.
+const ENTITIES_TABLE: redb::TableDefinition<String, EncodedValue> =
+    redb::TableDefinition::new("my_data2");
+
+/// Secondary indexes of entities, mapping encoded index keys to entity uids.
+const INDEX_TABLE: redb::MultimapTableDefinition<String, String> =
+    redb::MultimapTableDefinition::new("indexes");
+
+/// Full-text search documents, keyed by the uid of the entity they describe.
+const SEARCH_TABLE: redb::TableDefinition<String, EncodedValue> =
+    redb::TableDefinition::new("search");
+
+/// Metadata of the repository, such as the sandbox it holds and the scrolls
+/// that generated it, keyed by name.
+const METADATA_TABLE: redb::TableDefinition<String, EncodedValue> =
+    redb::TableDefinition::new("metadata");
+
+/// Compression dictionaries used for encoding values, keyed by their id.
+const DICTIONARIES_TABLE: redb::TableDefinition<u32, &[u8]> =
+    redb::TableDefinition::new("dictionaries");
+
+/// Maps snapshot names to the ids of redb persistent savepoints.
+const SNAPSHOTS_TABLE: redb::TableDefinition<String, u64> = redb::TableDefinition::new("snapshots");
+
+/// A storage backend keeping a repository in a redb file.
+///
+/// Snapshots are stored as redb persistent savepoints inside the file, so
+/// they survive closing and reopening it.
+pub struct RedbStorage {
+    db: redb::Database,
+    filepath: PathBuf,
+}
+
+impl RedbStorage {
+    pub fn create(filename: &str) -> Result<Self> {
+        let storage = RedbStorage {
+            db: redb::Database::create(filename)?,
+            filepath: PathBuf::from(filename),
+        };
+        // Create the tables right away, so they can be read before the
+        // first write is committed
+        storage.write(ValueEncoding::default(), &mut |_| Ok(()))?;
+        Ok(storage)
+    }
+
+    pub fn open(filename: &str) -> Result<Self> {
+        Ok(RedbStorage {
+            db: redb::Database::open(filename)?,
+            filepath: PathBuf::from(filename),
+        })
+    }
+
+    fn begin_write(&self) -> Result<redb::WriteTransaction> {
+        self.db
+            .begin_write()
+            .map_err(|_| anyhow!("Failed to begin write transaction"))
+    }
+
+    fn begin_read(&self) -> Result<redb::ReadTransaction> {
+        self.db
+            .begin_read()
+            .map_err(|_| anyhow!("Failed to begin read transaction"))
+    }
+}
+
+impl Storage for RedbStorage {
+    fn reader(&self) -> Result<BoxedStorageReader> {
+        let tx = self.begin_read()?;
+        Ok(Box::new(RedbReader {
+            entities: tx
+                .open_table(ENTITIES_TABLE)
+                .map_err(|_| anyhow!("Failed to open table"))?,
+            // Sandboxes created before indexing was introduced have no index
+            index: tx.open_multimap_table(INDEX_TABLE).ok(),
+            documents: tx.open_table(SEARCH_TABLE).ok(),
+            metadata: tx.open_table(METADATA_TABLE).ok(),
+        }))
+    }
+
+    fn write(
+        &self,
+        encoding: ValueEncoding,
+        f: &mut dyn FnMut(&mut dyn StorageWriter) -> Result<()>,
+    ) -> Result<()> {
+        let tx = self.begin_write()?;
+        {
+            let mut writer = RedbWriter {
+                entities: tx.open_table(ENTITIES_TABLE)?,
+                index: tx.open_multimap_table(INDEX_TABLE)?,
+                documents: tx.open_table(SEARCH_TABLE)?,
+                metadata: tx.open_table(METADATA_TABLE)?,
+                encoding,
+            };
+            f(&mut writer)?;
+        }
+        tx.commit()
+            .map_err(|_| anyhow!("Failed to commit transaction"))
+    }
+
+    fn savepoint(&self) -> Result<Savepoint> {
+        Ok(Savepoint::new(self.begin_write()?.ephemeral_savepoint()?))
+    }
+
+    fn restore(&self, savepoint: &Savepoint) -> Result<()> {
+        let mut tx = self.begin_write()?;
+        tx.restore_savepoint(savepoint.downcast_ref()?)?;
+        Ok(tx.commit()?)
+    }
+
+    fn snapshot(&self, name: &str) -> Result<()> {
+        let tx = self.begin_write()?;
+        let id = tx.persistent_savepoint()?;
+        {
+            let mut table = tx.open_table(SNAPSHOTS_TABLE)?;
+            if table.get(name.to_string())?.is_some() {
+                return Err(anyhow!("Snapshot {} already exists", name));
+            }
+            table.insert(name.to_string(), id)?;
+        }
+        tx.commit()
+            .map_err(|_| anyhow!("Failed to commit transaction"))?;
+        Ok(())
+    }
+
+    fn snapshots(&self) -> Result<Vec<String>> {
+        let tx = self.begin_read()?;
+        // Repositories that never had a snapshot have no snapshots table
+        let mut snapshots = match tx.open_table(SNAPSHOTS_TABLE) {
+            Ok(table) => read_snapshots(&table)?,
+            Err(_) => vec![],
+        };
+        snapshots.sort_by_key(|(_, id)| *id);
+        Ok(snapshots.into_iter().map(|(name, _)| name).collect())
+    }
+
+    fn restore_snapshot(&self, name: &str) -> Result<()> {
+        let mut tx = self.begin_write()?;
+        let snapshots = read_snapshots(&tx.open_table(SNAPSHOTS_TABLE)?)?;
+        let savepoint = tx.get_persistent_savepoint(snapshot_id(&snapshots, name)?)?;
+        tx.restore_savepoint(&savepoint)?;
+
+        // Restoring rolls back the snapshots table as well, so we write
+        // back every snapshot that is still valid after the restore.
+        let valid: Vec<u64> = tx.list_persistent_savepoints()?.collect();
+        {
+            let mut table = tx.open_table(SNAPSHOTS_TABLE)?;
+            table.retain(|_, _| false)?;
+            for (name, id) in snapshots.iter().filter(|(_, id)| valid.contains(id)) {
+                table.insert(name.to_string(), id)?;
+            }
+        }
+        tx.commit()
+            .map_err(|_| anyhow!("Failed to commit transaction"))?;
+        Ok(())
+    }
+
+    fn delete_snapshot(&self, name: &str) -> Result<()> {
+        let tx = self.begin_write()?;
+        let id = snapshot_id(&read_snapshots(&tx.open_table(SNAPSHOTS_TABLE)?)?, name)?;
+        tx.delete_persistent_savepoint(id)?;
+        tx.open_table(SNAPSHOTS_TABLE)?.remove(name.to_string())?;
+        tx.commit()
+            .map_err(|_| anyhow!("Failed to commit transaction"))?;
+        Ok(())
+    }
+
+    fn read_snapshot(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(&dyn StorageReader) -> Result<()>,
+    ) -> Result<()> {
+        // Restoring a savepoint invalidates every savepoint taken after it,
+        // even when the transaction is aborted, so the snapshot is restored
+        // in a copy of the file instead. Writers are only held off while
+        // copying it, so the copy holds no half-written commit.
+        let mut copy = self.filepath.clone().into_os_string();
+        copy.push(".snapshot");
+        let copy = PathBuf::from(copy);
+        {
+            let tx = self.begin_write()?;
+            snapshot_id(&read_snapshots(&tx.open_table(SNAPSHOTS_TABLE)?)?, name)?;
+            std::fs::copy(&self.filepath, &copy)?;
+            tx.abort()?;
+        }
+        let read = read_restored_snapshot(&copy, name, f);
+        std::fs::remove_file(&copy)?;
+        read
+    }
+
+    fn is_indexed(&self) -> Result<bool> {
+        let tx = self.begin_read()?;
+        Ok(tx.open_multimap_table(INDEX_TABLE).is_ok() && tx.open_table(SEARCH_TABLE).is_ok())
+    }
+
+    fn dictionaries(&self) -> Result<Vec<Vec<u8>>> {
+        let tx = self.begin_read()?;
+        let Ok(table) = tx.open_table(DICTIONARIES_TABLE) else {
+            return Ok(vec![]);
+        };
+        let mut dictionaries = Vec::new();
+        for entry in table.iter()? {
+            dictionaries.push(entry?.1.value().to_vec());
+        }
+        Ok(dictionaries)
+    }
+
+    fn add_dictionary(&self, id: u32, dictionary: &[u8]) -> Result<()> {
+        let tx = self.begin_write()?;
+        tx.open_table(DICTIONARIES_TABLE)?.insert(id, dictionary)?;
+        tx.commit()
+            .map_err(|_| anyhow!("Failed to commit transaction"))
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(std::fs::metadata(&self.filepath)?.len())
+    }
+
+    /// Compaction is skipped, returning `false`, while snapshots exist, since
+    /// redb cannot compact a file holding persistent savepoints.
+    fn compact(&mut self) -> Result<bool> {
+        if !self.snapshots()?.is_empty() {
+            return Ok(false);
+        }
+        self.db
+            .compact()
+            .map_err(|e| anyhow!("Failed to compact database: {}", e))
+    }
+}
+
+/// Restore a snapshot in a copy of a repository file, then read it.
+fn read_restored_snapshot(
+    filepath: &Path,
+    name: &str,
+    f: &mut dyn FnMut(&dyn StorageReader) -> Result<()>,
+) -> Result<()> {
+    let db = redb::Database::open(filepath)?;
+    let mut tx = db
+        .begin_write()
+        .map_err(|_| anyhow!("Failed to begin write transaction"))?;
+    let id = snapshot_id(&read_snapshots(&tx.open_table(SNAPSHOTS_TABLE)?)?, name)?;
+    let savepoint = tx.get_persistent_savepoint(id)?;
+    tx.restore_savepoint(&savepoint)?;
+    {
+        let reader = RedbWriter {
+            entities: tx.open_table(ENTITIES_TABLE)?,
+            index: tx.open_multimap_table(INDEX_TABLE)?,
+            documents: tx.open_table(SEARCH_TABLE)?,
+            metadata: tx.open_table(METADATA_TABLE)?,
+            encoding: ValueEncoding::default(),
+        };
+        f(&reader)?;
+    }
+    tx.abort()?;
+    Ok(())
+}
+
+fn read_snapshots(table: &impl ReadableTable<String, u64>) -> Result<Vec<(String, u64)>> {
+    let mut snapshots = Vec::new();
+    for entry in table.iter()? {
+        let (name, id) = entry?;
+        snapshots.push((name.value(), id.value()));
+    }
+    Ok(snapshots)
+}
+
+fn snapshot_id(snapshots: &[(String, u64)], name: &str) -> Result<u64> {
+    snapshots
+        .iter()
+        .find(|(snapshot_name, _)| snapshot_name == name)
+        .map(|(_, id)| *id)
+        .ok_or_else(|| anyhow!("Snapshot {} not found", name))
+}
+
+struct RedbReader {
+    entities: redb::ReadOnlyTable<String, EncodedValue>,
+    index: Option<redb::ReadOnlyMultimapTable<String, String>>,
+    documents: Option<redb::ReadOnlyTable<String, EncodedValue>>,
+    metadata: Option<redb::ReadOnlyTable<String, EncodedValue>>,
+}
+
+impl StorageReader for RedbReader {
+    fn get(&self, uid: &str) -> Result<Option<JsonValue>> {
+        table_value(&self.entities, uid)
+    }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        table_keys(&self.entities)
+    }
+
+    fn indexed(&self, key: &str) -> Result<Vec<String>> {
+        match &self.index {
+            Some(index) => multimap_values(index, key),
+            None => Err(anyhow!("Repository has no index")),
+        }
+    }
+
+    fn index_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        match &self.index {
+            Some(index) => multimap_keys(index, prefix),
+            None => Err(anyhow!("Repository has no index")),
+        }
+    }
+
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        match &self.documents {
+            Some(documents) => Ok(table_value(documents, uid)?.map(|document| document.value)),
+            None => Err(anyhow!("Repository has no search index")),
+        }
+    }
+
+    fn document_uids(&self) -> Result<Vec<String>> {
+        match &self.documents {
+            Some(documents) => table_keys(documents),
+            None => Err(anyhow!("Repository has no search index")),
+        }
+    }
+
+    fn documents_count(&self) -> Result<u64> {
+        match &self.documents {
+            Some(documents) => Ok(documents.len()?),
+            None => Err(anyhow!("Repository has no search index")),
+        }
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        match &self.metadata {
+            Some(metadata) => table_entries(metadata),
+            // Sandboxes created before metadata was introduced have none
+            None => Ok(vec![]),
+        }
+    }
+}
+
+struct RedbWriter<'a> {
+    entities: redb::Table<'a, String, EncodedValue>,
+    index: redb::MultimapTable<'a, String, String>,
+    documents: redb::Table<'a, String, EncodedValue>,
+    metadata: redb::Table<'a, String, EncodedValue>,
+    /// The encoding of values written.
+    encoding: ValueEncoding,
+}
+
+impl<'a> StorageReader for RedbWriter<'a> {
+    fn get(&self, uid: &str) -> Result<Option<JsonValue>> {
+        table_value(&self.entities, uid)
+    }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        table_keys(&self.entities)
+    }
+
+    fn indexed(&self, key: &str) -> Result<Vec<String>> {
+        multimap_values(&self.index, key)
+    }
+
+    fn index_keys(&self, prefix: &str) -> Result<Vec<String>> {
+        multimap_keys(&self.index, prefix)
+    }
+
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        Ok(table_value(&self.documents, uid)?.map(|document| document.value))
+    }
+
+    fn document_uids(&self) -> Result<Vec<String>> {
+        table_keys(&self.documents)
+    }
+
+    fn documents_count(&self) -> Result<u64> {
+        Ok(self.documents.len()?)
+    }
+
+    fn metadata(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        table_entries(&self.metadata)
+    }
+}
+
+impl<'a> StorageWriter for RedbWriter<'a> {
+    fn insert(&mut self, uid: &str, value: &serde_json::Value) -> Result<()> {
+        self.entities
+            .insert(uid.to_string(), encode(value, self.encoding)?.as_slice())?;
+        Ok(())
+    }
+
+    fn remove(&mut self, uid: &str) -> Result<()> {
+        self.entities.remove(uid.to_string())?;
+        Ok(())
+    }
+
+    fn index(&mut self, key: &str, uid: &str) -> Result<()> {
+        self.index.insert(key.to_string(), uid.to_string())?;
+        Ok(())
+    }
+
+    fn unindex(&mut self, key: &str, uid: &str) -> Result<()> {
+        self.index.remove(key.to_string(), uid.to_string())?;
+        Ok(())
+    }
+
+    fn take_indexed(&mut self, key: &str) -> Result<Vec<String>> {
+        let mut uids = Vec::new();
+        for uid in self.index.remove_all(key.to_string())? {
+            uids.push(uid?.value());
+        }
+        Ok(uids)
+    }
+
+    fn insert_document(&mut self, uid: &str, document: &serde_json::Value) -> Result<()> {
+        self.documents
+            .insert(uid.to_string(), encode(document, self.encoding)?.as_slice())?;
+        Ok(())
+    }
+
+    fn remove_document(&mut self, uid: &str) -> Result<()> {
+        self.documents.remove(uid.to_string())?;
+        Ok(())
+    }
+
+    fn clear_indexes(&mut self) -> Result<()> {
+        let mut keys = Vec::new();
+        for entry in self.index.iter()? {
+            keys.push(entry?.0.value());
+        }
+        for key in keys {
+            self.index.remove_all(key)?;
+        }
+        self.documents.retain(|_, _| false)?;
+        Ok(())
+    }
+
+    fn set_metadata(&mut self, key: &str, value: &serde_json::Value) -> Result<()> {
+        self.metadata
+            .insert(key.to_string(), encode(value, self.encoding)?.as_slice())?;
+        Ok(())
+    }
+}
+
+fn table_keys<T: ReadableTable<String, EncodedValue>>(table: &T) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for entry in table.iter()? {
+        keys.push(entry?.0.value());
+    }
+    Ok(keys)
+}
+
+fn table_entries<T: ReadableTable<String, EncodedValue>>(
+    table: &T,
+) -> Result<Vec<(String, serde_json::Value)>> {
+    let mut entries = Vec::new();
+    for entry in table.iter()? {
+        let (key, value) = entry?;
+        entries.push((key.value(), decode(value.value())?.0));
+    }
+    Ok(entries)
+}
+
+/// The decoded value of a key, failing rather than panicking when it cannot
+/// be decoded, for example when compressed using a missing dictionary.
+fn table_value<T: ReadableTable<String, EncodedValue>>(
+    table: &T,
+    key: &str,
+) -> Result<Option<JsonValue>> {
+    let Some(bytes) = table.get(key.to_string())? else {
+        return Ok(None);
+    };
+    let (value, encoding) = decode(bytes.value())
+        .map_err(|e| e.context(format!("Unable to decode the stored value of {}", key)))?;
+    Ok(Some(JsonValue { value, encoding }))
+}
+
+fn multimap_values<T: ReadableMultimapTable<String, String>>(
+    table: &T,
+    key: &str,
+) -> Result<Vec<String>> {
+    let mut values = Vec::new();
+    for value in table.get(key.to_string())? {
+        values.push(value?.value());
+    }
+    Ok(values)
+}
+
+fn multimap_keys<T: ReadableMultimapTable<String, String>>(
+    table: &T,
+    prefix: &str,
+) -> Result<Vec<String>> {
+    let mut keys = Vec::new();
+    for entry in table.range(prefix.to_string()..)? {
+        let key = entry?.0.value();
+        if !key.starts_with(prefix) {
+            break;
+        }
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// The bytes of a value as encoded by `encoding::encode`, which are decoded
+/// outside of redb, where failing to decode them can be reported.
+///
+/// It keeps the type name values were always stored under, so files written
+/// before remain readable.
+#[derive(Debug)]
+struct EncodedValue;
+
+impl redb::Value for EncodedValue {
+    type SelfType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+    type AsBytes<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn fixed_width() -> Option<usize> {
+        None
+    }
+
+    fn from_bytes<'a>(data: &'a [u8]) -> &'a [u8]
+    where
+        Self: 'a,
+    {
+        data
+    }
+
+    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> &'a [u8]
+    where
+        Self: 'a,
+        Self: 'b,
+    {
+        value
+    }
+
+    fn type_name() -> redb::TypeName {
+        redb::TypeName::new("test::JsonValue")
+    }
+}
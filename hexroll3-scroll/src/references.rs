@@ -0,0 +1,254 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::Result;
+
+use crate::instance::*;
+use crate::repository::*;
+
+/// The kind of a reference from one entity to another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ReferenceKind {
+    /// The entity was rolled as a child of the referencing entity.
+    Child,
+    /// The entity was picked by the referencing entity.
+    Pick,
+    /// The entity was used by the referencing entity.
+    Use,
+    /// An attribute of the entity is pointed-to by the referencing entity.
+    Pointer,
+    /// An attribute of the entity is copied by a context attribute of the
+    /// referencing entity.
+    Context,
+    /// The entity is collected in the frame of the referencing entity.
+    Collection,
+}
+
+/// A typed edge in the entity graph, from the referencing entity to the
+/// referenced entity, through an attribute of the referencing entity.
+///
+/// For collections, the attribute is the collection key in the frame.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Reference {
+    pub from: String,
+    pub to: String,
+    pub kind: ReferenceKind,
+    pub attr: String,
+}
+
+/// The direction to follow references when traversing the entity graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From referenced entities to the entities referencing them.
+    Incoming,
+    /// From referencing entities to the entities they reference.
+    Outgoing,
+}
+
+/// Find all the references to an entity, for example every entity that
+/// picked, used or points at an NPC.
+///
+/// Referencing entities are found using the reference index maintained
+/// when entities are stored (see `IndexKey::Reference`), listing the
+/// parent entity first, and collections holding the entity are found in
+/// the frames of its ancestors.
+pub fn references_to<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    uid: &str,
+) -> Result<Vec<Reference>> {
+    let entity = tx.retrieve(uid)?.value;
+    let mut references = vec![];
+    for from in tx.indexed(&IndexKey::Reference(uid.to_string()))? {
+        let Ok(referencing) = tx.retrieve(&from) else {
+            continue;
+        };
+        references.extend(
+            attribute_references(instance, &from, &referencing.value)
+                .into_iter()
+                .filter(|reference| reference.to == uid),
+        );
+    }
+    references.sort_by_key(|reference| reference.kind != ReferenceKind::Child);
+    let collected = serde_json::Value::from(uid);
+    let mut frame_owner_uid = entity["parent_uid"].as_str().unwrap_or("root").to_string();
+    while frame_owner_uid != "root" {
+        let Ok(frame) = tx.retrieve(&format!("{}_frame", frame_owner_uid)) else {
+            break;
+        };
+        let collections = &frame.value["$collections"];
+        for state in ["$unused", "$used"] {
+            for (key, uids) in collections[state].as_object().into_iter().flatten() {
+                if uids
+                    .as_array()
+                    .is_some_and(|uids| uids.contains(&collected))
+                {
+                    references.push(reference(
+                        &frame_owner_uid,
+                        uid,
+                        ReferenceKind::Collection,
+                        key,
+                    ));
+                }
+            }
+        }
+        frame_owner_uid = frame.value["$parent"]
+            .as_str()
+            .unwrap_or("root")
+            .to_string();
+    }
+    Ok(references)
+}
+
+/// Find all the references from an entity to other entities: its child
+/// entities, the entities it picked or used, the attributes it points at
+/// or copies from its context, and the entities collected in its frame.
+pub fn references_from<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    uid: &str,
+) -> Result<Vec<Reference>> {
+    let entity = tx.retrieve(uid)?.value;
+    let mut references = attribute_references(instance, uid, &entity);
+    if let Ok(frame) = tx.retrieve(&format!("{}_frame", uid)) {
+        let collections = &frame.value["$collections"];
+        for state in ["$unused", "$used"] {
+            for (key, uids) in collections[state].as_object().into_iter().flatten() {
+                for to in uids.as_array().into_iter().flatten() {
+                    if let Some(to) = to.as_str() {
+                        references.push(reference(uid, to, ReferenceKind::Collection, key));
+                    }
+                }
+            }
+        }
+    }
+    Ok(references)
+}
+
+/// The references held in the attributes of an entity.
+fn attribute_references(
+    instance: &SandboxInstance,
+    uid: &str,
+    entity: &serde_json::Value,
+) -> Vec<Reference> {
+    let mut references = vec![];
+    // Bookkeeping entries such as `$parent` and `$users` are not class
+    // attributes, so they have no reference kind, unlike `$` prefixed
+    // attributes declared in scrolls.
+    for (attr, value) in entity.as_object().into_iter().flatten() {
+        match value {
+            serde_json::Value::Array(uids) => {
+                let Some(kind) = attribute_reference_kind(instance, entity, attr) else {
+                    continue;
+                };
+                let fallbacks = entity["$fallbacks"][attr].as_array();
+                for to in uids.iter().filter_map(|to| to.as_str()) {
+                    let kind = if fallbacks.is_some_and(|f| f.iter().any(|v| v == to)) {
+                        ReferenceKind::Child
+                    } else {
+                        kind
+                    };
+                    references.push(reference(uid, to, kind, attr));
+                }
+            }
+            serde_json::Value::Object(_) => {
+                let kind = attribute_reference_kind(instance, entity, attr);
+                if let (Some(kind), Some(to)) = (kind, value["spec"]["uid"].as_str()) {
+                    references.push(reference(uid, to, kind, attr));
+                }
+            }
+            _ => {}
+        }
+    }
+    references
+}
+
+/// Traverse the entity graph from an entity, following references of the
+/// given kinds in the given direction, up to a maximum depth.
+///
+/// Returns every reference followed, in breadth-first order. Each entity
+/// is visited once, so cycles in the graph are safe to traverse.
+pub fn traverse<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    uid: &str,
+    direction: Direction,
+    kinds: &[ReferenceKind],
+    max_depth: usize,
+) -> Result<Vec<Reference>> {
+    let mut visited = HashSet::from([uid.to_string()]);
+    let mut queue = VecDeque::from([(uid.to_string(), 0)]);
+    let mut traversed = vec![];
+    while let Some((uid, depth)) = queue.pop_front() {
+        if depth == max_depth {
+            continue;
+        }
+        let references = match direction {
+            Direction::Incoming => references_to(instance, tx, &uid)?,
+            Direction::Outgoing => references_from(instance, tx, &uid)?,
+        };
+        for reference in references {
+            if !kinds.is_empty() && !kinds.contains(&reference.kind) {
+                continue;
+            }
+            let next = match direction {
+                Direction::Incoming => &reference.from,
+                Direction::Outgoing => &reference.to,
+            };
+            if visited.insert(next.clone()) {
+                queue.push_back((next.clone(), depth + 1));
+            }
+            traversed.push(reference);
+        }
+    }
+    Ok(traversed)
+}
+
+/// The kind of references an entity attribute holds, if any.
+fn attribute_reference_kind(
+    instance: &SandboxInstance,
+    entity: &serde_json::Value,
+    attr: &str,
+) -> Option<ReferenceKind> {
+    let value = &entity[attr];
+    if value["type"] == "pointer" {
+        return Some(ReferenceKind::Pointer);
+    }
+    if value["type"] == "context" {
+        return Some(ReferenceKind::Context);
+    }
+    let class = instance.classes.get(entity["class"].as_str()?)?;
+    class.attrs.get(attr)?.cmd.reference_kind()
+}
+
+fn reference(from: &str, to: &str, kind: ReferenceKind, attr: &str) -> Reference {
+    Reference {
+        from: from.to_string(),
+        to: to.to_string(),
+        kind,
+        attr: attr.to_string(),
+    }
+}
@@ -26,9 +26,10 @@ use anyhow::anyhow;
 use minijinja::Environment;
 use std::collections::HashMap;
 
+use crate::frame::criterion_matches;
 use crate::instance::SandboxInstance;
 use crate::renderer_env::prepare_renderer;
-use crate::repository::{ReadOnlyLoader, ReadOnlyTransaction};
+use crate::repository::ReadOnlyLoader;
 
 struct RendererContext<'a> {
     cache: HashMap<String, serde_json::value::Value>,
@@ -48,9 +49,9 @@ struct RendererContext<'a> {
 /// A `String` containing the rendered HTML if the class has an HTML body; otherwise, returns an empty string.
 ///
 /// The function sets up a rendering environment, prepares the renderer, and attempts to render the template with the provided data.
-pub fn render_entity_html(
+pub fn render_entity_html<T: ReadOnlyLoader>(
     instance: &SandboxInstance,
-    tx: &ReadOnlyTransaction,
+    tx: &T,
     obj: &serde_json::Value,
 ) -> anyhow::Result<(String, String)> {
     let mut env = Environment::new();
@@ -154,18 +155,25 @@ fn recursive_entity_renderer<T: ReadOnlyLoader>(
                 continue;
             }
             let frame = tx.retrieve(&format!("{}_frame", uuid))?;
-            let unused = &frame.value["$collections"]["$unused"][&spec.class_name];
-            ctx[&attr.attr_name] = serde_json::Value::from(
-                unused
-                    .as_array()
-                    .unwrap()
-                    .iter()
-                    .map(|unused_id| {
-                        let next = tx.retrieve(unused_id.as_str().unwrap())?;
-                        recursive_entity_renderer(context, instance, tx, &next.value, false, None)
-                    })
-                    .collect::<Result<Vec<serde_json::Value>, _>>()?,
-            );
+            let unused = &frame.value["$collections"]["$unused"][&spec.key()];
+            let criterion = &frame.value["$collections"]["$criteria"][&spec.key()];
+            let mut rendered = vec![];
+            for unused_id in unused.as_array().unwrap().iter() {
+                let unused_id = unused_id.as_str().unwrap();
+                if !criterion_matches(tx, criterion, unused_id)? {
+                    continue;
+                }
+                let next = tx.retrieve(unused_id)?;
+                rendered.push(recursive_entity_renderer(
+                    context,
+                    instance,
+                    tx,
+                    &next.value,
+                    false,
+                    None,
+                )?);
+            }
+            ctx[&attr.attr_name] = serde_json::Value::from(rendered);
             if attr.is_public || is_root {
                 ret[&attr.attr_name] = ctx[&attr.attr_name].clone();
             }
@@ -235,17 +243,26 @@ fn recursive_entity_renderer<T: ReadOnlyLoader>(
                 render_indirections(context, instance, tx, obj, attr_name)?
             }
             serde_json::Value::Null => {
-                let tmpl_str = class_spec.attrs[attr_name].cmd.value().unwrap();
-                serde_json::Value::String(context.env.render_str(&tmpl_str, &ctx).map_err(|e| {
-                        anyhow::anyhow!(
-                            "Failed to render string template {} for uid {} attr {} with error {:#}",
-                            tmpl_str,
-                            uuid,
-                            attr_name,
-                            e
-                        )
-                    })?,
-                )
+                // Null values of attributes without a template, such as
+                // injected ones, are rendered as is.
+                match class_spec
+                    .attrs
+                    .get(attr_name)
+                    .and_then(|attr_spec| attr_spec.cmd.value())
+                {
+                    Some(tmpl_str) => serde_json::Value::String(
+                        context.env.render_str(&tmpl_str, &ctx).map_err(|e| {
+                            anyhow::anyhow!(
+                                "Failed to render string template {} for uid {} attr {} with error {:#}",
+                                tmpl_str,
+                                uuid,
+                                attr_name,
+                                e
+                            )
+                        })?,
+                    ),
+                    None => serde_json::Value::Null,
+                }
             }
         };
         if is_public || is_root {
@@ -320,6 +337,9 @@ fn render_parent_attribute<T: ReadOnlyLoader>(
     parent_class: &str,
     parent_attr: &str,
 ) -> anyhow::Result<serde_json::Value> {
+    if pid == "root" {
+        return Ok(stub_context_attribute(tx, parent_class, parent_attr));
+    }
     let parent = tx.retrieve(pid)?.value;
     let class = parent["class"].as_str().unwrap();
     let Some(class_spec) = &instance.classes.get(class) else {
@@ -338,7 +358,7 @@ fn render_parent_attribute<T: ReadOnlyLoader>(
         //   * value copies
         //
         let v = &parent[parent_attr];
-        return if v.is_array() && !v.as_array().unwrap().is_empty() {
+        if v.is_array() && !v.as_array().unwrap().is_empty() {
             let data_uid = v.as_array().unwrap().first().unwrap().as_str().unwrap();
             let data = tx.retrieve(data_uid)?.value;
             recursive_entity_renderer(context, instance, tx, &data, false, Some(parent_attr))
@@ -346,21 +366,32 @@ fn render_parent_attribute<T: ReadOnlyLoader>(
             render_indirections(context, instance, tx, &parent, parent_attr)
         } else {
             Ok(parent[parent_attr].clone())
-        };
-    } else {
-        let my_pid = &parent["parent_uid"];
-        if my_pid != "root" {
-            return render_parent_attribute(
-                context,
-                instance,
-                tx,
-                my_pid.as_str().unwrap(),
-                parent_class,
-                parent_attr,
-            );
         }
+    } else {
+        render_parent_attribute(
+            context,
+            instance,
+            tx,
+            parent["parent_uid"].as_str().unwrap(),
+            parent_class,
+            parent_attr,
+        )
     }
-    Ok(serde_json::json!(false))
+}
+
+/// Retrieves a context attribute that has no matching ancestor from the stub
+/// context the sandbox was created with (see `BuilderOptions::stub_context`),
+/// defaulting to `false`.
+fn stub_context_attribute<T: ReadOnlyLoader>(
+    tx: &T,
+    parent_class: &str,
+    parent_attr: &str,
+) -> serde_json::Value {
+    tx.retrieve("$context")
+        .map(|stub| stub.value[parent_class][parent_attr].clone())
+        .ok()
+        .filter(|v| !v.is_null())
+        .unwrap_or(serde_json::json!(false))
 }
 
 /// Render pointers and context references indicated using a json object
@@ -384,7 +415,15 @@ fn render_indirections<T: ReadOnlyLoader>(
     attr_name: &str,
 ) -> Result<serde_json::Value, anyhow::Error> {
     let indirection = &obj[attr_name];
-    if indirection["type"] == "context" {
+    if indirection["type"] == "context" && indirection["spec"]["relation"].is_string() {
+        let spec = &indirection["spec"];
+        match spec["uid"].as_str() {
+            Some(uid) => {
+                render_pointer_attribute(context, instance, tx, uid, spec["attr"].as_str().unwrap())
+            }
+            None => Ok(serde_json::json!(false)),
+        }
+    } else if indirection["type"] == "context" {
         let pid = obj["parent_uid"].as_str().unwrap();
         let spec = &indirection["spec"];
         let parent_attr = spec["attr"].as_str().unwrap();
@@ -22,137 +22,392 @@
 // license. Please contact ithai at pendicepaper.com
 // for more information about commercial licensing terms.
 */
-use anyhow::{anyhow, Result};
-use redb::{ReadableTable, Savepoint};
+use crate::{encoding::*, memory_storage::MemoryStorage, redb_storage::RedbStorage, storage::*};
+use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
 use std::{
-    cell::RefCell,
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
 };
 
+/// A repository of entities and their frames, held by a storage backend.
+///
+/// Repositories are `Send + Sync`, so a sandbox can be shared by the threads
+/// of a server. Any number of threads can `load`, `inspect` and render while
+/// another thread is inside `mutate`: readers see the repository as last
+/// committed instead of waiting for the writer. Write transactions, on the
+/// other hand, run one at a time.
 pub struct Repository {
-    pub db: Option<Arc<Mutex<redb::Database>>>,
+    /// The storage backend holding the repository, once created or opened.
+    pub storage: Option<Arc<dyn Storage>>,
+    /// The path of the repository file, once created or opened.
+    pub filepath: Option<PathBuf>,
+    /// The attributes indexed for entities of each class.
+    pub indexed_attributes: Arc<IndexedAttributes>,
+    /// The encoding of values written to the repository.
+    pub encoding: ValueEncoding,
 }
 
 impl Repository {
     pub fn new() -> Self {
-        Repository { db: None }
+        Repository {
+            storage: None,
+            filepath: None,
+            indexed_attributes: Arc::new(IndexedAttributes::default()),
+            encoding: ValueEncoding::default(),
+        }
     }
 
+    /// Create a repository file, or a repository held in memory when
+    /// `filename` is `IN_MEMORY`.
     pub fn create(&mut self, filename: &str) -> Result<&mut Self> {
-        self.db = Some(Arc::new(Mutex::new(redb::Database::create(filename)?)));
-        Ok(self)
+        if filename == IN_MEMORY {
+            self.filepath = None;
+            return self.use_storage(Arc::new(MemoryStorage::new()));
+        }
+        self.filepath = Some(PathBuf::from(filename));
+        self.use_storage(Arc::new(RedbStorage::create(filename)?))
     }
 
     pub fn open(&mut self, filename: &str) -> Result<&mut Self> {
-        self.db = Some(Arc::new(Mutex::new(redb::Database::open(filename)?)));
+        if filename == IN_MEMORY {
+            return Err(anyhow!("Repositories held in memory cannot be opened"));
+        }
+        self.filepath = Some(PathBuf::from(filename));
+        self.use_storage(Arc::new(RedbStorage::open(filename)?))
+    }
+
+    /// Close the repository, releasing its storage once no transaction or
+    /// other handle holds it.
+    pub fn close(&mut self) -> &mut Self {
+        self.storage = None;
+        self.filepath = None;
+        self
+    }
+
+    /// Use a storage backend for the repository, registering the
+    /// dictionaries stored in it.
+    pub fn use_storage(&mut self, storage: Arc<dyn Storage>) -> Result<&mut Self> {
+        for dictionary in storage.dictionaries()? {
+            register_dictionary(&dictionary);
+        }
+        self.storage = Some(storage);
         Ok(self)
     }
 
-    pub fn load(&self, uid: &str) -> Result<serde_json::Value> {
-        let tx = self
-            .db
+    fn storage(&self) -> Result<&dyn Storage> {
+        self.storage
+            .as_deref()
+            .ok_or_else(|| anyhow!("Database not initialized"))
+    }
+
+    /// Set the encoding of values written to the repository from now on.
+    /// Values already stored are read regardless of their encoding.
+    pub fn encode_with(&mut self, encoding: ValueEncoding) -> Result<&mut Self> {
+        if !encoding.is_supported() {
+            return Err(anyhow!(
+                "Encoding values using {} is not supported",
+                encoding
+            ));
+        }
+        self.encoding = encoding;
+        Ok(self)
+    }
+
+    /// The compression dictionaries stored in the repository.
+    pub fn dictionaries(&self) -> Result<Vec<Vec<u8>>> {
+        self.storage()?.dictionaries()
+    }
+
+    /// The metadata of the repository by key (see `metadata::SandboxMetadata`).
+    pub fn metadata(&self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        Ok(self.storage()?.reader()?.metadata()?.into_iter().collect())
+    }
+
+    /// Store a compression dictionary in the repository and register it,
+    /// returning its id for encoding values using `ZstdDictionary`.
+    pub fn add_dictionary(&self, dictionary: &[u8]) -> Result<u32> {
+        let id = register_dictionary(dictionary);
+        self.storage()?.add_dictionary(id, dictionary)?;
+        Ok(id)
+    }
+
+    /// Train a compression dictionary of up to `max_size` bytes on the
+    /// values of the repository. Register it, or store it using
+    /// `add_dictionary`, to encode values using it.
+    #[cfg(feature = "zstd")]
+    pub fn train_dictionary(&self, max_size: usize) -> Result<Vec<u8>> {
+        // Training on many more samples than needed is slow, so entities
+        // are sampled evenly.
+        const MAX_SAMPLES: usize = 10000;
+        let samples = self.inspect(|tx| {
+            let uids = tx.uids()?;
+            let step = uids.len().div_ceil(MAX_SAMPLES).max(1);
+            uids.iter()
+                .step_by(step)
+                .map(|uid| Ok(tx.retrieve(uid)?.value))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        train_dictionary(&samples, max_size)
+    }
+
+    /// Convert the repository into a new repository file, storing every
+    /// value using another encoding, which may use any registered
+    /// dictionary. Indexes and metadata are converted as well, but snapshots
+    /// are not.
+    /// The returned repository is compacted, open and ready to use.
+    pub fn convert(&self, filename: &str, encoding: ValueEncoding) -> Result<Repository> {
+        let mut converted = Repository::new();
+        converted.indexed_attributes = self.indexed_attributes.clone();
+        converted.create(filename)?;
+        let mut dictionaries = self.dictionaries()?;
+        if let ValueEncoding::ZstdDictionary(id) = encoding {
+            dictionaries.extend(registered_dictionary(id));
+        }
+        for dictionary in dictionaries {
+            converted.add_dictionary(&dictionary)?;
+        }
+        converted.encode_with(encoding)?;
+        let source = self.storage()?.reader()?;
+        converted.mutate(|tx| {
+            for uid in source.uids()? {
+                if let Some(value) = source.get(&uid)? {
+                    tx.store(&uid, &value.value)?;
+                }
+            }
+            for (key, value) in source.metadata()? {
+                tx.store_metadata(&key, &value)?;
+            }
+            if let Ok(uids) = source.document_uids() {
+                for uid in uids {
+                    tx.store_document(&uid, source.document(&uid)?.as_ref())?;
+                }
+                // Converted documents are as up to date as they were
+                if source.indexed(&IndexKey::Changed.encode())?.is_empty() {
+                    tx.take_changed()?;
+                }
+            }
+            Ok(())
+        })?;
+        converted.compact()?;
+        Ok(converted)
+    }
+
+    /// Whether the storage is shared with another handle, in which case
+    /// it cannot be compacted.
+    ///
+    /// Only handles within this process are counted. Other processes are
+    /// kept out by the lock redb takes on the file while it is open, so
+    /// they fail to open it rather than share it.
+    pub fn is_shared(&self) -> bool {
+        self.storage
             .as_ref()
-            .ok_or_else(|| anyhow!("Database reference is missing"))?
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire lock"))?
-            .begin_read()
-            .map_err(|_| anyhow!("Failed to begin read transaction"))?;
+            .is_some_and(|storage| Arc::strong_count(storage) > 1)
+    }
 
-        const TABLE: redb::TableDefinition<String, JsonValue> =
-            redb::TableDefinition::new("my_data2");
+    /// The size of the repository in bytes, which is the size of the
+    /// repository file unless held in memory.
+    pub fn file_size(&self) -> Result<u64> {
+        self.storage()?.size()
+    }
 
-        let table = tx
-            .open_table(TABLE)
-            .map_err(|_| anyhow!("Failed to open table"))?;
+    /// Compact the repository, returning whether it was compacted.
+    ///
+    /// Compaction is skipped, returning `false`, while snapshots exist, since
+    /// redb cannot compact a file holding persistent savepoints. It fails
+    /// when the storage is shared with another handle or a transaction,
+    /// such as an ephemeral one, is still in progress.
+    pub fn compact(&mut self) -> Result<bool> {
+        let storage = self
+            .storage
+            .as_mut()
+            .ok_or_else(|| anyhow!("Database not initialized"))?;
+        Arc::get_mut(storage)
+            .ok_or_else(|| anyhow!("Database is open elsewhere and cannot be compacted"))?
+            .compact()
+    }
 
-        match table.get(uid.to_string()) {
-            Ok(Some(ret)) => Ok(ret.value().value),
-            Ok(None) => Err(anyhow!("No entry found for uid: {}", uid)),
-            Err(_) => Err(anyhow!("Error retrieving entry for uid: {}", uid)),
+    pub fn load(&self, uid: &str) -> Result<serde_json::Value> {
+        let stored = self
+            .storage()?
+            .reader()?
+            .get(uid)
+            .with_context(|| format!("Error retrieving entry for uid: {}", uid))?;
+        match stored {
+            Some(ret) => Ok(ret.value),
+            None => Err(anyhow!("No entry found for uid: {}", uid)),
         }
     }
 
+    /// Whether an entry is stored under `uid`.
+    pub fn contains(&self, uid: &str) -> Result<bool> {
+        Ok(self.storage()?.reader()?.get(uid)?.is_some())
+    }
+
     pub fn mutate<F, R>(&self, mut f: F) -> Result<R>
     where
         F: FnMut(&mut ReadWriteTransaction) -> Result<R>,
     {
-        let tx = self
-            .db
-            .as_ref()
-            .ok_or_else(|| anyhow!("Database not initialized"))?
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire lock"))?
-            .begin_write()
-            .map_err(|_| anyhow!("Failed to begin write transaction"))?;
-        const TABLE: redb::TableDefinition<String, JsonValue> =
-            redb::TableDefinition::new("my_data2");
-
-        let closure_result = {
-            let table = tx.open_table(TABLE)?;
+        let mut closure_result = None;
+        let storage = self.storage()?;
+        storage.write(self.encoding, &mut |writer| {
             let mut repo_tx = ReadWriteTransaction {
                 cache: HashMap::new(),
-                table,
+                table: TransactionTable::Storage(writer),
+                index: Some(Index {
+                    attributes: self.indexed_attributes.clone(),
+                    stored: HashMap::new(),
+                }),
+                storage: Some(storage),
+                touched: HashSet::new(),
             };
-            f(&mut repo_tx)?
-        };
-
-        tx.commit()
-            .map_err(|_| anyhow!("Failed to commit transaction"))?;
-        Ok(closure_result)
+            closure_result = Some(f(&mut repo_tx)?);
+            Ok(())
+        })?;
+        closure_result.ok_or_else(|| anyhow!("Transaction did not complete"))
     }
 
     pub fn savepoint(&self) -> Result<Savepoint> {
-        let tx = self
-            .db
-            .as_ref()
-            .ok_or_else(|| anyhow!("Database not initialized"))?
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire lock"))?
-            .begin_write()
-            .map_err(|_| anyhow!("Failed to begin write transaction"))?;
-        Ok(tx.ephemeral_savepoint()?)
+        self.storage()?.savepoint()
     }
 
     pub fn restore(&self, savepoint: &Savepoint) -> Result<()> {
-        let mut tx = self
-            .db
-            .as_ref()
-            .ok_or_else(|| anyhow!("Database not initialized"))?
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire lock"))?
-            .begin_write()
-            .map_err(|_| anyhow!("Failed to begin write transaction"))?;
-        tx.restore_savepoint(savepoint)?;
-        Ok(tx.commit()?)
+        self.storage()?.restore(savepoint)
+    }
+
+    /// Begin an ephemeral transaction, which reads from the repository but
+    /// keeps everything written to it in memory, never to be committed.
+    ///
+    /// Writes can be taken out of the transaction using `into_detached`
+    /// and later merged into a read/write transaction (see `merge`).
+    pub fn ephemeral(&self) -> Result<ReadWriteTransaction<'static>> {
+        Ok(ReadWriteTransaction::from_detached(DetachedTransaction {
+            base: Arc::new(HashMap::new()),
+            removed: Arc::new(HashSet::new()),
+            fallback: Some(Arc::from(self.storage()?.reader()?)),
+            originals: HashMap::new(),
+            written: HashMap::new(),
+        }))
+    }
+
+    /// Take a named, persistent snapshot of the repository's current state.
+    ///
+    /// Snapshots of repository files are stored as redb persistent
+    /// savepoints inside the file, so they survive closing and reopening
+    /// the sandbox.
+    pub fn snapshot(&self, name: &str) -> Result<()> {
+        self.storage()?.snapshot(name)
+    }
+
+    /// List the names of all snapshots, from the oldest to the newest.
+    pub fn snapshots(&self) -> Result<Vec<String>> {
+        self.storage()?.snapshots()
+    }
+
+    /// Restore the repository to the state it had when the snapshot was taken.
+    ///
+    /// The snapshot itself is kept and can be restored again, but any snapshot
+    /// taken after it is discarded.
+    pub fn restore_snapshot(&self, name: &str) -> Result<()> {
+        self.storage()?.restore_snapshot(name)
     }
 
-    pub fn inspect<F, R>(&self, f: F) -> Result<R>
+    /// Delete a snapshot, releasing the storage it retains.
+    pub fn delete_snapshot(&self, name: &str) -> Result<()> {
+        self.storage()?.delete_snapshot(name)
+    }
+
+    /// Branch a snapshot into a new repository file.
+    ///
+    /// The snapshot state is copied into `filename` while this repository
+    /// is left untouched. The returned repository is open and ready to use.
+    pub fn branch(&self, name: &str, filename: &str) -> Result<Repository> {
+        let mut branched = Repository::new();
+        branched.indexed_attributes = self.indexed_attributes.clone();
+        branched.create(filename)?;
+        for dictionary in self.dictionaries()? {
+            branched.add_dictionary(&dictionary)?;
+        }
+        branched.encoding = self.encoding;
+        self.storage()?.read_snapshot(name, &mut |source| {
+            branched.mutate(|branched_tx| {
+                for uid in source.uids()? {
+                    if let Some(value) = source.get(&uid)? {
+                        branched_tx.store(&uid, &value.value)?;
+                    }
+                }
+                for (key, value) in source.metadata()? {
+                    branched_tx.store_metadata(&key, &value)?;
+                }
+                Ok(())
+            })
+        })?;
+        Ok(branched)
+    }
+
+    /// Set the attributes indexed for entities of each class, usually those
+    /// declared in scrolls using `index`.
+    pub fn index_attributes(&mut self, attributes: IndexedAttributes) -> &mut Self {
+        self.indexed_attributes = Arc::new(attributes);
+        self
+    }
+
+    /// Whether the repository has secondary indexes, which sandboxes created
+    /// before indexing was introduced lack.
+    pub fn is_indexed(&self) -> Result<bool> {
+        self.storage()?.is_indexed()
+    }
+
+    /// Whether the secondary indexes were last rebuilt for other indexed
+    /// attributes than those set using `index_attributes`, or were never
+    /// recorded as rebuilt for any.
+    pub fn has_stale_indexes(&self) -> Result<bool> {
+        let indexed = self
+            .metadata()?
+            .remove(INDEXED_ATTRIBUTES)
+            .and_then(|value| serde_json::from_value::<IndexedAttributes>(value).ok());
+        Ok(indexed.as_ref() != Some(&*self.indexed_attributes))
+    }
+
+    /// Rebuild the secondary indexes of every entity, for example after
+    /// changing the indexed attributes, and record the attributes they were
+    /// rebuilt for in the metadata table.
+    ///
+    /// Every entity is marked as changed, so search documents are rebuilt
+    /// as well the next time the search index is updated.
+    pub fn reindex(&self) -> Result<()> {
+        self.storage()?.write(self.encoding, &mut |writer| {
+            writer.clear_indexes()?;
+            writer.set_metadata(
+                INDEXED_ATTRIBUTES,
+                &serde_json::to_value(&*self.indexed_attributes)?,
+            )?;
+            for uid in writer.uids()? {
+                let Some(value) = writer.get(&uid)? else {
+                    continue;
+                };
+                let keys = index_keys(&self.indexed_attributes, &value.value);
+                if !keys.is_empty() {
+                    writer.index(&IndexKey::Changed.encode(), &uid)?;
+                }
+                for key in keys {
+                    writer.index(&key, &uid)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    pub fn inspect<F, R>(&self, mut f: F) -> Result<R>
     where
         F: FnMut(&mut ReadOnlyTransaction) -> Result<R>,
     {
-        let f = RefCell::new(f);
-        let tx = self
-            .db
-            .as_ref()
-            .ok_or_else(|| anyhow!("Database reference is missing"))?
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire database lock"))?
-            .begin_read()
-            .map_err(|_| anyhow!("Failed to begin read transaction"))?;
-        const TABLE: redb::TableDefinition<String, JsonValue> =
-            redb::TableDefinition::new("my_data2");
-        let closure_result: R;
-        {
-            let table = tx.open_table(TABLE)?;
-            let mut repo_tx = ReadOnlyTransaction {
-                cache: HashMap::new(),
-                table,
-            };
-            closure_result = f.borrow_mut()(&mut repo_tx)?;
-        }
-        Ok(closure_result)
+        let mut repo_tx = ReadOnlyTransaction {
+            cache: HashMap::new(),
+            storage: self.storage()?.reader()?,
+        };
+        f(&mut repo_tx)
     }
 }
 
@@ -164,16 +419,329 @@ impl Default for Repository {
 
 pub trait ReadOnlyLoader {
     fn retrieve(&self, uid: &str) -> Result<JsonValue>;
+    /// The uids of every entry stored in the repository, including frames.
+    fn uids(&self) -> Result<Vec<String>>;
+    /// The uids of the entities indexed under a key.
+    fn indexed(&self, key: &IndexKey) -> Result<Vec<String>>;
+    /// The classes having any entity indexed under them (see
+    /// `IndexKey::Class`), in order.
+    fn indexed_classes(&self) -> Result<Vec<String>>;
+    /// The metadata of the repository by key (see `metadata::SandboxMetadata`).
+    fn metadata(&self) -> Result<serde_json::Map<String, serde_json::Value>>;
+    /// The search document of an entity, if it has one.
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>>;
+    /// The number of search documents.
+    fn documents_count(&self) -> Result<u64>;
+}
+
+/// The key of the metadata row recording the indexed attributes the
+/// secondary indexes were last rebuilt for.
+pub const INDEXED_ATTRIBUTES: &str = "indexed_attributes";
+
+/// The attributes indexed for entities of each class, keyed by class name.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct IndexedAttributes {
+    /// The hierarchy of each class, since entities are indexed under every
+    /// class in the hierarchy of their own (see `IndexKey::Class`).
+    pub hierarchies: HashMap<String, Vec<String>>,
+    /// Attributes indexed by their value (see `IndexKey::Attribute`).
+    pub values: HashMap<String, Vec<String>>,
+    /// Attributes holding the uids of other entities, such as child, used
+    /// or picked entities, indexed as references to them (see
+    /// `IndexKey::Reference`).
+    pub references: HashMap<String, Vec<String>>,
+}
+
+/// The prefix of the encoded keys of `IndexKey::Class`.
+const CLASS_KEY_PREFIX: &str = "class/";
+
+/// A key of the secondary indexes maintained for entities when saved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IndexKey {
+    /// Entities of a class, including entities of its subclasses.
+    Class(String),
+    /// Child entities of a parent entity.
+    Parent(String),
+    /// Entities with an attribute value, for indexed attributes only.
+    Attribute(String, serde_json::Value),
+    /// Entities stored or removed since the search index was last updated.
+    Changed,
+    /// Entities whose search document contains a term.
+    Term(String),
+    /// Entities referencing an entity, through an attribute holding its uid
+    /// or pointing at one of its attributes.
+    Reference(String),
+}
+
+impl IndexKey {
+    fn encode(&self) -> String {
+        match self {
+            IndexKey::Class(class) => format!("{}{}", CLASS_KEY_PREFIX, class),
+            IndexKey::Parent(parent_uid) => format!("parent/{}", parent_uid),
+            // Numbers are encoded as floats, so 3 and 3.0 share a key
+            IndexKey::Attribute(attr, value) => match value.as_f64() {
+                Some(number) => format!("attr/{}/{}", attr, number),
+                None => format!("attr/{}/{}", attr, value),
+            },
+            IndexKey::Changed => "changed".to_string(),
+            IndexKey::Term(term) => format!("term/{}", term),
+            IndexKey::Reference(uid) => format!("reference/{}", uid),
+        }
+    }
+}
+
+fn indexed_classes(reader: &dyn StorageReader) -> Result<Vec<String>> {
+    Ok(reader
+        .index_keys(CLASS_KEY_PREFIX)?
+        .into_iter()
+        .map(|key| key[CLASS_KEY_PREFIX.len()..].to_string())
+        .collect())
+}
+
+/// The encoded index keys of a stored value, none unless it is an entity.
+fn index_keys(attributes: &IndexedAttributes, value: &serde_json::Value) -> Vec<String> {
+    let Some(class) = value["class"].as_str() else {
+        return vec![];
+    };
+    let mut keys: Vec<String> = match attributes.hierarchies.get(class) {
+        Some(hierarchy) => hierarchy
+            .iter()
+            .map(|class| IndexKey::Class(class.clone()).encode())
+            .collect(),
+        None => vec![IndexKey::Class(class.to_string()).encode()],
+    };
+    if let Some(parent_uid) = value["parent_uid"].as_str() {
+        keys.push(IndexKey::Parent(parent_uid.to_string()).encode());
+    }
+    for attr in attributes.values.get(class).into_iter().flatten() {
+        let attr_value = &value[attr];
+        if attr_value.is_string() || attr_value.is_number() || attr_value.is_boolean() {
+            keys.push(IndexKey::Attribute(attr.clone(), attr_value.clone()).encode());
+        }
+    }
+    let mut referenced: Vec<&str> = attributes
+        .references
+        .get(class)
+        .into_iter()
+        .flatten()
+        .filter_map(|attr| value[attr].as_array())
+        .flatten()
+        .filter_map(|uid| uid.as_str())
+        .collect();
+    // Pointers and context attributes are objects holding the uid of the
+    // entity they copy an attribute of, and can be injected into any class
+    for attr_value in value.as_object().into_iter().flatten().map(|(_, v)| v) {
+        if attr_value["type"] == "pointer" || attr_value["type"] == "context" {
+            referenced.extend(attr_value["spec"]["uid"].as_str());
+        }
+    }
+    referenced.sort_unstable();
+    referenced.dedup();
+    for uid in referenced {
+        keys.push(IndexKey::Reference(uid.to_string()).encode());
+    }
+    keys
 }
 
 pub struct ReadWriteTransaction<'a> {
     cache: HashMap<String, serde_json::Value>,
-    pub table: redb::Table<'a, String, JsonValue>,
+    table: TransactionTable<'a>,
+    index: Option<Index>,
+    /// The storage written to, for reading its committed state when
+    /// detaching.
+    storage: Option<&'a dyn Storage>,
+    /// The uids stored or removed through the storage writer so far.
+    touched: HashSet<String>,
 }
 
 pub struct ReadOnlyTransaction {
     pub cache: HashMap<String, serde_json::Value>,
-    pub table: redb::ReadOnlyTable<String, JsonValue>,
+    storage: BoxedStorageReader,
+}
+
+/// The secondary indexes maintained by a read/write transaction.
+struct Index {
+    attributes: Arc<IndexedAttributes>,
+    /// The index keys of entities as currently stored, by uid.
+    stored: HashMap<String, Vec<String>>,
+}
+
+impl Index {
+    /// Update the index entries of an entity about to be stored, or
+    /// removed when `value` is `None`.
+    fn update(
+        &mut self,
+        writer: &mut dyn StorageWriter,
+        uid: &str,
+        value: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        let previous = match self.stored.remove(uid) {
+            Some(keys) => keys,
+            None => match writer.get(uid)? {
+                Some(previous) => index_keys(&self.attributes, &previous.value),
+                None => vec![],
+            },
+        };
+        let keys = value
+            .map(|value| index_keys(&self.attributes, value))
+            .unwrap_or_default();
+        for key in previous.iter().filter(|key| !keys.contains(key)) {
+            writer.unindex(key, uid)?;
+        }
+        for key in keys.iter().filter(|key| !previous.contains(key)) {
+            writer.index(key, uid)?;
+        }
+        if !previous.is_empty() || !keys.is_empty() {
+            writer.index(&IndexKey::Changed.encode(), uid)?;
+        }
+        self.stored.insert(uid.to_string(), keys);
+        Ok(())
+    }
+}
+
+/// The storage behind a read/write transaction: either a write transaction
+/// of the repository storage, or a detached in-memory view, used for
+/// transactions running on worker threads and for ephemeral transactions.
+enum TransactionTable<'a> {
+    Storage(&'a mut dyn StorageWriter),
+    Detached(DetachedTransaction),
+}
+
+/// An in-memory view of a read/write transaction that can be moved to a
+/// worker thread.
+///
+/// A detached transaction reads from an immutable `base` taken when it was
+/// detached, falling back to the committed state of the storage for
+/// anything else not `removed` since, and records everything written to
+/// it, so it can later be merged back into the transaction it was detached
+/// from (see `merge`).
+#[derive(Clone)]
+pub struct DetachedTransaction {
+    base: Arc<HashMap<String, serde_json::Value>>,
+    removed: Arc<HashSet<String>>,
+    fallback: Option<Arc<dyn StorageReader + Send + Sync>>,
+    /// Values read from `fallback` before being written, used as the merge
+    /// base of entities missing from `base`.
+    originals: HashMap<String, serde_json::Value>,
+    written: HashMap<String, Option<serde_json::Value>>,
+}
+
+impl DetachedTransaction {
+    fn get(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        if let Some(written) = self.written.get(uid) {
+            return Ok(written.clone());
+        }
+        if let Some(value) = self.base.get(uid) {
+            return Ok(Some(value.clone()));
+        }
+        if self.removed.contains(uid) {
+            return Ok(None);
+        }
+        match &self.fallback {
+            Some(reader) => Ok(reader.get(uid)?.map(|ret| ret.value)),
+            None => Ok(None),
+        }
+    }
+
+    /// The value an entity had when this transaction was detached, if any.
+    fn original(&self, uid: &str) -> Option<&serde_json::Value> {
+        self.base.get(uid).or_else(|| self.originals.get(uid))
+    }
+
+    /// Keep the committed value of an entity about to be written for the
+    /// first time, to be used as its merge base.
+    fn keep_original(&mut self, uid: &str) -> Result<()> {
+        if self.written.contains_key(uid)
+            || self.base.contains_key(uid)
+            || self.removed.contains(uid)
+        {
+            return Ok(());
+        }
+        if let Some(reader) = &self.fallback {
+            if let Some(ret) = reader.get(uid)? {
+                self.originals.insert(uid.to_string(), ret.value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply everything written to this transaction on top of `base`.
+    fn overlay(
+        &self,
+        mut base: HashMap<String, serde_json::Value>,
+    ) -> HashMap<String, serde_json::Value> {
+        for (uid, value) in self.written.iter() {
+            match value {
+                Some(value) => base.insert(uid.clone(), value.clone()),
+                None => base.remove(uid),
+            };
+        }
+        base
+    }
+}
+
+impl<'a> TransactionTable<'a> {
+    fn get(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        match self {
+            TransactionTable::Storage(writer) => Ok(writer.get(uid)?.map(|ret| ret.value)),
+            TransactionTable::Detached(detached) => detached.get(uid),
+        }
+    }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        let detached = match self {
+            TransactionTable::Storage(writer) => return writer.uids(),
+            TransactionTable::Detached(detached) => detached,
+        };
+        let mut uids = match &detached.fallback {
+            Some(reader) => reader.uids()?,
+            None => vec![],
+        };
+        uids.retain(|uid| !detached.removed.contains(uid) && !detached.base.contains_key(uid));
+        uids.extend(detached.base.keys().cloned());
+        uids.retain(|uid| !detached.written.contains_key(uid));
+        uids.extend(
+            detached
+                .written
+                .iter()
+                .filter(|(_, value)| value.is_some())
+                .map(|(uid, _)| uid.clone()),
+        );
+        Ok(uids)
+    }
+
+    /// The storage reader behind the transaction, unless detached.
+    fn reader(&self) -> Option<&dyn StorageReader> {
+        match self {
+            TransactionTable::Storage(writer) => Some(&**writer),
+            TransactionTable::Detached(_) => None,
+        }
+    }
+
+    fn insert(&mut self, uid: &str, value: &serde_json::Value) -> Result<()> {
+        match self {
+            TransactionTable::Storage(writer) => writer.insert(uid, value)?,
+            TransactionTable::Detached(detached) => {
+                detached.keep_original(uid)?;
+                detached
+                    .written
+                    .insert(uid.to_string(), Some(value.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, uid: &str) -> Result<()> {
+        match self {
+            TransactionTable::Storage(writer) => writer.remove(uid)?,
+            TransactionTable::Detached(detached) => {
+                detached.keep_original(uid)?;
+                detached.written.insert(uid.to_string(), None);
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<'a> ReadOnlyLoader for ReadWriteTransaction<'a> {
@@ -181,16 +749,94 @@ impl<'a> ReadOnlyLoader for ReadWriteTransaction<'a> {
         if let Some(cached) = self.cache.get(uid) {
             Ok(JsonValue {
                 value: cached.clone(),
+                encoding: ValueEncoding::default(),
+            })
+        } else if let Some(value) = self.table.get(uid)? {
+            Ok(JsonValue {
+                value,
+                encoding: ValueEncoding::default(),
             })
-        } else if let Ok(Some(ret)) = self.table.get(uid.to_string()) {
-            Ok(ret.value())
         } else {
             Err(anyhow!("error in loading {}", uid))
         }
     }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        self.table.uids()
+    }
+
+    fn indexed(&self, key: &IndexKey) -> Result<Vec<String>> {
+        self.indexed_reader()?.indexed(&key.encode())
+    }
+
+    fn indexed_classes(&self) -> Result<Vec<String>> {
+        indexed_classes(self.indexed_reader()?)
+    }
+
+    /// Detached transactions have no metadata.
+    fn metadata(&self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        Ok(match self.table.reader() {
+            Some(reader) => reader.metadata()?.into_iter().collect(),
+            None => serde_json::Map::new(),
+        })
+    }
+
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        self.indexed_reader()?.document(uid)
+    }
+
+    fn documents_count(&self) -> Result<u64> {
+        self.indexed_reader()?.documents_count()
+    }
+}
+
+impl ReadWriteTransaction<'static> {
+    /// Creates a transaction on top of a detached view, usually inside a
+    /// worker thread.
+    pub fn from_detached(detached: DetachedTransaction) -> Self {
+        ReadWriteTransaction {
+            cache: HashMap::new(),
+            table: TransactionTable::Detached(detached),
+            index: None,
+            storage: None,
+            touched: HashSet::new(),
+        }
+    }
+
+    /// Returns the detached view, holding everything written by this
+    /// transaction, so it can be merged.
+    pub fn into_detached(self) -> Result<DetachedTransaction> {
+        match self.table {
+            TransactionTable::Detached(detached) => Ok(detached),
+            TransactionTable::Storage(_) => Err(anyhow!("Transaction is not detached")),
+        }
+    }
 }
 
 impl<'a> ReadWriteTransaction<'a> {
+    /// The storage reader of an indexed transaction, which ephemeral and
+    /// detached transactions are not.
+    fn indexed_reader(&self) -> Result<&dyn StorageReader> {
+        match (&self.index, self.table.reader()) {
+            (Some(_), Some(reader)) => Ok(reader),
+            _ => Err(anyhow!("Transaction has no index")),
+        }
+    }
+
+    /// Whether the transaction maintains secondary indexes, which detached
+    /// and ephemeral transactions do not.
+    pub fn is_indexed(&self) -> bool {
+        self.indexed_reader().is_ok()
+    }
+
+    /// The storage writer of an indexed transaction.
+    fn indexed_writer(&mut self) -> Result<&mut dyn StorageWriter> {
+        match (&self.index, &mut self.table) {
+            (Some(_), TransactionTable::Storage(writer)) => Ok(&mut **writer),
+            _ => Err(anyhow!("Transaction has no index")),
+        }
+    }
+
     pub fn _has_cache(&mut self, uid: &str) -> bool {
         self.cache.contains_key(uid)
     }
@@ -203,56 +849,359 @@ impl<'a> ReadWriteTransaction<'a> {
     }
     pub fn load(&mut self, uid: &str) -> Result<&mut serde_json::Value> {
         if !(self.cache.contains_key(uid)) {
-            self.cache
-                .insert(uid.to_string(), self.retrieve(uid)?.value);
+            let value = self.retrieve(uid)?.value;
+            if let Some(index) = &mut self.index {
+                index
+                    .stored
+                    .entry(uid.to_string())
+                    .or_insert_with(|| index_keys(&index.attributes, &value));
+            }
+            self.cache.insert(uid.to_string(), value);
         }
         Ok(self.cache.get_mut(uid).unwrap())
     }
     pub fn store(&mut self, uid: &str, value: &serde_json::Value) -> Result<()> {
-        self.table
-            .insert(
-                uid.to_string(),
-                JsonValue {
-                    value: value.clone(),
-                },
-            )
-            .map_err(|e| anyhow!(e))?;
-        Ok(())
+        if let (Some(index), TransactionTable::Storage(writer)) = (&mut self.index, &mut self.table)
+        {
+            index.update(&mut **writer, uid, Some(value))?;
+        }
+        self.touch(uid);
+        // A cached copy, such as one merged in, would otherwise shadow it
+        if let Some(cached) = self.cache.get_mut(uid) {
+            *cached = value.clone();
+        }
+        self.table.insert(uid, value)
     }
     pub fn save(&mut self, uid: &str) -> Result<()> {
         if let Some(e) = self.cache.get(uid) {
-            self.table
-                .insert(uid.to_string(), &JsonValue { value: e.clone() })
-                .map_err(|e| anyhow!(e))?;
-            Ok(())
+            if let (Some(index), TransactionTable::Storage(writer)) =
+                (&mut self.index, &mut self.table)
+            {
+                index.update(&mut **writer, uid, Some(e))?;
+            }
+            if self.storage.is_some() {
+                self.touched.insert(uid.to_string());
+            }
+            self.table.insert(uid, e)
         } else {
             Err(anyhow!("Entity not found in cache"))
         }
     }
     pub fn remove(&mut self, uid: &str) -> Result<()> {
-        self.table.remove(uid.to_string())?;
+        if let (Some(index), TransactionTable::Storage(writer)) = (&mut self.index, &mut self.table)
+        {
+            index.update(&mut **writer, uid, None)?;
+        }
+        self.touch(uid);
+        self.table.remove(uid)?;
         if self.cache.contains_key(uid) {
             self.cache.remove(uid);
         }
         Ok(())
     }
+    /// Remember that an entity was stored or removed through the storage
+    /// writer, so detached views do not read its committed value.
+    fn touch(&mut self, uid: &str) {
+        if self.storage.is_some() {
+            self.touched.insert(uid.to_string());
+        }
+    }
+
+    /// Take the uids of the entities stored or removed since this was last
+    /// called, clearing them.
+    pub fn take_changed(&mut self) -> Result<Vec<String>> {
+        self.indexed_writer()?
+            .take_indexed(&IndexKey::Changed.encode())
+    }
+
+    /// Store the search document of an entity, or remove it when `document`
+    /// is `None`, indexing the terms in its `terms` object.
+    pub fn store_document(
+        &mut self,
+        uid: &str,
+        document: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        let writer = self.indexed_writer()?;
+        let terms = |document: &serde_json::Value| -> Vec<String> {
+            document["terms"]
+                .as_object()
+                .map(|terms| terms.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        let previous = match writer.document(uid)? {
+            Some(previous) => terms(&previous),
+            None => vec![],
+        };
+        let current = document.map(terms).unwrap_or_default();
+        for term in previous.iter().filter(|term| !current.contains(term)) {
+            writer.unindex(&IndexKey::Term(term.clone()).encode(), uid)?;
+        }
+        for term in current.iter().filter(|term| !previous.contains(term)) {
+            writer.index(&IndexKey::Term(term.clone()).encode(), uid)?;
+        }
+        match document {
+            Some(document) => writer.insert_document(uid, document),
+            None => writer.remove_document(uid),
+        }
+    }
+
+    /// Store a metadata value of the repository.
+    pub fn store_metadata(&mut self, key: &str, value: &serde_json::Value) -> Result<()> {
+        match &mut self.table {
+            TransactionTable::Storage(writer) => writer.set_metadata(key, value),
+            _ => Err(anyhow!("Transaction cannot store metadata")),
+        }
+    }
+
     pub fn emplace_and_save(&mut self, uid: &str, v: serde_json::Value) -> Result<()> {
         self.cache.insert(uid.to_string(), v);
         self.save(uid)
     }
+
+    /// Detach an in-memory view of this transaction for rolling entities
+    /// on a worker thread.
+    ///
+    /// The view holds every entity touched by this transaction so far and
+    /// reads anything else from the committed state of the storage.
+    pub fn detach(&self) -> Result<DetachedTransaction> {
+        let (base, removed, fallback) = match &self.table {
+            TransactionTable::Storage(_) => {
+                let mut base = HashMap::new();
+                let mut removed = HashSet::new();
+                for uid in self.touched.iter() {
+                    if self.cache.contains_key(uid) {
+                        continue;
+                    }
+                    if let Some(value) = self.table.get(uid)? {
+                        base.insert(uid.clone(), value);
+                    } else {
+                        removed.insert(uid.clone());
+                    }
+                }
+                let fallback = match self.storage {
+                    Some(storage) => Some(Arc::from(storage.reader()?)),
+                    None => None,
+                };
+                (base, removed, fallback)
+            }
+            TransactionTable::Detached(detached) => {
+                let mut removed = (*detached.removed).clone();
+                removed.extend(
+                    detached
+                        .written
+                        .iter()
+                        .filter(|(_, value)| value.is_none())
+                        .map(|(uid, _)| uid.clone()),
+                );
+                let mut base = detached.overlay((*detached.base).clone());
+                for (uid, value) in detached.originals.iter() {
+                    base.entry(uid.clone()).or_insert_with(|| value.clone());
+                }
+                (base, removed, detached.fallback.clone())
+            }
+        };
+        let mut base = base;
+        base.extend(self.cache.clone());
+        Ok(DetachedTransaction {
+            base: Arc::new(base),
+            removed: Arc::new(removed),
+            fallback,
+            originals: HashMap::new(),
+            written: HashMap::new(),
+        })
+    }
+
+    /// Whether a detached transaction can be merged into this transaction
+    /// without conflicts, which arise when both removed the same item from
+    /// an array, such as an entity taken from the same `$unused`
+    /// collection, when both changed the same value, such as the `$picks`
+    /// counter, when both added the same entity, or when it changed an
+    /// entity removed here since.
+    pub fn can_merge(&self, detached: &DetachedTransaction) -> Result<bool> {
+        for (uid, value) in detached.written.iter() {
+            let Some(theirs) = value else {
+                continue;
+            };
+            match (detached.original(uid), self.retrieve(uid)) {
+                (Some(base), Ok(ours)) if !conflicts(&ours.value, base, theirs) => {}
+                (Some(_), _) => return Ok(false),
+                // Added by both, such as the first `$picks` number, which
+                // both would then have taken
+                (None, Ok(_)) => return Ok(false),
+                (None, Err(_)) => {}
+            }
+        }
+        Ok(true)
+    }
+
+    /// Merge everything written to a detached transaction back into
+    /// this transaction, failing when it conflicts (see `can_merge`).
+    ///
+    /// New entities are stored as is. Entities that existed when the view
+    /// was detached, such as collecting frames, are merged using a three-way
+    /// merge, so concurrent changes made by other detached transactions to
+    /// the same entities are kept.
+    pub fn merge(&mut self, detached: DetachedTransaction) -> Result<()> {
+        if !self.can_merge(&detached)? {
+            return Err(anyhow!(
+                "Unable to merge entities changed concurrently by another transaction"
+            ));
+        }
+        let mut written: Vec<_> = detached.written.iter().collect();
+        written.sort_by_key(|(uid, _)| *uid);
+        for (uid, value) in written {
+            match (value, detached.original(uid)) {
+                (None, _) => self.remove(uid)?,
+                (Some(theirs), Some(base)) => {
+                    merge_values(self.load(uid)?, base, theirs);
+                    self.save(uid)?;
+                }
+                (Some(theirs), None) => self.emplace_and_save(uid, theirs.clone())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether merging `theirs` into `ours`, where both started at `base`,
+/// loses a change made in `ours`: when both changed the same value, other
+/// than an object or a set of uids (see `is_set`), when both added the same
+/// key with different values, or when `theirs` removes an array item
+/// `ours` has already removed.
+fn conflicts(
+    ours: &serde_json::Value,
+    base: &serde_json::Value,
+    theirs: &serde_json::Value,
+) -> bool {
+    if ours == base || theirs == base {
+        return false;
+    }
+    match (ours, base, theirs) {
+        (
+            serde_json::Value::Object(ours),
+            serde_json::Value::Object(base),
+            serde_json::Value::Object(theirs),
+        ) => theirs
+            .iter()
+            .any(|(key, their_value)| match (ours.get(key), base.get(key)) {
+                (Some(our_value), Some(base_value)) => {
+                    conflicts(our_value, base_value, their_value)
+                }
+                // Arrays and objects added by both are merged
+                (Some(our_value), None) => {
+                    !(our_value.is_array() || our_value.is_object()) && our_value != their_value
+                }
+                _ => false,
+            }),
+        (
+            serde_json::Value::Array(ours),
+            serde_json::Value::Array(base),
+            serde_json::Value::Array(theirs),
+        ) => {
+            !(is_set(ours) && is_set(base) && is_set(theirs))
+                || base
+                    .iter()
+                    .any(|v| !theirs.contains(v) && !ours.contains(v))
+        }
+        // Taking either value would lose the other change, for example
+        // numbering two picks alike when both took a `$picks` number
+        _ => true,
+    }
+}
+
+/// Whether an array holds no item twice, so it can be merged as a set.
+fn is_set(array: &[serde_json::Value]) -> bool {
+    let mut items = HashSet::new();
+    array.iter().all(|item| items.insert(item.to_string()))
+}
+
+/// A three-way merge of `theirs` into `ours`, where both started at `base`.
+///
+/// Objects are merged key by key. Arrays changed in both are treated as
+/// sets of uids, so items added or removed in `theirs` are added or removed
+/// in `ours`, once checked that `ours` did not remove the same items (see
+/// `conflicts`). Any other value changed in `theirs` replaces the value in
+/// `ours`, which `conflicts` checks was left unchanged.
+fn merge_values(
+    ours: &mut serde_json::Value,
+    base: &serde_json::Value,
+    theirs: &serde_json::Value,
+) {
+    match (ours, base, theirs) {
+        (
+            serde_json::Value::Object(ours),
+            serde_json::Value::Object(base),
+            serde_json::Value::Object(theirs),
+        ) => {
+            for (key, their_value) in theirs {
+                match (ours.get_mut(key), base.get(key)) {
+                    (Some(our_value), Some(base_value)) => {
+                        if base_value != their_value {
+                            merge_values(our_value, base_value, their_value);
+                        }
+                    }
+                    // Added by both, such as the first `$users` of an entity
+                    (Some(our_value), None) if our_value.is_array() || our_value.is_object() => {
+                        let empty = match our_value {
+                            serde_json::Value::Array(_) => serde_json::json!([]),
+                            _ => serde_json::json!({}),
+                        };
+                        merge_values(our_value, &empty, their_value);
+                    }
+                    _ => {
+                        ours.insert(key.clone(), their_value.clone());
+                    }
+                }
+            }
+            for key in base.keys() {
+                if !theirs.contains_key(key) {
+                    ours.swap_remove(key);
+                }
+            }
+        }
+        (
+            serde_json::Value::Array(ours),
+            serde_json::Value::Array(base),
+            serde_json::Value::Array(theirs),
+        ) if ours != base => {
+            ours.retain(|v| !base.contains(v) || theirs.contains(v));
+            for v in theirs {
+                if !base.contains(v) && !ours.contains(v) {
+                    ours.push(v.clone());
+                }
+            }
+        }
+        (ours, _, theirs) => *ours = theirs.clone(),
+    }
 }
 
 impl ReadOnlyLoader for ReadOnlyTransaction {
     fn retrieve(&self, uid: &str) -> Result<JsonValue> {
-        if let Some(cached) = self.cache.get(uid) {
-            Ok(JsonValue {
-                value: cached.clone(),
-            })
-        } else if let Ok(Some(ret)) = self.table.get(uid.to_string()) {
-            Ok(ret.value())
-        } else {
-            Err(anyhow!("error in loading {}", uid))
-        }
+        self.load(uid)
+    }
+
+    fn uids(&self) -> Result<Vec<String>> {
+        self.storage.uids()
+    }
+
+    fn indexed(&self, key: &IndexKey) -> Result<Vec<String>> {
+        self.storage.indexed(&key.encode())
+    }
+
+    fn indexed_classes(&self) -> Result<Vec<String>> {
+        indexed_classes(&*self.storage)
+    }
+
+    fn metadata(&self) -> Result<serde_json::Map<String, serde_json::Value>> {
+        Ok(self.storage.metadata()?.into_iter().collect())
+    }
+
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>> {
+        self.storage.document(uid)
+    }
+
+    fn documents_count(&self) -> Result<u64> {
+        self.storage.documents_count()
     }
 }
 
@@ -268,9 +1217,10 @@ impl ReadOnlyTransaction {
         if let Some(cached) = self.cache.get(uid) {
             Ok(JsonValue {
                 value: cached.clone(),
+                encoding: ValueEncoding::default(),
             })
-        } else if let Ok(Some(ret)) = self.table.get(uid.to_string()) {
-            Ok(ret.value())
+        } else if let Some(ret) = self.storage.get(uid)? {
+            Ok(ret)
         } else {
             Err(anyhow!("error in loading {}", uid))
         }
@@ -297,70 +1247,11 @@ impl Entity for serde_json::Value {
     }
 }
 
-fn json_bytes<T>(structure: T) -> Vec<u8>
-where
-    T: serde::Serialize,
-{
-    let mut bytes: Vec<u8> = Vec::new();
-    ciborium::into_writer(&structure, &mut bytes).unwrap();
-    #[cfg(feature = "zstd")]
-    {
-        zstd::encode_all(bytes.as_slice(), 0).unwrap()
-    }
-    #[cfg(not(feature = "zstd"))]
-    {
-        bytes
-    }
-}
-
-fn bytes_json(bytes: &[u8]) -> serde_json::Value {
-    #[cfg(feature = "zstd")]
-    let cbor = {
-        let bytes = zstd::decode_all(bytes).unwrap();
-        ciborium::from_reader(bytes.as_slice()).unwrap();
-    };
-
-    #[cfg(not(feature = "zstd"))]
-    let cbor: ciborium::Value = ciborium::from_reader(bytes).unwrap();
-
-    serde_json::to_value(cbor).unwrap()
-}
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JsonValue {
     pub value: serde_json::Value,
-}
-
-impl redb::Value for JsonValue {
-    type SelfType<'a> = JsonValue
-        where
-        Self: 'a;
-    type AsBytes<'a> = Vec<u8>
-        where
-        Self: 'a;
-
-    fn fixed_width() -> Option<usize> {
-        None
-    }
-
-    fn from_bytes<'a>(data: &'a [u8]) -> JsonValue
-    where
-        Self: 'a,
-    {
-        JsonValue {
-            value: bytes_json(data),
-        }
-    }
-
-    fn as_bytes<'a, 'b: 'a>(value: &'a Self::SelfType<'b>) -> Self::AsBytes<'a>
-    where
-        Self: 'a,
-        Self: 'b,
-    {
-        json_bytes(&value.value)
-    }
-
-    fn type_name() -> redb::TypeName {
-        redb::TypeName::new("test::JsonValue")
-    }
+    /// The encoding the value was stored with when read, or is to be
+    /// stored with when written.
+    #[serde(skip)]
+    pub encoding: ValueEncoding,
 }
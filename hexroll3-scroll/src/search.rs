@@ -0,0 +1,363 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use anyhow::Result;
+
+use crate::instance::*;
+use crate::renderer::render_entity;
+use crate::repository::*;
+
+/// The class of the entities describing how their parent entity is found
+/// in searches, usually declared in scrolls as:
+///
+/// ```text
+/// NPC {
+///     $IndexRef @ IndexedEntity {
+///         Render = "Name"
+///         Link = &HostingEntity
+///         Type = "location"
+///         Icon = "user"
+///     }
+/// }
+/// ```
+///
+/// The title of a search hit is the `Value` of the indexed entity, when
+/// rendered, or else the rendered attribute of the parent entity named by
+/// `Render`. `Details` and `Search` add searchable text, while `Link`,
+/// `Anchor`, `Icon` and `Type` are returned as is.
+pub const INDEXED_ENTITY_CLASS: &str = "IndexedEntity";
+
+/// How much a term weighs in a document by where it appears.
+const TITLE_WEIGHT: f64 = 3.0;
+const DETAILS_WEIGHT: f64 = 2.0;
+const TEXT_WEIGHT: f64 = 1.0;
+
+/// The number of words around the first matching term in snippets.
+const SNIPPET_WORDS_BEFORE: usize = 6;
+const SNIPPET_WORDS_AFTER: usize = 14;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SearchHit {
+    /// The uid of the entity found.
+    pub uid: String,
+    pub title: String,
+    pub details: String,
+    /// Text around the first term found, or the beginning of the text.
+    pub snippet: String,
+    /// The uid of the entity to navigate to, which may be an entity
+    /// hosting the entity found.
+    pub link: String,
+    pub anchor: Option<String>,
+    pub icon: Option<String>,
+    pub kind: Option<String>,
+    pub score: f64,
+}
+
+/// Search the documents of the search index, returning up to `limit` hits
+/// ranked by relevance.
+///
+/// Terms are weighed by how often they appear in a document, where terms in
+/// titles weigh the most, and by how rare they are in the whole sandbox.
+/// Documents matching more of the terms rank higher.
+pub fn search<T: ReadOnlyLoader>(tx: &T, text: &str, limit: usize) -> Result<Vec<SearchHit>> {
+    let terms: BTreeSet<String> = tokenize(text).collect();
+    if terms.is_empty() {
+        return Ok(vec![]);
+    }
+    let documents_count = tx.documents_count()? as f64;
+    let mut scores: HashMap<String, (f64, usize)> = HashMap::new();
+    for term in terms.iter() {
+        let uids = tx.indexed(&IndexKey::Term(term.clone()))?;
+        let idf = (1.0 + documents_count / uids.len().max(1) as f64).ln();
+        for uid in uids {
+            let Some(document) = tx.document(&uid)? else {
+                continue;
+            };
+            let weight = document["terms"][term].as_f64().unwrap_or(TEXT_WEIGHT);
+            let score = scores.entry(uid).or_default();
+            score.0 += (1.0 + weight.ln()) * idf;
+            score.1 += 1;
+        }
+    }
+    let mut ranked: Vec<(String, f64)> = scores
+        .into_iter()
+        .map(|(uid, (score, matched))| (uid, score * matched as f64 / terms.len() as f64))
+        .collect();
+    ranked.sort_by(|(a_uid, a), (b_uid, b)| b.total_cmp(a).then_with(|| a_uid.cmp(b_uid)));
+    ranked.truncate(limit);
+
+    let mut hits = vec![];
+    for (uid, score) in ranked {
+        let Some(document) = tx.document(&uid)? else {
+            continue;
+        };
+        let text = document["text"].as_str().unwrap_or_default();
+        let optional = |key: &str| document[key].as_str().map(str::to_string);
+        hits.push(SearchHit {
+            title: document["title"].as_str().unwrap_or_default().to_string(),
+            details: document["details"].as_str().unwrap_or_default().to_string(),
+            snippet: snippet(text, &terms),
+            link: optional("link").unwrap_or_else(|| uid.clone()),
+            anchor: optional("anchor"),
+            icon: optional("icon"),
+            kind: optional("kind"),
+            uid,
+            score,
+        });
+    }
+    Ok(hits)
+}
+
+/// Update the search documents of every entity stored or removed since the
+/// search index was last updated, returning the number of documents updated.
+///
+/// Changed entities are tracked by the repository, and their documents are
+/// updated within the same transaction by `append`, `unroll`, `reroll` and
+/// `promote`, as well as when creating, importing or migrating a sandbox.
+/// Transactions with no index, such as detached ones, are left as is.
+pub fn update(instance: &SandboxInstance, tx: &mut ReadWriteTransaction) -> Result<usize> {
+    if !tx.is_indexed() {
+        return Ok(0);
+    }
+    let mut owners = BTreeSet::new();
+    let mut visited = HashSet::new();
+    for uid in tx.take_changed()? {
+        let Ok(entity) = tx.retrieve(&uid).map(|entity| entity.value) else {
+            owners.insert(uid);
+            continue;
+        };
+        // Indexed entities and any other descendant, such as a name, change
+        // what their parent entity, and the nearest ancestor found in
+        // searches, are found by.
+        let mut ancestor = entity["parent_uid"].as_str().map(str::to_string);
+        if let Some(parent_uid) = &ancestor {
+            owners.insert(parent_uid.clone());
+        }
+        while let Some(ancestor_uid) = ancestor.take() {
+            if !visited.insert(ancestor_uid.clone()) {
+                break;
+            }
+            if indexed_entity(instance, tx, &ancestor_uid)?.is_some() {
+                owners.insert(ancestor_uid);
+                break;
+            }
+            ancestor = tx
+                .retrieve(&ancestor_uid)
+                .ok()
+                .and_then(|ancestor| ancestor.value["parent_uid"].as_str().map(str::to_string));
+        }
+        if !is_indexed_entity(instance, &entity) {
+            owners.insert(uid);
+        }
+    }
+    for uid in owners.iter() {
+        let document = document(instance, tx, uid)?;
+        tx.store_document(uid, document.as_ref())?;
+    }
+    Ok(owners.len())
+}
+
+/// The search document of an entity, if it exists and has an indexed
+/// entity as a child.
+fn document<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    uid: &str,
+) -> Result<Option<serde_json::Value>> {
+    let Ok(entity) = tx.retrieve(uid).map(|entity| entity.value) else {
+        return Ok(None);
+    };
+    let Some(indexed) = indexed_entity(instance, tx, uid)? else {
+        return Ok(None);
+    };
+    // Scrolls may use templating functions unknown to the renderer, in
+    // which case only the values that are not templates are searchable.
+    let indexed = render_entity(instance, tx, &indexed, true).unwrap_or(indexed);
+    let text_of = |value: &serde_json::Value| match value.as_str() {
+        Some(text) if !is_template(text) => strip_tags(text),
+        _ => String::new(),
+    };
+
+    let mut title = text_of(&indexed["Value"]);
+    if title.is_empty() {
+        if let Some(attr) = indexed["Render"].as_str() {
+            title = match &entity[attr] {
+                serde_json::Value::Array(uids) => match uids.first().and_then(|uid| uid.as_str()) {
+                    Some(child_uid) => {
+                        let child = tx.retrieve(child_uid)?.value;
+                        let rendered = render_entity(instance, tx, &child, true).unwrap_or(child);
+                        ["Full", "Title", "Name", "Value"]
+                            .iter()
+                            .map(|attr| text_of(&rendered[attr]))
+                            .find(|text| !text.is_empty())
+                            .unwrap_or_default()
+                    }
+                    None => String::new(),
+                },
+                value => text_of(value),
+            };
+        }
+    }
+    if title.is_empty() {
+        title = entity["class"].as_str().unwrap_or_default().to_string();
+    }
+    let details = text_of(&indexed["Details"]);
+    let mut text = vec![title.clone(), details.clone(), text_of(&indexed["Search"])];
+    if let Some(attrs) = entity.as_object() {
+        for (attr, value) in attrs {
+            if attr.starts_with('$')
+                || ["uid", "uuid", "class", "parent_uid"].contains(&attr.as_str())
+            {
+                continue;
+            }
+            let value = text_of(value);
+            // Pointers to other entities are not text
+            if !value.contains(' ') && tx.retrieve(&value).is_ok() {
+                continue;
+            }
+            text.push(value);
+        }
+    }
+    // Entities are also found by their class names, so a `ForestHex` is
+    // found searching for a forest or a hex.
+    if let Some(class) = entity["class"]
+        .as_str()
+        .and_then(|class| instance.classes.get(class))
+    {
+        text.extend(class.hierarchy.iter().map(|name| split_camel_case(name)));
+    }
+    text.retain(|text| !text.is_empty());
+    text.dedup();
+    let text = text.join(" ");
+
+    let mut terms: HashMap<String, f64> = HashMap::new();
+    for (part, weight) in [
+        (title.as_str(), TITLE_WEIGHT - TEXT_WEIGHT),
+        (details.as_str(), DETAILS_WEIGHT - TEXT_WEIGHT),
+        (text.as_str(), TEXT_WEIGHT),
+    ] {
+        for term in tokenize(part) {
+            *terms.entry(term).or_default() += weight;
+        }
+    }
+    let optional = |key: &str| match text_of(&indexed[key]) {
+        text if text.is_empty() => serde_json::Value::Null,
+        text => serde_json::Value::String(text),
+    };
+    Ok(Some(serde_json::json!({
+        "title": title,
+        "details": details,
+        "text": text,
+        "link": optional("Link"),
+        "anchor": optional("Anchor"),
+        "icon": optional("Icon"),
+        "kind": optional("Type"),
+        "terms": terms,
+    })))
+}
+
+/// The indexed entity among the children of an entity, if any.
+fn indexed_entity<T: ReadOnlyLoader>(
+    instance: &SandboxInstance,
+    tx: &T,
+    uid: &str,
+) -> Result<Option<serde_json::Value>> {
+    for child_uid in tx.indexed(&IndexKey::Parent(uid.to_string()))? {
+        let child = tx.retrieve(&child_uid)?.value;
+        if is_indexed_entity(instance, &child) {
+            return Ok(Some(child));
+        }
+    }
+    Ok(None)
+}
+
+fn is_indexed_entity(instance: &SandboxInstance, entity: &serde_json::Value) -> bool {
+    entity["class"]
+        .as_str()
+        .and_then(|class| instance.classes.get(class))
+        .is_some_and(|class| class.hierarchy.iter().any(|c| c == INDEXED_ENTITY_CLASS))
+}
+
+fn is_template(text: &str) -> bool {
+    text.contains("{{") || text.contains("{%")
+}
+
+/// Remove HTML tags and collapse whitespace.
+fn strip_tags(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                stripped.push(' ');
+            }
+            c if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn split_camel_case(name: &str) -> String {
+    let mut words = String::with_capacity(name.len() + 4);
+    let mut previous = ' ';
+    for c in name.chars() {
+        if c.is_uppercase() && previous.is_lowercase() {
+            words.push(' ');
+        }
+        words.push(c);
+        previous = c;
+    }
+    words
+}
+
+/// Split text into lowercase alphanumeric terms of two characters or more.
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(|word| word.to_lowercase())
+}
+
+/// The words of a text around the first of the terms it contains.
+fn snippet(text: &str, terms: &BTreeSet<String>) -> String {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let found = words
+        .iter()
+        .position(|word| tokenize(word).any(|term| terms.contains(&term)))
+        .unwrap_or(0);
+    let start = found.saturating_sub(SNIPPET_WORDS_BEFORE);
+    let end = words.len().min(found + SNIPPET_WORDS_AFTER);
+    let mut snippet = words[start..end].join(" ");
+    if start > 0 {
+        snippet.insert_str(0, "… ");
+    }
+    if end < words.len() {
+        snippet.push_str(" …");
+    }
+    snippet
+}
@@ -30,7 +30,7 @@ use indexmap::IndexMap;
 use std::marker::Send;
 use std::marker::Sync;
 
-use crate::{instance::*, repository::*};
+use crate::{instance::*, references::ReferenceKind, repository::*};
 
 /// Scroll class definition data:
 ///
@@ -48,6 +48,9 @@ pub struct Class {
     pub subclasses: SubclassesSpecifier,
     pub hierarchy: Vec<String>,
     pub collects: Vec<CollectionSpecifier>,
+    pub constraints: Vec<Constraint>,
+    pub predicates: Vec<SelectionFilter>,
+    pub indexed: Vec<String>,
     pub html_body: Option<String>,
     pub html_header: Option<String>,
 }
@@ -85,10 +88,44 @@ pub enum SubclassesSpecifier {
 ///     attribute! << AnotherClassNameToCollect
 /// }
 /// ```
+///
+/// Collections can be filtered on the attributes of collected entities,
+/// and capped to a maximum size:
+///
+/// ```text
+/// EntityClass {
+///     << Monster (Intelligent = true)
+///     magical? << Treasure (Category == magical, limit 5)
+///     shops? << Shop (owner.alive)
+/// }
+/// ```
 #[derive(Clone, PartialEq)]
 pub struct CollectionSpecifier {
     pub class_name: String,
     pub virtual_attribute: Option<CollectionAttribute>,
+    pub filters: Vec<SelectionFilter>,
+    pub limit: Option<usize>,
+}
+
+impl CollectionSpecifier {
+    /// Whether collected entities are filtered or capped.
+    pub fn is_filtered(&self) -> bool {
+        !self.filters.is_empty() || self.limit.is_some()
+    }
+
+    /// The key of the collection in entity frames.
+    ///
+    /// Filtered collections with an attribute are keyed by both the class
+    /// and the attribute names, so several of them can collect the same
+    /// class. Any other collection is keyed by the class name.
+    pub fn key(&self) -> String {
+        match &self.virtual_attribute {
+            Some(attr) if self.is_filtered() => {
+                format!("{}.{}", self.class_name, attr.attr_name)
+            }
+            _ => self.class_name.clone(),
+        }
+    }
 }
 
 /// An attribute specification for collections:
@@ -106,6 +143,67 @@ pub struct CollectionAttribute {
     pub is_array: bool,
 }
 
+/// A constraint on the entities of a class within an ancestor scope,
+/// enforced by re-drawing when rolling:
+///
+/// ```text
+/// Tavern {
+///     unique name in Settlement
+/// }
+///
+/// Dragon {
+///     quota 1 in Realm
+/// }
+///
+/// Ruler {
+///     unique class in Realm
+/// }
+/// ```
+///
+/// Constraints are inherited by subclasses and apply to all the entities
+/// of the declaring class, including entities of any of its subclasses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Constraint {
+    pub kind: ConstraintKind,
+    pub scope: String,
+    pub declared_by: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConstraintKind {
+    /// No two entities within the scope can have the same attribute value.
+    Unique(String),
+    /// At most this number of entities within the scope.
+    Quota(usize),
+}
+
+impl Constraint {
+    /// The key of the constraint in the registry of its scope entity frame.
+    pub fn key(&self) -> String {
+        match &self.kind {
+            ConstraintKind::Unique(attr) => format!("{}.{}", self.declared_by, attr),
+            ConstraintKind::Quota(_) => self.declared_by.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            ConstraintKind::Unique(attr) => write!(
+                f,
+                "unique {} of {} in {}",
+                attr, self.declared_by, self.scope
+            ),
+            ConstraintKind::Quota(count) => write!(
+                f,
+                "quota of {} {} in {}",
+                count, self.declared_by, self.scope
+            ),
+        }
+    }
+}
+
 /// Attr holds the generation command used to generate an attribute
 /// in an entity.
 ///
@@ -144,11 +242,28 @@ pub trait AttrCommand {
     fn value(&self) -> Option<String> {
         None
     }
+    /// The class names this command rolls entities of, and the average
+    /// number of entities it rolls, used to estimate generation sizes.
+    fn expected_rolls(&self, _builder: &SandboxBuilder) -> Option<(Vec<String>, f64)> {
+        None
+    }
+    /// The kind of references to other entities this command stores in
+    /// its attribute, if any.
+    fn reference_kind(&self) -> Option<ReferenceKind> {
+        None
+    }
+    /// The names of the attributes this command injects into the entities
+    /// it rolls, uses or picks, if any.
+    fn injected_attrs(&self) -> Vec<String> {
+        vec![]
+    }
 }
 
 /// InjectCommand can inject or eject attributes or attribute overrides to entities
 /// picked, used or pointed-at.
 pub trait InjectCommand {
+    /// The name of the attribute this command injects.
+    fn name(&self) -> &str;
     fn inject(
         &self,
         instance: &SandboxBuilder,
@@ -171,6 +286,125 @@ pub struct Injectors {
     pub appenders: Vec<Arc<dyn InjectCommand + Send + Sync>>,
 }
 
+impl Injectors {
+    /// The names of the attributes injected.
+    pub fn attr_names(&self) -> Vec<String> {
+        self.prependers
+            .iter()
+            .chain(self.appenders.iter())
+            .map(|injector| injector.name().to_string())
+            .collect()
+    }
+}
+
+/// How entities are selected when using or picking collected entities,
+/// specified in parentheses after the class name:
+///
+/// ```text
+/// quest_giver % NPC (least_picked, intelligent, level >= 3)
+/// ```
+///
+/// The selection can hold a strategy and any number of filters on the
+/// stored attributes of candidate entities.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Selection {
+    pub strategy: SelectionStrategy,
+    pub filters: Vec<SelectionFilter>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum SelectionStrategy {
+    /// Any candidate, chosen uniformly.
+    #[default]
+    Random,
+    /// The candidates closest to the selecting entity in the entity
+    /// hierarchy.
+    Nearest,
+    /// The candidates picked or used least recently, if ever.
+    LeastPicked,
+    /// Each candidate in turn, in the order they were collected, starting
+    /// over from the least recently picked candidate.
+    RoundRobin,
+    /// Any candidate, weighted by the value of a numeric attribute.
+    Weighted(String),
+}
+
+/// A predicate on a candidate attribute, such as `level >= 3`, or just the
+/// attribute name for testing its value is truthy. The attribute can be
+/// a path into child or referenced entities, such as `owner.alive`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SelectionFilter {
+    pub attr: String,
+    pub operator: FilterOperator,
+    pub value: serde_json::Value,
+}
+
+impl std::fmt::Display for SelectionFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = match self.operator {
+            FilterOperator::Truthy => return write!(f, "{}", self.attr),
+            FilterOperator::Equal => "==",
+            FilterOperator::NotEqual => "!=",
+            FilterOperator::Greater => ">",
+            FilterOperator::GreaterOrEqual => ">=",
+            FilterOperator::Less => "<",
+            FilterOperator::LessOrEqual => "<=",
+        };
+        match &self.value {
+            serde_json::Value::String(value) => write!(f, "{} {} {}", self.attr, operator, value),
+            value => write!(f, "{} {} {}", self.attr, operator, value),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum FilterOperator {
+    Truthy,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+/// What to do when using or picking finds no collected entity, specified
+/// after the class name and any selection:
+///
+/// ```text
+/// quest_giver % NPC else @ Peasant
+/// patron ? Noble else Merchant
+/// ruler ? Monarch else fail
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Fallback {
+    /// Leave the attribute without an entity.
+    #[default]
+    Nothing,
+    /// Roll a fresh entity of the class, collect it and select it.
+    Roll(String),
+    /// Try selecting a collected entity of another class.
+    Select(String),
+    /// Fail generation.
+    Fail,
+}
+
+/// How a context reference finds the entity holding the referenced attribute:
+///
+/// ```text
+/// realm_name = :Realm.name         # nearest ancestor of class Realm
+/// rival_name = :~rival.name        # entity in the parent's `rival` attribute
+/// town_name = :>Settlement.name    # first Settlement rolled under this entity
+/// inn_name = :Realm>Tavern.name    # nearest Tavern anywhere in the current Realm
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ContextRelation {
+    #[default]
+    Ancestor,
+    Sibling,
+    Descendant(Option<String>),
+}
+
 /// Provides the toolset required to properly define a Scroll class and
 /// is primarily used by the Scroll parser.
 pub struct ClassBuilder {
@@ -180,6 +414,9 @@ pub struct ClassBuilder {
     pub subclasses: SubclassesSpecifier,
     pub hierarchy: Vec<String>,
     pub collects: Vec<CollectionSpecifier>,
+    pub constraints: Vec<Constraint>,
+    pub predicates: Vec<SelectionFilter>,
+    pub indexed: Vec<String>,
     pub html_body: Option<String>,
     pub html_header: Option<String>,
     expanded: bool,
@@ -195,6 +432,9 @@ impl ClassBuilder {
             subclasses: SubclassesSpecifier::Empty(),
             hierarchy: vec![],
             collects: vec![],
+            constraints: vec![],
+            predicates: vec![],
+            indexed: vec![],
             expanded: false,
             html_body: None,
             html_header: None,
@@ -266,6 +506,41 @@ impl ClassBuilder {
         self.collects.push(spec);
     }
 
+    /// Adds a constraint on the entities of the class.
+    pub fn constrain(&mut self, kind: ConstraintKind, scope: &str) {
+        self.constraints.push(Constraint {
+            kind,
+            scope: scope.to_string(),
+            declared_by: self.name.clone(),
+        });
+    }
+
+    /// Adds a predicate rolled entities of the class must meet, re-rolling
+    /// them until they do:
+    ///
+    /// ```text
+    /// Dungeon {
+    ///     until traps >= 3
+    /// }
+    /// ```
+    pub fn predicate(&mut self, filter: SelectionFilter) {
+        self.predicates.push(filter);
+    }
+
+    /// Adds an attribute to index for entities of the class, so they can be
+    /// queried by its value:
+    ///
+    /// ```text
+    /// Tavern {
+    ///     index Name, Quality
+    /// }
+    /// ```
+    pub fn index(&mut self, attr: &str) {
+        if !self.indexed.iter().any(|a| a == attr) {
+            self.indexed.push(attr.to_string());
+        }
+    }
+
     /// Expands the class with attributes from another class using its name.
     pub fn expand(
         &mut self,
@@ -283,6 +558,9 @@ impl ClassBuilder {
     /// Extends this class with attributes and properties of a parent class.
     pub fn extends(&mut self, instance: &SandboxInstance, parent_class_name: &str) -> &mut Self {
         self.parent = parent_class_name.to_string();
+        self.constraints = instance.classes[parent_class_name].constraints.clone();
+        self.predicates = instance.classes[parent_class_name].predicates.clone();
+        self.indexed = instance.classes[parent_class_name].indexed.clone();
         let mut parent_class_name_mut = parent_class_name;
 
         while !parent_class_name_mut.is_empty() {
@@ -322,6 +600,9 @@ impl ClassBuilder {
             subclasses: self.subclasses,
             hierarchy: self.hierarchy,
             collects: self.collects,
+            constraints: self.constraints,
+            predicates: self.predicates,
+            indexed: self.indexed,
             html_body: self.html_body,
             html_header: self.html_header,
         }
@@ -0,0 +1,157 @@
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use crate::{encoding::ValueEncoding, repository::JsonValue};
+use anyhow::{anyhow, Result};
+use std::any::Any;
+
+/// The path used to create a repository held in memory rather than in a file.
+pub const IN_MEMORY: &str = ":memory:";
+
+/// A storage backend holding the tables of a repository: entities and their
+/// frames, secondary indexes, search documents, metadata, compression
+/// dictionaries and snapshots.
+///
+/// `RedbStorage` stores them in a redb file, while `MemoryStorage` keeps
+/// them in memory.
+///
+/// Backends serialize write transactions, but readers never wait for a
+/// writer: they read the tables as last committed, so rendering proceeds
+/// while a long write, such as rerolling a region, is in progress.
+pub trait Storage: Send + Sync {
+    /// Begin reading a consistent view of the tables, unaffected by any
+    /// write committed later. Readers can be moved to or shared with other
+    /// threads.
+    fn reader(&self) -> Result<BoxedStorageReader>;
+
+    /// Run `f` in a write transaction, storing values using `encoding`
+    /// where the backend encodes values. Everything written is committed
+    /// when `f` succeeds and discarded otherwise.
+    fn write(
+        &self,
+        encoding: ValueEncoding,
+        f: &mut dyn FnMut(&mut dyn StorageWriter) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Take a savepoint of the current state, lasting as long as it is held.
+    fn savepoint(&self) -> Result<Savepoint>;
+
+    /// Restore the state a savepoint was taken at.
+    fn restore(&self, savepoint: &Savepoint) -> Result<()>;
+
+    /// Take a named snapshot of the current state.
+    fn snapshot(&self, name: &str) -> Result<()>;
+
+    /// The names of all snapshots, from the oldest to the newest.
+    fn snapshots(&self) -> Result<Vec<String>>;
+
+    /// Restore the state a snapshot was taken at, discarding any snapshot
+    /// taken after it.
+    fn restore_snapshot(&self, name: &str) -> Result<()>;
+
+    /// Delete a snapshot.
+    fn delete_snapshot(&self, name: &str) -> Result<()>;
+
+    /// Run `f` reading the tables as they were when a snapshot was taken.
+    fn read_snapshot(
+        &self,
+        name: &str,
+        f: &mut dyn FnMut(&dyn StorageReader) -> Result<()>,
+    ) -> Result<()>;
+
+    /// Whether the index and search tables exist.
+    fn is_indexed(&self) -> Result<bool>;
+
+    /// The stored compression dictionaries.
+    fn dictionaries(&self) -> Result<Vec<Vec<u8>>>;
+
+    /// Store a compression dictionary under its id.
+    fn add_dictionary(&self, id: u32, dictionary: &[u8]) -> Result<()>;
+
+    /// The size of the stored data in bytes.
+    fn size(&self) -> Result<u64>;
+
+    /// Reclaim unused space, returning whether anything was compacted.
+    fn compact(&mut self) -> Result<bool>;
+}
+
+/// A reader of a storage backend that can be shared across threads.
+pub type BoxedStorageReader = Box<dyn StorageReader + Send + Sync>;
+
+/// Reads the tables of a storage backend.
+///
+/// Index keys are encoded (see `IndexKey`). Reading the index or search
+/// documents fails when the backend has no such tables.
+pub trait StorageReader {
+    /// The stored value of an entity or frame.
+    fn get(&self, uid: &str) -> Result<Option<JsonValue>>;
+    /// The uids of every stored value, in order.
+    fn uids(&self) -> Result<Vec<String>>;
+    /// The uids indexed under an encoded index key, in order.
+    fn indexed(&self, key: &str) -> Result<Vec<String>>;
+    /// The encoded index keys starting with a prefix that index any uid, in
+    /// order.
+    fn index_keys(&self, prefix: &str) -> Result<Vec<String>>;
+    /// The search document of an entity.
+    fn document(&self, uid: &str) -> Result<Option<serde_json::Value>>;
+    /// The uids of every entity having a search document, in order.
+    fn document_uids(&self) -> Result<Vec<String>>;
+    /// The number of search documents.
+    fn documents_count(&self) -> Result<u64>;
+    /// Every metadata value by key, in order.
+    fn metadata(&self) -> Result<Vec<(String, serde_json::Value)>>;
+}
+
+/// Writes the tables of a storage backend within a write transaction.
+pub trait StorageWriter: StorageReader {
+    fn insert(&mut self, uid: &str, value: &serde_json::Value) -> Result<()>;
+    fn remove(&mut self, uid: &str) -> Result<()>;
+    /// Index a uid under an encoded index key.
+    fn index(&mut self, key: &str, uid: &str) -> Result<()>;
+    /// Remove a uid from an encoded index key.
+    fn unindex(&mut self, key: &str, uid: &str) -> Result<()>;
+    /// Remove every uid indexed under an encoded index key, returning them.
+    fn take_indexed(&mut self, key: &str) -> Result<Vec<String>>;
+    fn insert_document(&mut self, uid: &str, document: &serde_json::Value) -> Result<()>;
+    fn remove_document(&mut self, uid: &str) -> Result<()>;
+    /// Remove every index entry and search document.
+    fn clear_indexes(&mut self) -> Result<()>;
+    fn set_metadata(&mut self, key: &str, value: &serde_json::Value) -> Result<()>;
+}
+
+/// A savepoint taken by a storage backend, which only the backend that
+/// took it can restore.
+pub struct Savepoint(Box<dyn Any + Send>);
+
+impl Savepoint {
+    pub fn new<T: Any + Send>(savepoint: T) -> Self {
+        Savepoint(Box::new(savepoint))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Result<&T> {
+        self.0
+            .downcast_ref()
+            .ok_or_else(|| anyhow!("Savepoint was taken by another storage backend"))
+    }
+}
@@ -3,9 +3,16 @@ mod utils;
 #[cfg(test)]
 mod tests {
 
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
     use hexroll3_scroll::generators::*;
     use hexroll3_scroll::instance::*;
+    use hexroll3_scroll::progress::*;
+    use hexroll3_scroll::references::*;
     use hexroll3_scroll::renderer::*;
+    use hexroll3_scroll::repository::{IndexKey, ReadOnlyLoader, ReadWriteTransaction};
+    use hexroll3_scroll::storage::IN_MEMORY;
 
     use crate::utils::create_tempfile;
 
@@ -56,8 +63,7 @@ class1 {
     ]
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         let generated_ids = instance
             .repo
             .mutate(|tx| {
@@ -90,8 +96,7 @@ class1 {
     test7 @ 2d20+1
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         let _generated_id = instance
             .repo
             .mutate(|tx| {
@@ -118,8 +123,7 @@ class2 {
     [3..10 list] @ class1
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         let generated_ids = instance
             .repo
             .mutate(|tx| {
@@ -150,8 +154,7 @@ class2 {
     entity @ &indirection
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         let generated_ids = instance
             .repo
             .mutate(|tx| {
@@ -188,8 +191,7 @@ class2 {
     child! @ class1
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         let generated_ids = instance
             .repo
             .mutate(|tx| {
@@ -211,6 +213,123 @@ class2 {
         assert_eq!(rendered_result["child"]["value2"], "bar");
     }
 
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_sibling_and_descendant_context_attributes() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name! = Bob
+}
+
+Rival {
+    foe! = :~hero.name
+}
+
+Guide {
+    town! = :Region>Settlement.name
+}
+
+Tavern {
+    name! = Inn
+}
+
+Settlement {
+    name! = Town
+    [1..1 taverns!] @ Tavern
+    inn_name! = :>Tavern.name
+}
+
+Hex {
+    [1..1 settlements!] @ Settlement
+}
+
+Camp {
+    scout! @ Guide
+}
+
+Region {
+    hero! @ NPC
+    rival! @ Rival
+    [2..2 hexes!] @ Hex
+    [1..1 camps!] @ Camp
+}
+
+Frontier {
+    [2..2 hexes!] @ Hex
+    outpost! @ Settlement
+    camp! @ Camp
+}",
+        );
+        instance.repo.create(IN_MEMORY).unwrap();
+        let builder = SandboxBuilder::from_instance(&instance);
+        let region_uid = instance
+            .repo
+            .mutate(|tx| roll(&builder, tx, "Region", "root", None))
+            .unwrap();
+        let region = instance.repo.load(&region_uid).unwrap();
+        let rendered = instance
+            .repo
+            .inspect(|tx| render_entity(&instance, tx, &region, false))
+            .unwrap();
+        assert_eq!(rendered["rival"]["foe"], "Bob");
+        assert_eq!(rendered["hexes"][0]["settlements"][0]["inn_name"], "Inn");
+        assert_eq!(rendered["camps"][0]["scout"]["town"], "Town");
+
+        let settlement_of = |hex: &serde_json::Value| {
+            let hex = instance.repo.load(hex.as_str().unwrap()).unwrap();
+            hex["settlements"][0].as_str().unwrap().to_string()
+        };
+        let first = settlement_of(&region["hexes"][0]);
+        let second = settlement_of(&region["hexes"][1]);
+        let camp = instance
+            .repo
+            .load(region["camps"][0].as_str().unwrap())
+            .unwrap();
+        let scout_uid = camp["scout"][0].as_str().unwrap().to_string();
+        let scout = instance.repo.load(&scout_uid).unwrap();
+        assert_eq!(scout["town"]["spec"]["uid"], first.as_str());
+        assert_eq!(
+            instance.repo.load(&first).unwrap()["$users"],
+            serde_json::json!([{"uid": scout_uid, "attr": "town"}])
+        );
+
+        instance
+            .repo
+            .mutate(|tx| unroll(&builder, tx, &first, None))
+            .unwrap();
+        let scout = instance.repo.load(&scout_uid).unwrap();
+        assert_eq!(scout["town"]["spec"]["uid"], second.as_str());
+        assert_eq!(
+            instance.repo.load(&second).unwrap()["$users"],
+            serde_json::json!([{"uid": scout_uid, "attr": "town"}])
+        );
+
+        instance
+            .repo
+            .mutate(|tx| unroll(&builder, tx, &scout_uid, None))
+            .unwrap();
+        assert!(instance.repo.load(&second).unwrap()["$users"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+
+        // The nearest descendant is found the same way with and without
+        // indexes, which ephemeral transactions do not maintain
+        let scout_town = |tx: &mut ReadWriteTransaction| -> anyhow::Result<bool> {
+            let frontier_uid = roll(&builder, tx, "Frontier", "root", None)?;
+            let frontier = tx.load(&frontier_uid)?.clone();
+            let camp = tx.load(frontier["camp"][0].as_str().unwrap())?.clone();
+            let scout = tx.load(camp["scout"][0].as_str().unwrap())?;
+            Ok(scout["town"]["spec"]["uid"] == frontier["outpost"][0])
+        };
+        assert!(instance.repo.mutate(scout_town).unwrap());
+        let mut ephemeral = instance.repo.ephemeral().unwrap();
+        assert!(!ephemeral.is_indexed());
+        assert!(scout_town(&mut ephemeral).unwrap());
+    }
+
     // ------------------------------------------------------------------------
     #[test]
     fn test_pointer_attribute() {
@@ -229,8 +348,7 @@ class2 {
     output! = <% {{child.injected}} %>
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         let generated_ids = instance
             .repo
             .mutate(|tx| {
@@ -274,8 +392,7 @@ class3 {
     b @ class2
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         instance
             .repo
             .mutate(|tx| {
@@ -318,9 +435,8 @@ class3 {
 }",
         );
 
-        let tmp = create_tempfile();
         let mut v: Vec<String> = Vec::new();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         instance
             .repo
             .mutate(|tx| {
@@ -385,8 +501,7 @@ class3 {
     b @ class2
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         instance
             .repo
             .mutate(|tx| {
@@ -452,8 +567,7 @@ class3 {
     b @ class2
 }",
         );
-        let tmp = create_tempfile();
-        instance.repo.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.repo.create(IN_MEMORY).unwrap();
         instance
             .repo
             .mutate(|tx| {
@@ -504,6 +618,196 @@ class3 {
             })
             .unwrap();
     }
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_seeded_concurrent_rolling() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Hex {
+    name! @ [
+        * a
+        * b
+        * c
+    ]
+}
+
+Region {
+    [2..4 hexes!] @ Hex
+}
+
+main {
+    << Hex
+    [3..3 regions!] @ Region
+    all_hexes! << Hex
+}",
+        );
+        let options = BuilderOptions {
+            seed: Some(42),
+            concurrent_classes: ["Region".to_string()].into(),
+            ..Default::default()
+        };
+        let sandboxes = instance
+            .create_many(&[IN_MEMORY, IN_MEMORY], &options)
+            .unwrap();
+        assert_ne!(sandboxes[0].sid(), sandboxes[1].sid());
+
+        let render_root = |sandbox: &SandboxInstance| {
+            let root = sandbox.repo.load(&sandbox.sid().unwrap()).unwrap();
+            sandbox
+                .repo
+                .inspect(|tx| render_entity(sandbox, tx, &root, true))
+                .unwrap()
+        };
+        let rendered = render_root(&sandboxes[0]);
+        let hexes: usize = rendered["regions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|region| region["hexes"].as_array().unwrap().len())
+            .sum();
+        assert_eq!(rendered["all_hexes"].as_array().unwrap().len(), hexes);
+
+        let mut again = SandboxInstance::new();
+        again.classes = instance.classes.clone();
+        again
+            .create_with_options(
+                IN_MEMORY,
+                BuilderOptions {
+                    seed: Randomizer::seeded(42).next_seed().into(),
+                    ..options
+                },
+            )
+            .unwrap();
+        assert_eq!(again.sid(), sandboxes[0].sid());
+        assert_eq!(render_root(&again), rendered);
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_concurrent_rolling_conflicts_and_stored_entities() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+}
+
+Villain {
+    name = Zed
+}
+
+Region {
+    patron ? NPC
+    nemesis % Villain
+}
+
+Kingdom {
+    [2..2 regions!] @ Region
+}
+
+main {
+    << NPC
+    << Villain
+    [1..1 npcs!] @ NPC
+    [1..1 villains!] @ Villain
+    [1..1 kingdoms!] @ Kingdom
+}",
+        );
+        let options = BuilderOptions {
+            seed: Some(7),
+            concurrent_classes: ["Region".to_string()].into(),
+            ..Default::default()
+        };
+        instance
+            .create_with_options(IN_MEMORY, options.clone())
+            .unwrap();
+        let main = instance.repo.load(&instance.sid().unwrap()).unwrap();
+        let users_of = |instance: &SandboxInstance, attr: &str| {
+            let list = if attr == "patron" { "npcs" } else { "villains" };
+            instance.repo.load(main.first_in(list).unwrap()).unwrap()["$users"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter(|user| user["attr"] == attr)
+                .count()
+        };
+        // Both regions were rolled concurrently, but only one can use the
+        // villain, while both can pick the NPC
+        assert_eq!(users_of(&instance, "nemesis"), 1);
+        assert_eq!(users_of(&instance, "patron"), 2);
+        assert!(instance.check().unwrap().is_consistent());
+
+        // Regions rolled concurrently in a later transaction pick the NPC,
+        // which was stored before and never loaded by that transaction
+        instance
+            .repo
+            .mutate(|tx| {
+                let builder = SandboxBuilder::with_options(&instance, options.clone());
+                append(
+                    &builder,
+                    tx,
+                    main["uid"].as_str().unwrap(),
+                    "kingdoms",
+                    None,
+                )
+            })
+            .unwrap();
+        assert_eq!(users_of(&instance, "nemesis"), 1);
+        assert_eq!(users_of(&instance, "patron"), 4);
+        assert!(instance.check().unwrap().is_consistent());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_concurrent_rolling_picks_like_sequential_rolling() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+}
+
+Region {
+    patron ? NPC (least_picked)
+}
+
+main {
+    << NPC
+    [3..3 npcs!] @ NPC
+    [3..3 regions!] @ Region
+}",
+        );
+        // The number of picks of every NPC, and every pick number taken
+        let mut picks = |concurrent_classes: HashSet<String>| {
+            instance
+                .create_with_options(
+                    IN_MEMORY,
+                    BuilderOptions {
+                        seed: Some(3),
+                        concurrent_classes,
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+            let main = instance.repo.load(&instance.sid().unwrap()).unwrap();
+            let mut counts = vec![];
+            let mut numbers = vec![];
+            for npc in main["npcs"].as_array().unwrap() {
+                let npc = instance.repo.load(npc.as_str().unwrap()).unwrap();
+                let users = npc["$users"].as_array().cloned().unwrap_or_default();
+                counts.push(users.len());
+                numbers.extend(users.iter().map(|user| user["picked"].as_u64().unwrap()));
+            }
+            numbers.sort();
+            assert!(instance.check().unwrap().is_consistent());
+            (counts, numbers)
+        };
+        let sequential = picks(HashSet::new());
+        assert_eq!(sequential, (vec![1, 1, 1], vec![1, 2, 3]));
+        assert_eq!(picks(["Region".to_string()].into()), sequential);
+    }
+
     // ------------------------------------------------------------------------
     #[test]
     fn test_create_instance() {
@@ -527,4 +831,1103 @@ main {
             instance.open(tmp.path().to_str().unwrap()).unwrap();
         }
     }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_progress_and_cancellation() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Hex {}
+
+Region {
+    [4..4 hexes!] @ Hex
+}
+
+main {
+    [2..2 regions!] @ Region
+}",
+        );
+        let reports = Arc::new(Mutex::new(Vec::<Progress>::new()));
+        let options = BuilderOptions {
+            on_progress: Some({
+                let reports = reports.clone();
+                Arc::new(move |progress: &Progress| {
+                    reports.lock().unwrap().push(progress.clone());
+                })
+            }),
+            ..Default::default()
+        };
+        instance
+            .create_with_options(IN_MEMORY, options.clone())
+            .unwrap();
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 11);
+        let last = reports.last().unwrap();
+        assert_eq!(last.rolled, 11);
+        assert_eq!(last.estimated_total, 11);
+        assert_eq!(last.depth, 0);
+        assert_eq!(last.rolled_per_class["Hex"], 8);
+        assert_eq!(last.rolled_per_class["Region"], 2);
+        assert_eq!(reports.iter().map(|p| p.depth).max(), Some(2));
+
+        let cancellation = CancellationToken::new();
+        let options = BuilderOptions {
+            on_progress: Some({
+                let cancellation = cancellation.clone();
+                Arc::new(move |progress: &Progress| {
+                    if progress.rolled == 3 {
+                        cancellation.cancel();
+                    }
+                })
+            }),
+            cancellation,
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("cancelled.h3");
+        let mut cancelled = SandboxInstance::new();
+        cancelled.classes = instance.classes.clone();
+        assert!(cancelled
+            .create_with_options(filepath.to_str().unwrap(), options)
+            .is_err());
+        assert!(cancelled.sid().is_none());
+        assert!(cancelled.repo.load("root").is_err());
+        // Cancelled creates leave no file behind
+        assert!(!filepath.exists());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_runaway_generation_limits() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Cave {
+    [1..1 deeper_caves] @ Cave
+}
+
+Hex {}
+
+main {
+    [1..1 caves] @ Cave
+    [5..5 hexes] @ Hex
+}",
+        );
+        let create = |limits: Limits| {
+            let mut sandbox = SandboxInstance::new();
+            sandbox.classes = instance.classes.clone();
+            sandbox
+                .create_with_options(
+                    IN_MEMORY,
+                    BuilderOptions {
+                        limits,
+                        ..Default::default()
+                    },
+                )
+                .map(|_| ())
+                .map_err(|e| format!("{:#}", e))
+        };
+
+        let error = create(Limits {
+            max_depth: Some(4),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(error.contains("maximum depth of 4"));
+        assert!(error.contains("main > Cave > Cave > Cave > Cave"));
+
+        let error = create(Limits {
+            max_entities: Some(20),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(error.contains("maximum of 20 entities"));
+
+        let error = create(Limits {
+            max_entities_per_class: Some(10),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert!(error.contains("maximum of 10 Cave entities"));
+
+        // Recursive scrolls are stopped by default
+        let error = create(Limits::default()).unwrap_err();
+        assert!(error.contains(&format!("maximum depth of {}", DEFAULT_MAX_DEPTH)));
+
+        // Failed rolls leave the class chain empty
+        let mut sandbox = SandboxInstance::new();
+        sandbox.classes = instance.classes.clone();
+        sandbox.repo.create(IN_MEMORY).unwrap();
+        let builder = SandboxBuilder::with_options(
+            &sandbox,
+            BuilderOptions {
+                limits: Limits {
+                    max_depth: Some(4),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert!(sandbox
+            .repo
+            .mutate(|tx| roll(&builder, tx, "main", "root", None))
+            .is_err());
+        assert!(builder.class_chain.borrow().is_empty());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_standalone_class_sandbox() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Room {
+    realm! = :Realm.title
+    weather! = :Region.weather
+}
+
+Dungeon {
+    [2..2 rooms!] @ Room
+    realm! = :Realm.title
+}
+
+Realm {
+    title = Kingdom
+    [1..1 dungeons!] @ Dungeon
+}
+
+main {
+    [1..1 realms!] @ Realm
+}",
+        );
+        instance
+            .create_with_options(
+                IN_MEMORY,
+                BuilderOptions {
+                    root_class: Some("Dungeon".to_string()),
+                    stub_context: serde_json::json!({"Realm": {"title": "Elsewhere"}}),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let root = instance.repo.load(&instance.sid().unwrap()).unwrap();
+        assert_eq!(root["class"], "Dungeon");
+        let rendered = instance
+            .repo
+            .inspect(|tx| render_entity(&instance, tx, &root, true))
+            .unwrap();
+        assert_eq!(rendered["realm"], "Elsewhere");
+        assert_eq!(rendered["rooms"][0]["realm"], "Elsewhere");
+        assert_eq!(rendered["rooms"][1]["weather"], false);
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_oracle_roll_and_promote() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Encounter {
+    realm! = :main.title
+    creature! @ [
+        * wolf
+        * bear
+    ]
+}
+
+Hex {
+    [0..0 encounters!] @ Encounter
+}
+
+main {
+    << Encounter
+    title = Kingdom
+    [1..1 hexes!] @ Hex
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let main = instance.repo.load(&instance.sid().unwrap()).unwrap();
+        let hex_uid = main.first_in("hexes").unwrap().to_string();
+
+        let builder = SandboxBuilder::from_instance(&instance);
+        let oracle_roll = oracle(&builder, "Encounter", &hex_uid).unwrap();
+        assert_eq!(oracle_roll.rendered["realm"], "Kingdom");
+        assert!(["wolf", "bear"].contains(&oracle_roll.rendered["creature"].as_str().unwrap()));
+        assert!(instance.repo.load(&oracle_roll.uid).is_err());
+        assert!(instance.repo.load(&hex_uid).unwrap()["encounters"]
+            .as_array()
+            .unwrap()
+            .is_empty());
+
+        let rendered = oracle_roll.rendered.clone();
+        let uid = instance
+            .repo
+            .mutate(|tx| promote(&builder, tx, oracle_roll.clone(), "encounters"))
+            .unwrap();
+        let hex = instance.repo.load(&hex_uid).unwrap();
+        assert_eq!(hex.first_in("encounters"), Some(uid.as_str()));
+        let encounter = instance.repo.load(&uid).unwrap();
+        assert_eq!(encounter["$parent"]["uid"], hex_uid.as_str());
+        let rendered_again = instance
+            .repo
+            .inspect(|tx| render_entity(&instance, tx, &encounter, true))
+            .unwrap();
+        assert_eq!(rendered_again["creature"], rendered["creature"]);
+        let main_frame = instance
+            .repo
+            .load(&format!("{}_frame", instance.sid().unwrap()))
+            .unwrap();
+        assert!(main_frame["$collections"]["$unused"]["Encounter"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!(uid)));
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_parameters_override_globals() {
+        let scroll = "
+num_regions = 2
+climates = [
+    * temperate
+]
+
+Region {
+    climate! @ $climates
+}
+
+main {
+    [$num_regions..$num_regions regions!] @ Region
+}";
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(scroll);
+        let tmp = create_tempfile();
+        instance
+            .create_with_options(
+                tmp.path().to_str().unwrap(),
+                BuilderOptions {
+                    parameters: [
+                        ("num_regions".to_string(), serde_json::json!(5)),
+                        ("climates".to_string(), serde_json::json!(["arctic"])),
+                    ]
+                    .into(),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let sid = instance.sid().unwrap();
+        let main = instance.repo.load(&sid).unwrap();
+        assert_eq!(main["regions"].as_array().unwrap().len(), 5);
+        assert_eq!(
+            instance
+                .repo
+                .load(main.first_in("regions").unwrap())
+                .unwrap()["climate"],
+            "arctic"
+        );
+
+        drop(instance);
+        let mut reopened = SandboxInstance::new();
+        reopened.parse_buffer(scroll);
+        reopened.open(tmp.path().to_str().unwrap()).unwrap();
+        assert_eq!(reopened.global("num_regions"), Some(&serde_json::json!(5)));
+        let appended = reopened
+            .repo
+            .mutate(|tx| {
+                let builder = SandboxBuilder::from_instance(&reopened);
+                append(&builder, tx, &sid, "regions", None)
+            })
+            .unwrap();
+        assert_eq!(reopened.repo.load(&appended).unwrap()["climate"], "arctic");
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_selection_strategies_and_filters() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {}
+
+Smart(NPC) {
+    intelligent = true
+    level = 5
+}
+
+Dumb(NPC) {
+    intelligent = false
+    level = 0
+}
+
+FilteredQuest {
+    giver ? NPC (intelligent)
+}
+
+BalancedQuest {
+    giver ? NPC (least_picked, level >= 5)
+}
+
+RotatingQuest {
+    giver ? NPC (round_robin, intelligent == false)
+}
+
+WeightedQuest {
+    giver % NPC (weighted(level))
+}
+
+NearQuest {
+    giver ? NPC (nearest)
+}
+
+Region {
+    [1..1 npcs] @ Smart
+    [1..1 quests] @ NearQuest
+}
+
+main {
+    << NPC
+    [3..3 regions] @ Region
+    [3..3 smarts] @ Smart
+    [4..4 dumbs] @ Dumb
+    [12..12 balanced] @ BalancedQuest
+    [8..8 filtered] @ FilteredQuest
+    [8..8 rotating] @ RotatingQuest
+    [3..3 weighted] @ WeightedQuest
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let load = |uid: &serde_json::Value| instance.repo.load(uid.as_str().unwrap()).unwrap();
+        let givers = |main: &serde_json::Value, attr: &str| -> Vec<serde_json::Value> {
+            main[attr]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|quest| load(quest)["giver"][0].clone())
+                .collect()
+        };
+        let main = instance.repo.load(&instance.sid().unwrap()).unwrap();
+
+        for giver in givers(&main, "filtered") {
+            assert_eq!(load(&giver)["class"], "Smart");
+        }
+
+        let balanced = givers(&main, "balanced");
+        for giver in balanced.iter() {
+            assert_eq!(load(giver)["level"], 5);
+        }
+        // Region NPCs were already picked once by their region quests
+        let mut picks: Vec<usize> = main["smarts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|smart| balanced.iter().filter(|giver| *giver == smart).count())
+            .collect();
+        for region in main["regions"].as_array().unwrap().iter() {
+            let npc = &load(region)["npcs"][0];
+            picks.push(balanced.iter().filter(|giver| *giver == npc).count() + 1);
+        }
+        assert!(picks.iter().max().unwrap() - picks.iter().min().unwrap() <= 1);
+        // Picking the least recently picked NPCs repeats the order of picks
+        assert_eq!(balanced[..6], balanced[6..]);
+
+        let rotating = givers(&main, "rotating");
+        let dumbs = main["dumbs"].as_array().unwrap();
+        assert_eq!(rotating[..4], dumbs[..]);
+        assert_eq!(rotating[4..], dumbs[..]);
+
+        // Unrolling a quest forgets its pick, so appending a quest picks the
+        // same NPC again
+        let sid = instance.sid().unwrap();
+        let last = main["rotating"][7].as_str().unwrap().to_string();
+        let appended = instance
+            .repo
+            .mutate(|tx| {
+                let builder = SandboxBuilder::from_instance(&instance);
+                unroll(&builder, tx, &last, None)?;
+                append(&builder, tx, &sid, "rotating", None)
+            })
+            .unwrap();
+        assert_eq!(load(&serde_json::json!(appended))["giver"][0], dumbs[3]);
+
+        for giver in givers(&main, "weighted") {
+            assert_eq!(load(&giver)["class"], "Smart");
+        }
+
+        for region in main["regions"].as_array().unwrap().iter() {
+            let region = load(region);
+            let quest = load(&region["quests"][0]);
+            assert_eq!(quest["giver"][0], region["npcs"][0]);
+        }
+    }
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_use_and_pick_fallbacks() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {}
+Peasant(NPC) {}
+Noble(NPC) {}
+Monarch {}
+
+Quest {
+    giver % Noble else @ Peasant
+}
+
+Favor {
+    patron ? Noble (nearest) else Peasant
+}
+
+Court {
+    ruler ? Monarch else fail
+}
+
+Audience {
+    host ? Peasant (nearest)
+}
+
+main {
+    << Noble
+    << Peasant
+    << Monarch
+    [1..1 peasants] @ Peasant
+    [2..2 quests] @ Quest
+    [2..2 favors] @ Favor
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        let main = instance.repo.load(&sid).unwrap();
+        let load = |uid: &serde_json::Value| instance.repo.load(uid.as_str().unwrap());
+        let frame = instance.repo.load(&format!("{}_frame", sid)).unwrap();
+        let peasant = &main["peasants"][0];
+
+        let quests = main["quests"].as_array().unwrap();
+        let mut rolled = vec![];
+        for quest_uid in quests.iter() {
+            let quest = load(quest_uid).unwrap();
+            let giver = load(&quest["giver"][0]).unwrap();
+            assert_eq!(giver["class"], "Peasant");
+            assert_eq!(giver["parent_uid"], *quest_uid);
+            assert_eq!(quest["$fallbacks"]["giver"][0], giver["uid"]);
+            rolled.push(giver["uid"].clone());
+        }
+        assert_eq!(
+            frame["$collections"]["$used"]["Peasant"],
+            serde_json::json!(rolled)
+        );
+        assert_eq!(
+            frame["$collections"]["$unused"]["Peasant"],
+            serde_json::json!([peasant])
+        );
+
+        for favor in main["favors"].as_array().unwrap().iter() {
+            let favor = load(favor).unwrap();
+            assert_eq!(favor["patron"][0], *peasant);
+            assert!(favor["$fallbacks"].is_null());
+        }
+
+        instance
+            .repo
+            .mutate(|tx| {
+                unroll(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    quests[0].as_str().unwrap(),
+                    None,
+                )
+            })
+            .unwrap();
+        assert!(load(&rolled[0]).is_err());
+        let frame = instance.repo.load(&format!("{}_frame", sid)).unwrap();
+        assert_eq!(
+            frame["$collections"]["$used"]["Peasant"],
+            serde_json::json!([rolled[1]])
+        );
+
+        // Unrolling a fallback entity prunes it from the fallbacks of its
+        // user, which then rolls another one
+        instance
+            .repo
+            .mutate(|tx| {
+                unroll(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    rolled[1].as_str().unwrap(),
+                    None,
+                )
+            })
+            .unwrap();
+        let quest = load(&quests[1]).unwrap();
+        assert_ne!(quest["giver"][0], rolled[1]);
+        assert_eq!(quest["$fallbacks"]["giver"], quest["giver"]);
+
+        // Failing to select a collected entity fails the roll
+        instance
+            .repo
+            .mutate(|tx| tx.remove(peasant.as_str().unwrap()))
+            .unwrap();
+        let failed = instance.repo.mutate(|tx| {
+            roll(
+                &SandboxBuilder::from_instance(&instance),
+                tx,
+                "Audience",
+                &sid,
+                None,
+            )
+        });
+        assert!(failed.is_err());
+
+        let failed = instance.create_with_options(
+            IN_MEMORY,
+            BuilderOptions {
+                root_class: Some("Court".to_string()),
+                ..Default::default()
+            },
+        );
+        assert!(format!("{:#}", failed.err().unwrap()).contains("No collected Monarch"));
+    }
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_filtered_collections() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Monster {
+    Intelligent = false
+}
+Ogre(Monster) {}
+Lich(Monster) {
+    Intelligent = true
+}
+
+Treasure {}
+Coins(Treasure) {
+    Category = coins
+}
+Gem(Treasure) {
+    Category = gems
+}
+
+Alive {
+    alive = true
+}
+Dead {
+    alive = false
+}
+Shop {
+    open = true
+}
+GoodShop(Shop) {
+    owner @ Alive
+}
+BadShop(Shop) {
+    owner @ Dead
+}
+
+Hunt {
+    prey ? Monster
+}
+
+Dungeon {
+    << Monster (Intelligent = True)
+    << Shop
+    coins! << Treasure (Category == coins, limit 2)
+    gems! << Treasure (Category == gems)
+    living? << Shop (owner.alive)
+    open? << Shop (open)
+    [3..3 ogres] @ Ogre
+    [2..2 liches] @ Lich
+    [4..4 coin_piles] @ Coins
+    [2..2 gem_piles] @ Gem
+    [2..2 good] @ GoodShop
+    [1..1 bad] @ BadShop
+    robbed % Shop (owner.alive) {
+        open = false
+    }
+}",
+        );
+        instance
+            .create_with_options(
+                IN_MEMORY,
+                BuilderOptions {
+                    root_class: Some("Dungeon".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let sid = instance.sid().unwrap();
+        let dungeon = instance.repo.load(&sid).unwrap();
+        let frame = instance.repo.load(&format!("{}_frame", sid)).unwrap();
+        let collection = |key: &str| {
+            let collections = &frame["$collections"];
+            let mut uids = collections["$unused"][key].as_array().unwrap().clone();
+            uids.extend(collections["$used"][key].as_array().unwrap().clone());
+            uids
+        };
+
+        assert_eq!(
+            collection("Monster"),
+            dungeon["liches"].as_array().unwrap()[..]
+        );
+        assert_eq!(
+            collection("Treasure.coins"),
+            dungeon["coin_piles"].as_array().unwrap()[..2]
+        );
+        assert_eq!(
+            collection("Treasure.gems"),
+            dungeon["gem_piles"].as_array().unwrap()[..]
+        );
+        assert_eq!(
+            collection("Shop.living"),
+            dungeon["good"].as_array().unwrap()[..]
+        );
+
+        // The robbed shop is no longer open
+        let robbed = &dungeon["robbed"][0];
+        assert!(dungeon["good"].as_array().unwrap().contains(robbed));
+        let open = collection("Shop.open");
+        assert_eq!(open.len(), 2);
+        assert!(!open.contains(robbed));
+
+        let rendered = instance
+            .repo
+            .inspect(|tx| render_entity(&instance, tx, &dungeon, false))
+            .unwrap();
+        assert_eq!(rendered["coins"].as_array().unwrap().len(), 2);
+        assert_eq!(rendered["gems"].as_array().unwrap().len(), 2);
+
+        // Withdrawn entities are removed from filtered collections too
+        instance
+            .repo
+            .mutate(|tx| {
+                unroll(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    dungeon["gem_piles"][0].as_str().unwrap(),
+                    None,
+                )
+            })
+            .unwrap();
+        let frame = instance.repo.load(&format!("{}_frame", sid)).unwrap();
+        assert_eq!(
+            frame["$collections"]["$unused"]["Treasure.gems"],
+            serde_json::json!([dungeon["gem_piles"][1]])
+        );
+
+        // Criteria are checked again for entities changed since collected
+        let hunts = instance
+            .repo
+            .mutate(|tx| {
+                let builder = SandboxBuilder::from_instance(&instance);
+                let lich_uid = dungeon["liches"][0].as_str().unwrap();
+                tx.load(lich_uid)?["Intelligent"] = serde_json::json!(false);
+                tx.save(lich_uid)?;
+                let good = tx.load(dungeon["good"][0].as_str().unwrap())?.clone();
+                let owner_uid = good["owner"][0].as_str().unwrap();
+                tx.load(owner_uid)?["alive"] = serde_json::json!(false);
+                tx.save(owner_uid)?;
+                (0..5)
+                    .map(|_| roll(&builder, tx, "Hunt", &sid, None))
+                    .collect::<anyhow::Result<Vec<String>>>()
+            })
+            .unwrap();
+        for hunt in hunts.iter() {
+            let hunt = instance.repo.load(hunt).unwrap();
+            assert_eq!(hunt["prey"][0], dungeon["liches"][1]);
+        }
+        let dungeon = instance.repo.load(&sid).unwrap();
+        let rendered = instance
+            .repo
+            .inspect(|tx| render_entity(&instance, tx, &dungeon, true))
+            .unwrap();
+        let living = rendered["living"].as_array().unwrap();
+        assert_eq!(living.len(), 1);
+        assert_eq!(living[0]["uuid"], dungeon["good"][1]);
+    }
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_quotas_and_uniqueness_constraints() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Ruler {
+    ^ [
+        * King
+        * Queen
+        * Duke
+        * Count
+    ]
+    unique class in Realm
+}
+King(Ruler) {}
+Queen(Ruler) {}
+Duke(Ruler) {}
+Count(Ruler) {}
+
+Tavern {
+    name @ [
+        * Red Dragon
+        * Prancing Pony
+        * Green Dragon
+        * Rusty Anchor
+        * Drunken Goat
+        * Broken Wheel
+    ]
+    unique name in Realm
+}
+
+Monster {
+    ^ [
+        * Dragon
+        * Goblin
+    ]
+}
+Dragon(Monster) {
+    quota 1 in Realm
+}
+Goblin(Monster) {}
+
+Region {
+    ruler @ Ruler
+    tavern @ Tavern
+    [3..3 monsters] @ Monster
+}
+
+Realm {
+    [4..4 regions] @ Region
+}
+",
+        );
+        instance
+            .create_with_options(
+                IN_MEMORY,
+                BuilderOptions {
+                    root_class: Some("Realm".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let sid = instance.sid().unwrap();
+        let realm = instance.repo.load(&sid).unwrap();
+        let load = |uid: &serde_json::Value| instance.repo.load(uid.as_str().unwrap()).unwrap();
+        let regions: Vec<serde_json::Value> = realm["regions"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(load)
+            .collect();
+
+        let distinct = |values: Vec<serde_json::Value>| {
+            let count = values.len();
+            let mut values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            values.sort();
+            values.dedup();
+            values.len() == count
+        };
+        assert!(distinct(
+            regions
+                .iter()
+                .map(|r| load(&r["ruler"][0])["class"].clone())
+                .collect()
+        ));
+        assert!(distinct(
+            regions
+                .iter()
+                .map(|r| load(&r["tavern"][0])["name"].clone())
+                .collect()
+        ));
+        let dragons = regions
+            .iter()
+            .flat_map(|r| r["monsters"].as_array().unwrap().clone())
+            .filter(|monster| load(monster)["class"] == "Dragon")
+            .count();
+        assert!(dragons <= 1);
+
+        // Unrolling releases the taken values
+        instance
+            .repo
+            .mutate(|tx| {
+                unroll(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    regions[0]["uid"].as_str().unwrap(),
+                    None,
+                )
+            })
+            .unwrap();
+        let frame = instance.repo.load(&format!("{}_frame", sid)).unwrap();
+        let registry = &frame["$registry"];
+        assert_eq!(registry["Ruler.class"].as_object().unwrap().len(), 3);
+        assert_eq!(registry["Tavern.name"].as_object().unwrap().len(), 3);
+        assert!(registry["Tavern.name"]
+            .get(regions[0]["tavern"][0].as_str().unwrap())
+            .is_none());
+
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Throne {
+    quota 1 in Kingdom
+}
+
+Kingdom {
+    [2..2 thrones] @ Throne
+}
+",
+        );
+        let failed = instance.create_with_options(
+            IN_MEMORY,
+            BuilderOptions {
+                root_class: Some("Kingdom".to_string()),
+                limits: Limits {
+                    max_redraws: Some(3),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert!(format!("{:#}", failed.err().unwrap())
+            .contains("Unable to satisfy the quota of 1 Throne in Kingdom"));
+    }
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_roll_until_predicates() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Trap {}
+
+Ruler {
+    ^ [
+        * Necromancer
+        * Paladin
+    ]
+}
+Necromancer(Ruler) {}
+Paladin(Ruler) {}
+
+Dungeon {
+    [0..6 traps] @ Trap
+    until traps >= 3
+}
+
+Settlement {
+    ruler @ Ruler
+    until ruler.class != Necromancer
+}
+
+Cave { [0..6 traps] @ Trap until traps >= 3 }
+
+main {
+    << Trap
+    [5..5 dungeons] @ Dungeon
+    [5..5 settlements] @ Settlement
+    [5..5 caves] @ Cave
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        let main = instance.repo.load(&sid).unwrap();
+        let load = |uid: &serde_json::Value| instance.repo.load(uid.as_str().unwrap()).unwrap();
+
+        let mut traps = vec![];
+        for dungeon in main["dungeons"].as_array().unwrap() {
+            let dungeon_traps = load(dungeon)["traps"].as_array().unwrap().clone();
+            assert!(dungeon_traps.len() >= 3);
+            traps.extend(dungeon_traps);
+        }
+        // A predicate value ends at the closing brace of a one-line class
+        for cave in main["caves"].as_array().unwrap() {
+            let cave_traps = load(cave)["traps"].as_array().unwrap().clone();
+            assert!(cave_traps.len() >= 3);
+            traps.extend(cave_traps);
+        }
+        // Traps of discarded dungeons were not left behind
+        let frame = instance.repo.load(&format!("{}_frame", sid)).unwrap();
+        assert_eq!(
+            frame["$collections"]["$unused"]["Trap"],
+            serde_json::json!(traps)
+        );
+        for settlement in main["settlements"].as_array().unwrap() {
+            assert_eq!(load(&load(settlement)["ruler"][0])["class"], "Paladin");
+        }
+
+        // Discarded attempts of a custom predicate leave nothing behind
+        let attempts = std::cell::RefCell::new(vec![]);
+        let builder = SandboxBuilder::from_instance(&instance);
+        let uid = instance
+            .repo
+            .mutate(|tx| {
+                roll_with(&builder, tx, "Dungeon", &sid, None, |tx, uid| {
+                    let dungeon = tx.load(uid)?;
+                    let mut uids = vec![uid.to_string()];
+                    for trap in dungeon["traps"].as_array().unwrap() {
+                        uids.push(trap.as_str().unwrap().to_string());
+                    }
+                    attempts.borrow_mut().push(uids);
+                    Ok(attempts.borrow().len() == 3)
+                })
+            })
+            .unwrap();
+        let attempts = attempts.into_inner();
+        assert_eq!(attempts.len(), 3);
+        assert_eq!(attempts[2][0], uid);
+        for discarded in attempts[..2].iter().flatten() {
+            assert!(instance.repo.load(discarded).is_err());
+            assert!(instance.repo.load(&format!("{}_frame", discarded)).is_err());
+        }
+
+        let failed = instance
+            .repo
+            .mutate(|tx| roll_with(&builder, tx, "Dungeon", &sid, None, |_, _| Ok(false)));
+        // The last draw may also fail the class predicate, which is reported first
+        let message = format!("{:#}", failed.err().unwrap());
+        assert!(message.starts_with("Unable to roll Dungeon meeting"));
+        assert!(message.ends_with("after 32 re-draws"));
+    }
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_references_index() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+}
+
+Tavern {
+    name = Inn
+    keeper % NPC {
+        tavern_name = *name
+    }
+    patron ? NPC
+    realm_name = :Realm.name
+}
+
+Realm {
+    name = Land
+    << NPC
+    [2..2 npcs] @ NPC
+    [1..1 taverns] @ Tavern
+}",
+        );
+        instance
+            .create_with_options(
+                IN_MEMORY,
+                BuilderOptions {
+                    root_class: Some("Realm".to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let sid = instance.sid().unwrap();
+        let realm = instance.repo.load(&sid).unwrap();
+        let tavern_uid = realm["taverns"][0].as_str().unwrap().to_string();
+        let tavern = instance.repo.load(&tavern_uid).unwrap();
+        let keeper_uid = tavern["keeper"][0].as_str().unwrap().to_string();
+        let patron_uid = tavern["patron"][0].as_str().unwrap().to_string();
+        let referencing = |uid: &str| {
+            let mut uids = instance
+                .repo
+                .inspect(|tx| tx.indexed(&IndexKey::Reference(uid.to_string())))
+                .unwrap();
+            uids.sort();
+            uids
+        };
+        let mut expected = vec![sid.clone(), tavern_uid.clone()];
+        expected.sort();
+        assert_eq!(referencing(&keeper_uid), expected);
+        let edge = |from: &str, to: &str, kind: ReferenceKind, attr: &str| Reference {
+            from: from.to_string(),
+            to: to.to_string(),
+            kind,
+            attr: attr.to_string(),
+        };
+
+        let to_keeper = instance.references_to(&keeper_uid).unwrap();
+        assert!(to_keeper.contains(&edge(&sid, &keeper_uid, ReferenceKind::Child, "npcs")));
+        assert!(to_keeper.contains(&edge(
+            &tavern_uid,
+            &keeper_uid,
+            ReferenceKind::Use,
+            "keeper"
+        )));
+        assert!(to_keeper.contains(&edge(&sid, &keeper_uid, ReferenceKind::Collection, "NPC")));
+
+        let to_tavern = instance.references_to(&tavern_uid).unwrap();
+        assert_eq!(
+            to_tavern,
+            vec![
+                edge(&sid, &tavern_uid, ReferenceKind::Child, "taverns"),
+                edge(
+                    &keeper_uid,
+                    &tavern_uid,
+                    ReferenceKind::Pointer,
+                    "tavern_name"
+                ),
+            ]
+        );
+        assert!(instance.references_to(&sid).unwrap().contains(&edge(
+            &tavern_uid,
+            &sid,
+            ReferenceKind::Context,
+            "realm_name"
+        )));
+
+        let from_tavern = instance.references_from(&tavern_uid).unwrap();
+        assert_eq!(
+            from_tavern,
+            vec![
+                edge(&tavern_uid, &keeper_uid, ReferenceKind::Use, "keeper"),
+                edge(&tavern_uid, &patron_uid, ReferenceKind::Pick, "patron"),
+                edge(&tavern_uid, &sid, ReferenceKind::Context, "realm_name"),
+            ]
+        );
+
+        let children = instance
+            .repo
+            .inspect(|tx| {
+                traverse(
+                    &instance,
+                    tx,
+                    &sid,
+                    Direction::Outgoing,
+                    &[ReferenceKind::Child],
+                    5,
+                )
+            })
+            .unwrap();
+        assert_eq!(children.len(), 3);
+        let users = instance
+            .repo
+            .inspect(|tx| traverse(&instance, tx, &keeper_uid, Direction::Incoming, &[], 1))
+            .unwrap();
+        assert_eq!(users, to_keeper);
+
+        instance
+            .repo
+            .mutate(|tx| {
+                unroll(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    &tavern_uid,
+                    None,
+                )
+            })
+            .unwrap();
+        let to_keeper = instance.references_to(&keeper_uid).unwrap();
+        assert!(to_keeper.iter().all(|r| r.from != tavern_uid));
+        assert_eq!(referencing(&keeper_uid), vec![sid.clone()]);
+        assert!(instance
+            .references_to(&sid)
+            .unwrap()
+            .iter()
+            .all(|r| r.kind != ReferenceKind::Context));
+        assert_eq!(
+            instance.repo.load(&keeper_uid).unwrap()["tavern_name"],
+            false
+        );
+    }
 }
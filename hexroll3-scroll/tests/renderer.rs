@@ -1,5 +1,3 @@
-mod utils;
-
 #[cfg(test)]
 mod renderer {
     use hexroll3_scroll::renderer::render_entity;
@@ -7,8 +5,7 @@ mod renderer {
 
     use hexroll3_scroll::instance::*;
     use hexroll3_scroll::renderer_env::*;
-
-    use crate::utils::create_tempfile;
+    use hexroll3_scroll::storage::IN_MEMORY;
 
     fn render(template: &str) -> String {
         let mut env = Environment::new();
@@ -123,8 +120,7 @@ main {
 }
 ",
         );
-        let tmp = create_tempfile();
-        instance.create(tmp.path().to_str().unwrap()).unwrap();
+        instance.create(IN_MEMORY).unwrap();
         instance
             .repo
             .inspect(|tx| {
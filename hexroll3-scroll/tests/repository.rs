@@ -0,0 +1,1297 @@
+mod utils;
+
+#[cfg(test)]
+mod tests {
+
+    use hexroll3_scroll::encoding::*;
+    use hexroll3_scroll::export;
+    use hexroll3_scroll::fsck::*;
+    use hexroll3_scroll::generators::*;
+    use hexroll3_scroll::instance::*;
+    use hexroll3_scroll::metadata::*;
+    use hexroll3_scroll::migrations::*;
+    use hexroll3_scroll::query::*;
+    use hexroll3_scroll::renderer::*;
+    use hexroll3_scroll::repository::*;
+    use hexroll3_scroll::semantics::*;
+    use hexroll3_scroll::storage::*;
+
+    use crate::utils::create_tempfile;
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_snapshots() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+class1 {
+    foo = bar
+}
+
+main {
+    [1..1 list] @ class1
+}",
+        );
+        let tmp = create_tempfile();
+        instance.create(tmp.path().to_str().unwrap()).unwrap();
+        let sid = instance.sid().unwrap();
+
+        instance.repo.snapshot("before").unwrap();
+        assert!(instance.repo.snapshot("before").is_err());
+
+        let appended = instance
+            .repo
+            .mutate(|tx| {
+                append(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    &sid,
+                    "list",
+                    None,
+                )
+            })
+            .unwrap();
+        instance.repo.snapshot("after").unwrap();
+        assert_eq!(instance.repo.snapshots().unwrap(), vec!["before", "after"]);
+        assert_eq!(
+            instance.repo.load(&sid).unwrap()["list"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let branch_tmp = create_tempfile();
+        let branched = instance
+            .repo
+            .branch("before", branch_tmp.path().to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            branched.load(&sid).unwrap()["list"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(instance.repo.load(&appended).is_ok());
+        // Branching leaves every snapshot usable
+        assert_eq!(instance.repo.snapshots().unwrap(), vec!["before", "after"]);
+        instance.repo.restore_snapshot("after").unwrap();
+        assert!(instance.repo.load(&appended).is_ok());
+        assert_eq!(instance.repo.snapshots().unwrap(), vec!["before", "after"]);
+
+        instance.repo.restore_snapshot("before").unwrap();
+        assert_eq!(
+            instance.repo.load(&sid).unwrap()["list"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(instance.repo.load(&appended).is_err());
+        assert_eq!(instance.repo.snapshots().unwrap(), vec!["before"]);
+
+        instance.repo.delete_snapshot("before").unwrap();
+        assert!(instance.repo.snapshots().unwrap().is_empty());
+        assert!(instance.repo.restore_snapshot("before").is_err());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_check_and_repair() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+}
+
+Tavern {
+    keeper ? NPC
+    realm = :main.title
+}
+
+main {
+    title = Land
+    << NPC
+    [2..2 npcs] @ NPC
+    [1..1 taverns] @ Tavern
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        assert!(instance.check().unwrap().is_consistent());
+
+        let main = instance.repo.load(&sid).unwrap();
+        let tavern_uid = main["taverns"][0].as_str().unwrap().to_string();
+        let keeper_uid = instance.repo.load(&tavern_uid).unwrap()["keeper"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+        instance
+            .repo
+            .mutate(|tx| {
+                tx.remove(&keeper_uid)?;
+                tx.remove(&format!("{}_frame", tavern_uid))?;
+                tx.store(
+                    "stranger",
+                    &serde_json::json!({"uid": "stranger", "class": "NPC", "parent_uid": sid}),
+                )?;
+                tx.store("stranger_frame", &serde_json::json!({"$parent": sid}))
+            })
+            .unwrap();
+
+        let dangling = |uid: &str, attr: &str| Issue::DanglingReference {
+            uid: uid.to_string(),
+            attr: attr.to_string(),
+            missing: keeper_uid.clone(),
+        };
+        let report = instance.check().unwrap();
+        assert_eq!(report.entities, 4);
+        assert!(report.issues.contains(&dangling(&sid, "npcs")));
+        assert!(report.issues.contains(&dangling(&tavern_uid, "keeper")));
+        assert!(report
+            .issues
+            .contains(&Issue::MissingFrame(tavern_uid.clone())));
+        assert!(report
+            .issues
+            .contains(&Issue::OrphanFrame(format!("{}_frame", keeper_uid))));
+        assert!(report.issues.contains(&Issue::StaleFrameEntry {
+            frame: format!("{}_frame", sid),
+            entry: "$collections.$unused.NPC".to_string(),
+            missing: keeper_uid.clone(),
+        }));
+        assert!(report
+            .issues
+            .contains(&Issue::Unreachable("stranger".to_string())));
+
+        let report = instance.repair().unwrap();
+        assert_eq!(report.repaired.len(), 5);
+        assert_eq!(
+            report.issues,
+            vec![Issue::Unreachable("stranger".to_string())]
+        );
+        assert_eq!(instance.check().unwrap().issues, report.issues);
+        let main = instance.repo.load(&sid).unwrap();
+        assert_eq!(main["npcs"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            instance.repo.load(&tavern_uid).unwrap()["keeper"],
+            serde_json::json!([])
+        );
+        assert!(instance.repo.load(&format!("{}_frame", tavern_uid)).is_ok());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_collect_garbage() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+}
+
+main {
+    << NPC
+    [3..3 npcs] @ NPC
+}",
+        );
+        let tmp = create_tempfile();
+        instance.create(tmp.path().to_str().unwrap()).unwrap();
+        let sid = instance.sid().unwrap();
+        let stray = instance.repo.load(&sid).unwrap()["npcs"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let padding = "x".repeat(10000);
+        instance
+            .repo
+            .mutate(|tx| {
+                // Detach an NPC from main, leaving it in main's collection
+                let main = tx.load(&sid)?;
+                main["npcs"].as_array_mut().unwrap().remove(0);
+                tx.save(&sid)?;
+                for i in 0..200 {
+                    let uid = format!("leaked{}", i);
+                    tx.store(
+                        &uid,
+                        &serde_json::json!({
+                            "uid": uid, "class": "NPC", "parent_uid": sid, "name": padding
+                        }),
+                    )?;
+                    tx.store(&format!("{}_frame", uid), &serde_json::json!({}))?;
+                }
+                tx.store("ghost_frame", &serde_json::json!({}))
+            })
+            .unwrap();
+
+        let shared = instance.repo.storage.clone();
+        assert!(instance.collect_garbage().is_err());
+        drop(shared);
+
+        let report = instance.collect_garbage().unwrap();
+        assert_eq!(report.entities.len(), 201);
+        assert!(report.entities.contains(&stray));
+        assert_eq!(report.frames, vec!["ghost_frame"]);
+        assert_eq!(report.references, 1);
+        assert!(report.compacted);
+        assert!(report.bytes_reclaimed > 0);
+        assert!(instance.check().unwrap().is_consistent());
+        assert!(instance.repo.load(&stray).is_err());
+        assert_eq!(
+            instance.repo.load(&format!("{}_frame", sid)).unwrap()["$collections"]["$unused"]
+                ["NPC"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Snapshots keep old pages alive, so the file is not compacted
+        instance.repo.snapshot("kept").unwrap();
+        let report = instance.collect_garbage().unwrap();
+        assert!(report.entities.is_empty());
+        assert!(!report.compacted);
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_collect_garbage_recycles_used_entities() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+}
+
+Tavern {
+    keeper % NPC
+}
+
+main {
+    << NPC
+    [1..1 npcs] @ NPC
+    [1..1 taverns] @ Tavern
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        let main = instance.repo.load(&sid).unwrap();
+        let npc = main["npcs"][0].clone();
+        let tavern = main["taverns"][0].as_str().unwrap().to_string();
+        assert_eq!(instance.repo.load(&tavern).unwrap()["keeper"][0], npc);
+        instance
+            .repo
+            .mutate(|tx| {
+                // Detach the tavern from main, leaving its keeper used
+                tx.load(&sid)?["taverns"] = serde_json::json!([]);
+                tx.save(&sid)
+            })
+            .unwrap();
+
+        let report = instance.collect_garbage().unwrap();
+        assert_eq!(report.entities, vec![tavern]);
+        assert!(instance.check().unwrap().is_consistent());
+        let appended = instance
+            .repo
+            .mutate(|tx| {
+                append(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    &sid,
+                    "taverns",
+                    None,
+                )
+            })
+            .unwrap();
+        assert_eq!(instance.repo.load(&appended).unwrap()["keeper"][0], npc);
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_query() {
+        let scroll = "
+Monster {
+    index Kind
+    Kind = beast
+    HP @ 1d20
+}
+
+Goblin(Monster) {
+    Kind = goblinoid
+}
+
+Wolf(Monster) {}
+
+Lair {
+    [2..2 goblins] @ Goblin
+    [1..1 wolves] @ Wolf
+}
+
+main {
+    [2..2 lairs] @ Lair
+    [1..1 wolves] @ Wolf
+}";
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(scroll);
+        let tmp = create_tempfile();
+        instance.create(tmp.path().to_str().unwrap()).unwrap();
+        let sid = instance.sid().unwrap();
+        let lair = instance.repo.load(&sid).unwrap()["lairs"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+        let count = |query: Query| instance.query(&query).unwrap().total;
+
+        let monsters = || Query {
+            class: Some("Monster".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(count(monsters()), 7);
+        assert_eq!(
+            count(Query {
+                class: Some("Goblin".to_string()),
+                ..Default::default()
+            }),
+            4
+        );
+        assert_eq!(
+            count(Query {
+                parent: Some(lair.clone()),
+                ..Default::default()
+            }),
+            3
+        );
+        assert_eq!(
+            count(Query {
+                within: Some(lair.clone()),
+                ..monsters()
+            }),
+            3
+        );
+        let kind = |value: &str| SelectionFilter {
+            attr: "Kind".to_string(),
+            operator: FilterOperator::Equal,
+            value: serde_json::json!(value),
+        };
+        assert_eq!(
+            count(Query {
+                filters: vec![kind("beast")],
+                ..monsters()
+            }),
+            3
+        );
+        assert_eq!(
+            count(Query {
+                filters: vec![kind("goblinoid")],
+                within: Some(lair.clone()),
+                ..Default::default()
+            }),
+            2
+        );
+
+        let sorted = instance
+            .query(&Query {
+                sort_by: Some("HP".to_string()),
+                ..monsters()
+            })
+            .unwrap();
+        let hp = |uid: &String| instance.repo.load(uid).unwrap()["HP"].as_i64().unwrap();
+        assert!(sorted.uids.windows(2).all(|w| hp(&w[0]) <= hp(&w[1])));
+        let page = instance
+            .query(&Query {
+                sort_by: Some("HP".to_string()),
+                descending: true,
+                offset: 1,
+                limit: Some(3),
+                ..monsters()
+            })
+            .unwrap();
+        assert_eq!(page.total, 7);
+        assert_eq!(page.uids.len(), 3);
+        assert!(page.uids.windows(2).all(|w| hp(&w[0]) >= hp(&w[1])));
+
+        // Indexes follow entities being unrolled
+        let goblin = instance.repo.load(&lair).unwrap()["goblins"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+        instance
+            .repo
+            .mutate(|tx| unroll(&SandboxBuilder::from_instance(&instance), tx, &goblin, None))
+            .unwrap();
+        assert_eq!(count(monsters()), 6);
+        assert_eq!(
+            count(Query {
+                filters: vec![kind("goblinoid")],
+                ..monsters()
+            }),
+            3
+        );
+
+        // Indexes are rebuilt when missing
+        instance.repo.reindex().unwrap();
+        assert!(instance.repo.is_indexed().unwrap());
+        assert_eq!(count(monsters()), 6);
+
+        // Entities are indexed under every class in their hierarchy
+        let indexed = |instance: &SandboxInstance, key: IndexKey| {
+            instance.repo.inspect(|tx| tx.indexed(&key)).unwrap().len()
+        };
+        assert_eq!(
+            indexed(&instance, IndexKey::Class("Monster".to_string())),
+            6
+        );
+
+        // Indexes are rebuilt when opened using scrolls indexing other
+        // attributes
+        let goblinoids = || IndexKey::Attribute("Kind".to_string(), serde_json::json!("goblinoid"));
+        let filepath = tmp.path().to_str().unwrap();
+        instance.repo.close();
+        let mut unindexed = SandboxInstance::new();
+        unindexed.parse_buffer(&scroll.replace("index Kind", ""));
+        unindexed.open(filepath).unwrap();
+        assert_eq!(indexed(&unindexed, goblinoids()), 0);
+        drop(unindexed);
+        instance.open(filepath).unwrap();
+        assert_eq!(indexed(&instance, goblinoids()), 3);
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_search() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+IndexedEntity {
+    Anchor = \"\"
+}
+
+GivenName {
+    Value! = Ulbert
+}
+
+FullName {
+    Given @ GivenName
+    Last = Stonehand
+    Full ~ <%{{Given.Value}} {{Last}}%>
+}
+
+NPC {
+    Name @ FullName
+    Kind = village blacksmith
+    $IndexRef @ IndexedEntity {
+        Render = \"Name\"
+        Kind = village blacksmith
+        Details = \"The {{Kind}}\"
+        Type = npc
+        Icon = user
+    }
+}
+
+Tavern {
+    Title = The Prancing Pony
+    $IndexRef @ IndexedEntity {
+        Render = \"Title\"
+        Icon = beer
+    }
+}
+
+main {
+    npc @ NPC
+    tavern @ Tavern
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        let npc = instance.repo.load(&sid).unwrap()["npc"][0]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let hits = instance.search("Ulbert", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].uid, npc);
+        assert_eq!(hits[0].link, npc);
+        assert_eq!(hits[0].title, "Ulbert Stonehand");
+        assert_eq!(hits[0].details, "The village blacksmith");
+        assert_eq!(hits[0].icon.as_deref(), Some("user"));
+        assert_eq!(hits[0].kind.as_deref(), Some("npc"));
+        assert!(hits[0].snippet.contains("Ulbert"));
+
+        let hits = instance.search("prancing pony tavern", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].title, "The Prancing Pony");
+        assert!(instance.search("blacksmith", 10).unwrap()[0].uid == npc);
+        assert!(instance.search("", 10).unwrap().is_empty());
+
+        // Documents follow changes to any descendant of the entity found
+        let given = instance.repo.inspect(|tx| {
+            let name = tx.retrieve(&npc)?.value["Name"][0].clone();
+            Ok(tx.retrieve(name.as_str().unwrap())?.value["Given"][0].clone())
+        });
+        let given = given.unwrap().as_str().unwrap().to_string();
+        instance
+            .repo
+            .mutate(|tx| {
+                tx.load(&given)?["Value"] = serde_json::json!("Olbert");
+                tx.save(&given)
+            })
+            .unwrap();
+        assert_eq!(instance.update_search_index().unwrap(), 3);
+        assert_eq!(
+            instance.search("Stonehand", 10).unwrap()[0].title,
+            "Olbert Stonehand"
+        );
+        assert!(instance.search("Ulbert", 10).unwrap().is_empty());
+
+        // The index follows entities being rerolled and unrolled, within the
+        // same transaction
+        let builder = SandboxBuilder::from_instance(&instance);
+        let rerolled = instance
+            .repo
+            .mutate(|tx| reroll(&builder, tx, &npc, None))
+            .unwrap();
+        let changed = instance.repo.inspect(|tx| tx.indexed(&IndexKey::Changed));
+        assert!(changed.unwrap().is_empty());
+        let hits = instance.search("Ulbert", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].uid, rerolled);
+        instance
+            .repo
+            .mutate(|tx| unroll(&builder, tx, &rerolled, None))
+            .unwrap();
+        assert!(instance.search("Ulbert", 10).unwrap().is_empty());
+        assert_eq!(instance.search("pony", 10).unwrap().len(), 1);
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_export_and_import() {
+        let scroll = "
+NPC {
+    name = Bob
+    hp @ 1d20
+}
+
+main {
+    Version = 2
+    [2..5 npcs] @ NPC
+}";
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(scroll);
+        instance
+            .create_with_options(
+                IN_MEMORY,
+                BuilderOptions {
+                    seed: Some(7),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let metadata = SandboxMetadata {
+            created: Some(1),
+            ..instance.metadata().unwrap().unwrap()
+        };
+        instance.repo.mutate(|tx| metadata.store(tx)).unwrap();
+        let exported = create_tempfile();
+        let header = instance.export(exported.path().to_str().unwrap()).unwrap();
+        assert_eq!(header.format, "hexroll3-sandbox");
+        assert_eq!(header.scroll_version, Some(serde_json::json!(2)));
+        assert_eq!(header.seed, Some(7));
+        assert_eq!(header.sid, instance.sid());
+        let export = std::fs::read_to_string(exported.path()).unwrap();
+        assert_eq!(export.lines().count(), header.entries + 1);
+
+        let mut imported = SandboxInstance::new();
+        imported.parse_buffer(scroll);
+        let imported_header = imported
+            .import(IN_MEMORY, exported.path().to_str().unwrap())
+            .unwrap();
+        assert_eq!(imported_header, header);
+        assert_eq!(imported.sid(), instance.sid());
+        // The metadata of the exported sandbox is kept
+        let imported_metadata = imported.metadata().unwrap().unwrap();
+        assert_eq!(imported_metadata.created, Some(1));
+        assert_eq!(imported_metadata.scroll_hash, metadata.scroll_hash);
+        assert_eq!(imported_metadata.entities, metadata.entities);
+        let reexported = create_tempfile();
+        imported
+            .export(reexported.path().to_str().unwrap())
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(reexported.path()).unwrap(), export);
+        let npcs = Query {
+            class: Some("NPC".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            imported.query(&npcs).unwrap(),
+            instance.query(&npcs).unwrap()
+        );
+        assert!(imported.check().unwrap().is_consistent());
+
+        // Only complete exports are imported, into new repositories
+        let truncated = create_tempfile();
+        std::fs::write(
+            truncated.path(),
+            export.lines().take(3).collect::<Vec<_>>().join("\n"),
+        )
+        .unwrap();
+        assert!(SandboxInstance::new()
+            .import(IN_MEMORY, truncated.path().to_str().unwrap())
+            .is_err());
+        // Failed imports leave no new file behind
+        let dir = tempfile::tempdir().unwrap();
+        let filepath = dir.path().join("truncated.h3");
+        assert!(SandboxInstance::new()
+            .import(
+                filepath.to_str().unwrap(),
+                truncated.path().to_str().unwrap()
+            )
+            .is_err());
+        assert!(!filepath.exists());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_value_encodings() {
+        let value = serde_json::json!({"uid": "abc", "class": "NPC", "hp": 7});
+        let encoded = encode(&value, ValueEncoding::Cbor).unwrap();
+        assert_eq!(
+            decode(&encoded).unwrap(),
+            (value.clone(), ValueEncoding::Cbor)
+        );
+        // Values stored before encoding headers are plain CBOR
+        let mut legacy = Vec::new();
+        ciborium::into_writer(&value, &mut legacy).unwrap();
+        assert_eq!(decode(&legacy).unwrap(), (value, ValueEncoding::Cbor));
+
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name = Bob
+    hp @ 1d20
+}
+
+main {
+    [20..20 npcs] @ NPC
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        let stored_encoding =
+            |repo: &Repository| repo.inspect(|tx| Ok(tx.load(&sid)?.encoding)).unwrap();
+        let exported = |repo: &Repository| {
+            let mut bytes = Vec::new();
+            repo.inspect(|tx| export::export(tx, &mut bytes)).unwrap();
+            bytes
+        };
+        // Values are not compressed unless asked to, whatever the features
+        assert_eq!(stored_encoding(&instance.repo), ValueEncoding::Cbor);
+
+        let mut encodings = vec![ValueEncoding::Cbor];
+        if cfg!(feature = "zstd") {
+            encodings.push(ValueEncoding::Zstd);
+            #[cfg(feature = "zstd")]
+            encodings.push(ValueEncoding::ZstdDictionary(register_dictionary(
+                &instance.repo.train_dictionary(1024).unwrap(),
+            )));
+        } else {
+            assert!(instance.repo.encode_with(ValueEncoding::Zstd).is_err());
+        }
+        for encoding in encodings {
+            let converted = create_tempfile();
+            let filepath = converted.path().to_str().unwrap();
+            drop(instance.repo.convert(filepath, encoding).unwrap());
+            // Dictionaries are stored in the converted repository
+            let mut reopened = Repository::new();
+            reopened.open(filepath).unwrap();
+            assert_eq!(stored_encoding(&reopened), encoding);
+            assert_eq!(exported(&reopened), exported(&instance.repo));
+            assert_eq!(
+                reopened
+                    .inspect(|tx| tx.indexed(&IndexKey::Class("NPC".to_string())))
+                    .unwrap()
+                    .len(),
+                20
+            );
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    /// The raw bytes of stored values, under the type name they are stored
+    /// with, for writing values that cannot be decoded.
+    #[derive(Debug)]
+    struct RawValue;
+
+    impl redb::Value for RawValue {
+        type SelfType<'a>
+            = &'a [u8]
+        where
+            Self: 'a;
+        type AsBytes<'a>
+            = &'a [u8]
+        where
+            Self: 'a;
+
+        fn fixed_width() -> Option<usize> {
+            None
+        }
+
+        fn from_bytes<'a>(data: &'a [u8]) -> &'a [u8]
+        where
+            Self: 'a,
+        {
+            data
+        }
+
+        fn as_bytes<'a, 'b: 'a>(value: &'a &'b [u8]) -> &'a [u8]
+        where
+            Self: 'a,
+            Self: 'b,
+        {
+            value
+        }
+
+        fn type_name() -> redb::TypeName {
+            redb::TypeName::new("test::JsonValue")
+        }
+    }
+
+    #[test]
+    fn test_undecodable_values() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+main {
+    name = Bob
+}",
+        );
+        let tmp = create_tempfile();
+        let filepath = tmp.path().to_str().unwrap();
+        instance.create(filepath).unwrap();
+        let sid = instance.sid().unwrap();
+        instance.repo.close();
+        {
+            let db = redb::Database::open(filepath).unwrap();
+            let tx = db.begin_write().unwrap();
+            // A value compressed using a dictionary that was never registered
+            tx.open_table(redb::TableDefinition::<String, RawValue>::new("my_data2"))
+                .unwrap()
+                .insert(sid.clone(), [0xfc, 2, 1, 2, 3, 4, 5].as_slice())
+                .unwrap();
+            // A value compressed using zstd
+            tx.open_table(redb::TableDefinition::<String, RawValue>::new("my_data2"))
+                .unwrap()
+                .insert("compressed".to_string(), [0xfc, 1, 1, 2, 3].as_slice())
+                .unwrap();
+            tx.commit().unwrap();
+        }
+        let mut repo = Repository::new();
+        repo.open(filepath).unwrap();
+        let error = repo.load(&sid).err().unwrap();
+        assert!(format!("{:#}", error).contains(&sid));
+        assert!(instance.open(filepath).is_err());
+
+        // Every transaction reports why a value could not be decoded
+        let errors = [
+            repo.load("compressed").err().unwrap(),
+            repo.inspect(|tx| tx.load("compressed")).err().unwrap(),
+            repo.mutate(|tx| tx.retrieve("compressed")).err().unwrap(),
+            repo.ephemeral()
+                .unwrap()
+                .retrieve("compressed")
+                .err()
+                .unwrap(),
+        ];
+        for error in errors {
+            let error = format!("{:#}", error);
+            assert!(error.contains("compressed"));
+            #[cfg(not(feature = "zstd"))]
+            assert!(error.contains("needs the zstd feature"));
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_merging_detached_transactions() {
+        let mut repo = Repository::new();
+        repo.create(IN_MEMORY).unwrap();
+        repo.mutate(|tx| tx.store("a", &serde_json::json!({"list": [1, 1, 2], "n": 1})))
+            .unwrap();
+        repo.mutate(|tx| {
+            let detached = tx.detach()?;
+            let worker =
+                |f: &dyn Fn(&mut serde_json::Value)| -> anyhow::Result<DetachedTransaction> {
+                    let mut worker_tx = ReadWriteTransaction::from_detached(detached.clone());
+                    f(worker_tx.load("a")?);
+                    worker_tx.save("a")?;
+                    worker_tx.into_detached()
+                };
+            // Arrays changed by one transaction only are kept as they are
+            tx.merge(worker(&|a| a["list"] = serde_json::json!([1, 1, 2, 2]))?)?;
+            assert_eq!(tx.load("a")?["list"], serde_json::json!([1, 1, 2, 2]));
+            tx.merge(worker(&|a| a["n"] = serde_json::json!(2))?)?;
+            assert_eq!(tx.load("a")?["n"], 2);
+            // Values changed by both conflict
+            assert!(!tx.can_merge(&worker(&|a| a["n"] = serde_json::json!(3))?)?);
+            assert!(!tx.can_merge(&worker(&|a| a["list"] = serde_json::json!([1]))?)?);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_in_memory_storage() {
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+NPC {
+    name @ [
+        * Alice
+        * Bob
+    ]
+}
+
+main {
+    [5..5 npcs] @ NPC
+}",
+        );
+        instance.create(IN_MEMORY).unwrap();
+        let sid = instance.sid().unwrap();
+        assert!(instance.repo.filepath.is_none());
+        assert!(instance.repo.file_size().unwrap() > 0);
+        assert!(Repository::new().open(IN_MEMORY).is_err());
+
+        let npcs = |repo: &Repository| {
+            repo.inspect(|tx| tx.indexed(&IndexKey::Class("NPC".to_string())))
+                .unwrap()
+        };
+        assert_eq!(npcs(&instance.repo).len(), 5);
+        assert_eq!(
+            instance
+                .query(&Query {
+                    class: Some("NPC".to_string()),
+                    ..Default::default()
+                })
+                .unwrap()
+                .total,
+            5
+        );
+
+        // Readers keep the state they began with
+        let removed = npcs(&instance.repo)[0].clone();
+        instance
+            .repo
+            .inspect(|before| {
+                instance.repo.mutate(|tx| tx.remove(&removed))?;
+                assert!(before.load(&removed).is_ok());
+                assert!(instance.repo.load(&removed).is_err());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(npcs(&instance.repo).len(), 4);
+
+        // Failed transactions are discarded
+        assert!(instance
+            .repo
+            .mutate(|tx| {
+                tx.remove(&sid)?;
+                Err::<(), _>(anyhow::anyhow!("failed"))
+            })
+            .is_err());
+        assert!(instance.repo.load(&sid).is_ok());
+
+        let savepoint = instance.repo.savepoint().unwrap();
+        instance.repo.snapshot("four").unwrap();
+        instance.repo.mutate(|tx| tx.remove(&sid)).unwrap();
+        assert!(instance.repo.load(&sid).is_err());
+        instance.repo.restore(&savepoint).unwrap();
+        assert!(instance.repo.load(&sid).is_ok());
+
+        instance.repo.reindex().unwrap();
+        assert_eq!(npcs(&instance.repo).len(), 4);
+        let branched = instance.repo.branch("four", IN_MEMORY).unwrap();
+        assert_eq!(npcs(&branched).len(), 4);
+        assert!(!instance.repo.compact().unwrap());
+    }
+
+    // ------------------------------------------------------------------------
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_concurrent_readers() {
+        assert_send_sync::<SandboxInstance>();
+        assert_send_sync::<Repository>();
+        assert_send_sync::<ReadOnlyTransaction>();
+
+        let tmp = create_tempfile();
+        for filepath in [tmp.path().to_str().unwrap(), IN_MEMORY] {
+            let mut instance = SandboxInstance::new();
+            instance.parse_buffer(
+                "
+NPC {
+    name = Alice
+    Title! ~ <%{{name}}%>
+}
+
+main {
+    [3..3 npcs] @ NPC
+}",
+            );
+            instance.create(filepath).unwrap();
+            let instance = &instance;
+            let npc = instance
+                .query(&Query {
+                    class: Some("NPC".to_string()),
+                    ..Default::default()
+                })
+                .unwrap()
+                .uids[0]
+                .clone();
+            let npc = npc.as_str();
+
+            let (written_tx, written) = std::sync::mpsc::channel();
+            let (resume_tx, resume) = std::sync::mpsc::channel::<()>();
+            std::thread::scope(|scope| {
+                let writer = scope.spawn(move || {
+                    instance.repo.mutate(|tx| {
+                        tx.load(npc)?["name"] = serde_json::json!("Bob");
+                        tx.save(npc)?;
+                        written_tx.send(()).unwrap();
+                        resume.recv().unwrap();
+                        Ok(())
+                    })
+                });
+                written.recv().unwrap();
+
+                // Readers render the committed state while the write is in progress
+                let readers: Vec<_> = (0..4)
+                    .map(|_| {
+                        scope.spawn(move || {
+                            instance
+                                .repo
+                                .inspect(|tx| {
+                                    let entity = tx.load(npc)?.value;
+                                    render_entity(instance, tx, &entity, false)
+                                })
+                                .unwrap()["Title"]
+                                .clone()
+                        })
+                    })
+                    .collect();
+                for reader in readers {
+                    assert_eq!(reader.join().unwrap(), "Alice");
+                }
+                resume_tx.send(()).unwrap();
+                writer.join().unwrap().unwrap();
+            });
+            assert_eq!(instance.repo.load(npc).unwrap()["name"], "Bob");
+        }
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_sandbox_metadata() {
+        let scroll = "
+NPC {
+    name = Alice
+}
+
+main {
+    Version = 3
+    [4..4 npcs] @ NPC
+    [0..0 pets] @ Pet
+}
+
+Pet {
+    name = Rex
+}";
+        let with_scroll = |scroll: &str| {
+            let mut instance = SandboxInstance::new();
+            instance.parse_buffer(scroll);
+            instance
+        };
+        let mut instance = with_scroll(scroll);
+        let tmp = create_tempfile();
+        let filepath = tmp.path().to_str().unwrap();
+        instance
+            .create_with_options(
+                filepath,
+                BuilderOptions {
+                    seed: Some(7),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+
+        let metadata = instance.metadata().unwrap().unwrap();
+        assert_eq!(metadata.format_version, FORMAT_VERSION);
+        assert_eq!(metadata.scroll_version, Some(serde_json::json!(3)));
+        assert!(metadata.scroll_hash.is_some());
+        assert_eq!(metadata.seed, Some(7));
+        assert!(metadata.created.is_some());
+        assert_eq!(metadata.sid, instance.sid());
+        assert_eq!(metadata.entities["NPC"], 4);
+        assert_eq!(metadata.entities["main"], 1);
+        assert_eq!(metadata.checked_by, None);
+        drop(instance);
+
+        // Changed scrolls are only warned about
+        assert!(with_scroll(scroll).open(filepath).is_ok());
+        assert!(with_scroll(&scroll.replace("Alice", "Bob"))
+            .open(filepath)
+            .is_ok());
+        assert!(SandboxInstance::new().open(filepath).is_ok());
+        let error = with_scroll(&scroll.replace("NPC", "Monster"))
+            .open(filepath)
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("NPC"));
+
+        // Classes appended since creating are checked as well
+        assert!(with_scroll(&scroll.replace("Pet", "Dog"))
+            .open(filepath)
+            .is_ok());
+        let mut instance = with_scroll(scroll);
+        instance.open(filepath).unwrap();
+        assert_eq!(
+            instance.metadata().unwrap().unwrap().checked_by,
+            Some(generator())
+        );
+        let sid = instance.sid().unwrap();
+        instance
+            .repo
+            .mutate(|tx| {
+                append(
+                    &SandboxBuilder::from_instance(&instance),
+                    tx,
+                    &sid,
+                    "pets",
+                    None,
+                )
+            })
+            .unwrap();
+        drop(instance);
+        let error = with_scroll(&scroll.replace("Pet", "Dog"))
+            .open(filepath)
+            .err()
+            .unwrap();
+        assert!(format!("{:#}", error).contains("Pet"));
+
+        let mut instance = with_scroll(scroll);
+        instance.open(filepath).unwrap();
+        instance
+            .repo
+            .mutate(|tx| {
+                SandboxMetadata {
+                    format_version: FORMAT_VERSION + 1,
+                    ..metadata.clone()
+                }
+                .store(tx)
+            })
+            .unwrap();
+        drop(instance);
+        assert!(with_scroll(scroll).open(filepath).is_err());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_embedded_scrolls() {
+        let scrolls = tempfile::tempdir().unwrap();
+        std::fs::create_dir(scrolls.path().join("lib")).unwrap();
+        std::fs::write(
+            scrolls.path().join("lib/npc.scroll"),
+            "
+NPC {
+    name = Alice
+    Title! ~ <%{{name}}%>
+}",
+        )
+        .unwrap();
+        let main_scroll = scrolls.path().join("main.scroll");
+        std::fs::write(
+            &main_scroll,
+            "
++ /lib/npc
+
+main {
+    [2..2 npcs] @ NPC
+}",
+        )
+        .unwrap();
+
+        let tmp = create_tempfile();
+        let filepath = tmp.path().to_str().unwrap();
+        let not_embedded = create_tempfile();
+        let mut instance = SandboxInstance::new();
+        instance.with_scroll(main_scroll).unwrap();
+        assert_eq!(instance.scroll_sources.roots, vec!["main.scroll"]);
+        assert!(instance.scroll_sources.files.contains_key("lib/npc.scroll"));
+        instance
+            .create_with_options(
+                filepath,
+                BuilderOptions {
+                    embed_scrolls: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let scroll_hash = instance.scroll_hash;
+        instance
+            .create(not_embedded.path().to_str().unwrap())
+            .unwrap();
+        drop(instance);
+        drop(scrolls);
+        assert!(SandboxInstance::has_embedded_scrolls(filepath).unwrap());
+        assert!(
+            !SandboxInstance::has_embedded_scrolls(not_embedded.path().to_str().unwrap()).unwrap()
+        );
+
+        let mut instance = SandboxInstance::new();
+        instance.open_self_contained(filepath).unwrap();
+        assert!(instance.classes.contains_key("NPC"));
+        assert_eq!(instance.scroll_hash, scroll_hash);
+        let npc = instance
+            .query(&Query {
+                class: Some("NPC".to_string()),
+                ..Default::default()
+            })
+            .unwrap()
+            .uids[0]
+            .clone();
+        let entity = instance.repo.load(&npc).unwrap();
+        let rendered = instance
+            .repo
+            .inspect(|tx| render_entity(&instance, tx, &entity, false))
+            .unwrap();
+        assert_eq!(rendered["Title"], "Alice");
+
+        assert!(SandboxInstance::new()
+            .open_self_contained(not_embedded.path().to_str().unwrap())
+            .is_err());
+    }
+
+    // ------------------------------------------------------------------------
+    #[test]
+    fn test_migrations() {
+        let tmp = create_tempfile();
+        let filepath = tmp.path().to_str().unwrap();
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Pet {
+    kind = cat
+}
+NPC {
+    Version = 1
+    name = Alice
+    mood = grumpy
+    [1..1 pets] @ Pet
+}
+main {
+    Version = 1
+    [2..2 npcs] @ NPC
+}",
+        );
+        instance.create(filepath).unwrap();
+        drop(instance);
+
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Pet {
+    kind = cat
+}
+NPC {
+    Version = 2
+    name = Alice
+    Mood = calm
+    Title = Captain
+}
+main {
+    Version = 1
+    [2..2 npcs] @ NPC
+}",
+        );
+        instance.open(filepath).unwrap();
+        let mut migrations = Migrations::new();
+        migrations.step("NPC", 1, |_, tx, uid| {
+            let npc = tx.load(uid)?;
+            npc["Mood"] = npc["mood"].clone();
+            Ok(())
+        });
+        let report = instance.migrate(&migrations).unwrap();
+        assert!(!report.baseline);
+        assert_eq!(report.entities.len(), 2);
+        for migrated in report.entities.iter() {
+            assert_eq!(migrated.class, "NPC");
+            assert_eq!(migrated.versions, Some((1, 2)));
+            assert_eq!(migrated.steps, vec![("NPC".to_string(), 1)]);
+            assert_eq!(migrated.added, vec!["Title"]);
+            assert_eq!(migrated.removed, vec!["mood", "pets"]);
+            let npc = instance.repo.load(&migrated.uid).unwrap();
+            assert_eq!(npc["Version"], 2);
+            assert_eq!(npc["Mood"], "grumpy");
+            assert_eq!(npc["Title"], "Captain");
+            assert!(npc.is_missing("mood"));
+            assert!(npc.is_missing("pets"));
+        }
+        let pets = instance
+            .query(&Query {
+                class: Some("Pet".to_string()),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(pets.uids.is_empty());
+        assert!(instance.check().unwrap().is_consistent());
+
+        // Migrated sandboxes store the current model
+        assert!(instance.migrate(&migrations).unwrap().entities.is_empty());
+        drop(instance);
+
+        // Sandboxes without a stored model have each entity compared with
+        // its class, keeping the attributes injected into it
+        let mut instance = SandboxInstance::new();
+        instance.parse_buffer(
+            "
+Pet {
+    kind = cat
+}
+NPC {
+    Version = 2
+    name = Alice
+    Mood = calm
+    Rank = Sergeant
+    [1..1 pets] @ Pet {
+        owner = *name
+    }
+}
+main {
+    Version = 1
+    [2..2 npcs] @ NPC
+}",
+        );
+        instance.open(filepath).unwrap();
+        instance.repo.mutate(|tx| tx.remove("$model")).unwrap();
+        let report = instance.migrate(&migrations).unwrap();
+        assert!(report.baseline);
+        assert_eq!(report.entities.len(), 2);
+        for migrated in report.entities.iter() {
+            assert_eq!(migrated.class, "NPC");
+            assert_eq!(migrated.versions, None);
+            assert_eq!(migrated.added, vec!["Rank", "pets"]);
+            assert_eq!(migrated.removed, vec!["Title"]);
+            let npc = instance.repo.load(&migrated.uid).unwrap();
+            assert_eq!(npc["Rank"], "Sergeant");
+            assert!(npc.is_missing("Title"));
+        }
+        assert!(instance.check().unwrap().is_consistent());
+        instance.repo.mutate(|tx| tx.remove("$model")).unwrap();
+        let report = instance.migrate(&migrations).unwrap();
+        assert!(report.baseline);
+        assert!(report.entities.is_empty());
+    }
+}
@@ -27,10 +27,12 @@ mod views;
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
 use std::{collections::HashMap, rc::Rc};
 
 use hexroll3_scroll::instance::SandboxInstance;
+use hexroll3_scroll::progress::{CancellationToken, Progress};
+use hexroll3_scroll::search::SearchHit;
 
 use helpers::config::load_settings;
 use helpers::html::Element;
@@ -41,6 +43,7 @@ use self::helpers::config::TestbedConfig;
 pub struct HexrollTestbedApp {
     // Main app state
     instance: Option<SandboxInstance>,
+    rolling: Option<RollingSandbox>,
     current_entity: EntityData,
     scroll_in_filepath_is_valid: bool,
 
@@ -51,6 +54,8 @@ pub struct HexrollTestbedApp {
     center_view_mode: CenterViewMode,
     file: egui_file_dialog::FileDialog,
     history: VecDeque<String>,
+    search_text: String,
+    search_hits: Vec<SearchHit>,
 
     // Utilities
     app_logs: LogStorage,
@@ -66,6 +71,14 @@ pub struct EntityData {
     html_demidom: Rc<RefCell<HashMap<usize, Element>>>,
 }
 
+/// A sandbox being rolled in the background.
+pub struct RollingSandbox {
+    filepath: String,
+    progress: Arc<Mutex<Progress>>,
+    cancellation: CancellationToken,
+    receiver: mpsc::Receiver<anyhow::Result<SandboxInstance>>,
+}
+
 #[derive(PartialEq, Clone)]
 enum CenterViewMode {
     Preview,
@@ -88,6 +101,7 @@ impl Default for HexrollTestbedApp {
         Self {
             // Main app state
             instance: None,
+            rolling: None,
             current_entity: EntityData::default(),
             scroll_in_filepath_is_valid: HexrollTestbedApp::test_scroll_filepath(
                 &cconfig.main_scroll_filepath,
@@ -109,6 +123,8 @@ impl Default for HexrollTestbedApp {
                 )
                 .default_file_filter("H3"),
             history: VecDeque::new(),
+            search_text: String::new(),
+            search_hits: Vec::new(),
             // Utilities
             app_logs: storage,
             config: cconfig,
@@ -126,6 +142,8 @@ impl eframe::App for HexrollTestbedApp {
                 self.top_app_bar(ctx, ui);
             });
 
+        self.poll_rolling_sandbox();
+        self.rolling_window(ctx);
         self.trace_panel(ctx, _frame);
         match self.instance {
             Some(_) => self.instance_panels(ctx, _frame),
@@ -139,6 +157,33 @@ impl eframe::App for HexrollTestbedApp {
 }
 
 impl HexrollTestbedApp {
+    pub fn rolling_window(&mut self, ctx: &egui::Context) {
+        let Some(rolling) = &self.rolling else {
+            return;
+        };
+        let progress = rolling.progress.lock().unwrap().clone();
+        egui::Window::new("Rolling a new sandbox")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "Rolled {} of about {} entities (depth {})",
+                    progress.rolled, progress.estimated_total, progress.depth
+                ));
+                if progress.estimated_total > 0 {
+                    ui.add(egui::ProgressBar::new(
+                        (progress.rolled as f32 / progress.estimated_total as f32).min(1.0),
+                    ));
+                }
+                if rolling.cancellation.is_cancelled() {
+                    ui.label("Cancelling...");
+                } else if ui.button("Cancel").clicked() {
+                    rolling.cancellation.cancel();
+                }
+            });
+    }
+
     pub fn trace_panel(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::TopBottomPanel::bottom("bottom-panel")
             .min_height(100.0)
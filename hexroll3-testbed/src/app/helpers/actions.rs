@@ -22,17 +22,20 @@
 // license. Please contact ithai at pendicepaper.com
 // for more information about commercial licensing terms.
 */
+use std::sync::{mpsc, Arc, Mutex};
 use std::{path::PathBuf, str::FromStr};
 
 use anyhow::Result;
 
 use hexroll3_scroll::{
     generators::{append, reroll, unroll},
-    instance::{SandboxBuilder, SandboxInstance},
+    instance::{BuilderOptions, SandboxBuilder, SandboxInstance},
+    migrations::Migrations,
+    progress::{CancellationToken, Progress},
     renderer::{render_entity, render_entity_html},
 };
 
-use crate::app::{helpers::config::ConfigSandboxState, HexrollTestbedApp};
+use crate::app::{helpers::config::ConfigSandboxState, HexrollTestbedApp, RollingSandbox};
 
 impl HexrollTestbedApp {
     fn refresh_raw_json(&mut self) {
@@ -80,11 +83,15 @@ impl HexrollTestbedApp {
 
     pub fn open_existing_sandbox(&mut self, filepath: &str) -> Result<()> {
         let mut instance = SandboxInstance::new();
-        if let Some(root_uid) = instance
-            .with_scroll(PathBuf::from_str(&self.config.main_scroll_filepath)?)?
-            .open(filepath)?
-            .sid()
-        {
+        // Sandboxes with embedded scrolls need no scroll directory
+        if SandboxInstance::has_embedded_scrolls(filepath)? {
+            instance.open_self_contained(filepath)?;
+        } else {
+            instance
+                .with_scroll(PathBuf::from_str(&self.config.main_scroll_filepath)?)?
+                .open(filepath)?;
+        }
+        if let Some(root_uid) = instance.sid() {
             self.instance = Some(instance);
             let temp = self
                 .config
@@ -104,19 +111,67 @@ impl HexrollTestbedApp {
         }
     }
 
-    pub fn roll_new_sandbox(&mut self, filepath: &str) -> Result<()> {
+    pub fn roll_new_sandbox(&mut self, ctx: &egui::Context, filepath: &str) -> Result<()> {
         let mut instance = SandboxInstance::new();
-        if let Some(root_uid) = instance
-            .with_scroll(PathBuf::from_str(&self.config.main_scroll_filepath)?)?
-            .create(filepath)?
-            .sid()
-        {
-            self.instance = Some(instance);
-            self.navigate(&root_uid, true);
-            self.update_mru_list(filepath);
-            Ok(())
-        } else {
-            unreachable!()
+        instance.with_scroll(PathBuf::from_str(&self.config.main_scroll_filepath)?)?;
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let cancellation = CancellationToken::new();
+        let options = BuilderOptions {
+            on_progress: Some({
+                let progress = progress.clone();
+                let ctx = ctx.clone();
+                Arc::new(move |p: &Progress| {
+                    *progress.lock().unwrap() = p.clone();
+                    ctx.request_repaint();
+                })
+            }),
+            cancellation: cancellation.clone(),
+            ..Default::default()
+        };
+        let (sender, receiver) = mpsc::channel();
+        let path = filepath.to_owned();
+        let create = move || {
+            let result = instance.create_with_options(&path, options).map(|_| ());
+            sender.send(result.map(|_| instance)).ok();
+        };
+        // Rolling a sandbox can take a while, so it happens in the
+        // background where threads are available, keeping the UI responsive.
+        #[cfg(not(target_arch = "wasm32"))]
+        std::thread::spawn(create);
+        #[cfg(target_arch = "wasm32")]
+        create();
+        self.rolling = Some(RollingSandbox {
+            filepath: filepath.to_owned(),
+            progress,
+            cancellation,
+            receiver,
+        });
+        Ok(())
+    }
+
+    pub fn poll_rolling_sandbox(&mut self) {
+        let Some(rolling) = &self.rolling else {
+            return;
+        };
+        let result = match rolling.receiver.try_recv() {
+            Ok(result) => result,
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Err(anyhow::anyhow!("Sandbox rolling has stopped unexpectedly"))
+            }
+        };
+        let rolling = self.rolling.take().unwrap();
+        match result {
+            Ok(instance) => {
+                let root_uid = instance.sid().unwrap();
+                self.instance = Some(instance);
+                self.navigate(&root_uid, true);
+                self.update_mru_list(&rolling.filepath);
+            }
+            Err(e) => {
+                log::error!("Unable to roll a new sandbox. {:#}", e);
+                self.instance = None;
+            }
         }
     }
 
@@ -191,6 +246,130 @@ impl HexrollTestbedApp {
         }
     }
 
+    /// Check the sandbox consistency, repairing it when asked to, and log
+    /// every issue found.
+    pub fn check_sandbox(&mut self, repair: bool) {
+        if let Some(instance) = &self.instance {
+            let report = if repair {
+                instance.repair()
+            } else {
+                instance.check()
+            };
+            match report {
+                Ok(report) => {
+                    for issue in report.repaired.iter() {
+                        log::info!("Repaired: {}", issue);
+                    }
+                    for issue in report.issues.iter() {
+                        log::warn!("Inconsistency: {}", issue);
+                    }
+                    log::info!(
+                        "Checked {} entities and {} frames, found {} issues and repaired {}",
+                        report.entities,
+                        report.frames,
+                        report.issues.len() + report.repaired.len(),
+                        report.repaired.len()
+                    );
+                    if repair {
+                        self.prepare_demidom();
+                        self.refresh_raw_json();
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error when checking the sandbox: {:?}", e);
+                }
+            }
+        }
+    }
+
+    pub fn collect_garbage(&mut self) {
+        if let Some(instance) = &mut self.instance {
+            match instance.collect_garbage() {
+                Ok(report) => {
+                    log::info!(
+                        "Deleted {} unreachable entities and {} stale frames, reclaiming {} bytes",
+                        report.entities.len(),
+                        report.frames.len(),
+                        report.bytes_reclaimed
+                    );
+                    if !report.compacted {
+                        log::warn!("The sandbox file was not compacted as it holds snapshots");
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error when collecting garbage: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Migrate the sandbox entities to the loaded scrolls, logging every
+    /// entity migrated.
+    pub fn migrate_sandbox(&mut self) {
+        if let Some(instance) = &self.instance {
+            match instance.migrate(&Migrations::new()) {
+                Ok(report) => {
+                    for migrated in report.entities.iter() {
+                        log::info!("Migrated {}", migrated);
+                    }
+                    log::info!("Migrated {} entities", report.entities.len());
+                    self.prepare_demidom();
+                    self.refresh_raw_json();
+                }
+                Err(e) => {
+                    log::error!("Error when migrating the sandbox: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Embed the loaded scrolls in the sandbox, so it can be opened without
+    /// the scroll directory.
+    pub fn embed_scrolls(&mut self) {
+        if let Some(instance) = &self.instance {
+            match instance.embed_scrolls() {
+                Ok(()) => log::info!("Embedded the scrolls in the sandbox"),
+                Err(e) => log::error!("Error when embedding the scrolls: {:?}", e),
+            }
+        }
+    }
+
+    /// Export the sandbox next to its file, as JSON Lines.
+    pub fn export_sandbox(&mut self) {
+        if let Some(instance) = &self.instance {
+            let Some(filepath) = instance.repo.filepath.as_ref() else {
+                return;
+            };
+            let export_filepath = filepath.with_extension("jsonl");
+            match instance.export(export_filepath.to_str().unwrap()) {
+                Ok(header) => {
+                    log::info!(
+                        "Exported {} entries to {}",
+                        header.entries,
+                        export_filepath.display()
+                    );
+                }
+                Err(e) => {
+                    log::error!("Error when exporting the sandbox: {:?}", e);
+                }
+            }
+        }
+    }
+
+    pub fn search(&mut self) {
+        if let Some(instance) = &self.instance {
+            match instance.search(&self.search_text, 50) {
+                Ok(hits) => {
+                    log::info!("Found {} results for {}", hits.len(), self.search_text);
+                    self.search_hits = hits;
+                }
+                Err(e) => {
+                    log::error!("Error when searching the sandbox: {:?}", e);
+                }
+            }
+        }
+    }
+
     pub fn load_rendered_json(&mut self) {
         log::trace!("Loading rendered json for {}", self.current_entity.uid);
         if let Some(instance) = &self.instance {
@@ -22,7 +22,7 @@
 // license. Please contact ithai at pendicepaper.com
 // for more information about commercial licensing terms.
 */
-use egui::{Color32, Context, CursorIcon, Ui};
+use egui::{Color32, Context, Ui};
 
 use crate::app::HexrollTestbedApp;
 
@@ -78,18 +78,57 @@ impl HexrollTestbedApp {
                             .with_scroll((&self.config.main_scroll_filepath).into())
                             .expect("That's odd!");
                     }
+                    ui.separator();
+                    if ui.button("Check Sandbox").clicked() {
+                        self.check_sandbox(false);
+                    }
+                    if ui.button("Repair Sandbox").clicked() {
+                        self.check_sandbox(true);
+                    }
+                    if ui.button("Collect Garbage").clicked() {
+                        self.collect_garbage();
+                    }
+                    if ui.button("Migrate Sandbox").clicked() {
+                        self.migrate_sandbox();
+                    }
+                    if ui.button("Export Sandbox").clicked() {
+                        self.export_sandbox();
+                    }
+                    if ui.button("Embed Scrolls").clicked() {
+                        self.embed_scrolls();
+                    }
                 });
+                let search = ui.add(
+                    egui::TextEdit::singleline(&mut self.search_text)
+                        .hint_text("Search")
+                        .desired_width(160.0),
+                );
+                if search.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    self.search();
+                }
+                if !self.search_hits.is_empty() {
+                    ui.menu_button(format!("{} results", self.search_hits.len()), |ui| {
+                        for hit in self.search_hits.clone() {
+                            if ui
+                                .button(format!("{}: {}", hit.title, hit.snippet))
+                                .clicked()
+                            {
+                                self.navigate(&hit.link, true);
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                }
             }
             if let Some(mut path) = self.file.take_selected() {
                 match self.file.mode() {
-                    egui_file_dialog::DialogMode::SaveFile => {
-                        ctx.set_cursor_icon(CursorIcon::Wait);
-                        if path.set_extension("h3")
-                            && self.roll_new_sandbox(path.to_str().unwrap()).is_err()
-                        {
-                            self.instance = None;
-                        }
-                        ctx.set_cursor_icon(CursorIcon::Default);
+                    egui_file_dialog::DialogMode::SaveFile if path.set_extension("h3") => {
+                        self.roll_new_sandbox(ctx, path.to_str().unwrap())
+                            .map_err(|e| {
+                                log::error!("Unable to roll a new sandbox. {:#}", e);
+                                self.instance = None;
+                            })
+                            .ok();
                     }
                     egui_file_dialog::DialogMode::SelectFile => {
                         self.open_existing_sandbox(path.to_str().unwrap())
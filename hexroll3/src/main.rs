@@ -1 +1,171 @@
-fn main() {}
+/*
+// Copyright (C) 2020-2025 Pen, Dice & Paper
+//
+// This program is dual-licensed under the following terms:
+//
+// Option 1: (Non-Commercial) GNU Affero General Public License (AGPL)
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Option 2: Commercial License
+// For commercial use, you are required to obtain a separate commercial
+// license. Please contact ithai at pendicepaper.com
+// for more information about commercial licensing terms.
+*/
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use anyhow::{anyhow, Result};
+
+use hexroll3_scroll::encoding::{register_dictionary, ValueEncoding};
+use hexroll3_scroll::instance::SandboxInstance;
+use hexroll3_scroll::metadata::SandboxMetadata;
+use hexroll3_scroll::repository::Repository;
+
+const USAGE: &str = "Usage:
+  hexroll3 convert <source.h3> <target.h3> [cbor|zstd|dictionary]
+  hexroll3 info <sandbox.h3>
+  hexroll3 check [--repair] <sandbox.h3> [main.scroll]
+  hexroll3 gc <sandbox.h3> [main.scroll]";
+
+/// The largest dictionary trained when converting using a dictionary.
+const DICTIONARY_MAX_SIZE: usize = 112640;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("convert") => convert(&args[1..]),
+        Some("info") => info(&args[1..]),
+        Some("check") => check(&args[1..]),
+        Some("gc") => gc(&args[1..]),
+        _ => Err(anyhow!(USAGE)),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Convert a sandbox file, storing its values using another encoding.
+fn convert(args: &[String]) -> Result<()> {
+    let [source, target, encoding @ ..] = args else {
+        return Err(anyhow!(USAGE));
+    };
+    let mut repo = Repository::new();
+    repo.open(source)?;
+    let encoding = match encoding.first().map(String::as_str) {
+        Some("cbor") => ValueEncoding::Cbor,
+        Some("zstd") | None => ValueEncoding::Zstd,
+        Some("dictionary") => ValueEncoding::ZstdDictionary(register_dictionary(
+            &repo.train_dictionary(DICTIONARY_MAX_SIZE)?,
+        )),
+        Some(encoding) => return Err(anyhow!("Unknown encoding {}\n{}", encoding, USAGE)),
+    };
+    let converted = repo.convert(target, encoding)?;
+    println!(
+        "Converted {} ({} bytes) to {} ({} bytes) using {}",
+        source,
+        repo.file_size()?,
+        target,
+        converted.file_size()?,
+        encoding
+    );
+    Ok(())
+}
+
+/// Print the metadata of a sandbox file.
+fn info(args: &[String]) -> Result<()> {
+    let [filepath] = args else {
+        return Err(anyhow!(USAGE));
+    };
+    let mut repo = Repository::new();
+    repo.open(filepath)?;
+    match SandboxMetadata::read(&repo)? {
+        Some(metadata) => println!("{}", serde_json::to_string_pretty(&metadata)?),
+        None => println!("{} has no metadata", filepath),
+    }
+    Ok(())
+}
+
+/// Check the consistency of a sandbox file, and possibly repair it, using
+/// the given scrolls or else the scrolls embedded in it.
+fn check(args: &[String]) -> Result<()> {
+    let (repair, args) = match args {
+        [flag, args @ ..] if flag == "--repair" => (true, args),
+        args => (false, args),
+    };
+    let [filepath, scroll @ ..] = args else {
+        return Err(anyhow!(USAGE));
+    };
+    let instance = open(filepath, scroll)?;
+    let report = if repair {
+        instance.repair()?
+    } else {
+        instance.check()?
+    };
+    println!(
+        "Checked {} entities and {} frames in {}",
+        report.entities, report.frames, filepath
+    );
+    for issue in &report.repaired {
+        println!("Repaired: {}", issue);
+    }
+    for issue in &report.issues {
+        println!("Inconsistent: {}", issue);
+    }
+    if !report.is_consistent() {
+        return Err(anyhow!("{} is inconsistent", filepath));
+    }
+    Ok(())
+}
+
+/// Delete unreachable entities and stale frames from a sandbox file, then
+/// compact it, using the given scrolls or else the scrolls embedded in it.
+fn gc(args: &[String]) -> Result<()> {
+    let [filepath, scroll @ ..] = args else {
+        return Err(anyhow!(USAGE));
+    };
+    let mut instance = open(filepath, scroll)?;
+    let report = instance.collect_garbage()?;
+    println!(
+        "Deleted {} entities and {} frames, and removed {} references from {}",
+        report.entities.len(),
+        report.frames.len(),
+        report.references,
+        filepath
+    );
+    if report.compacted {
+        println!(
+            "Compacted {}, reclaiming {} bytes",
+            filepath, report.bytes_reclaimed
+        );
+    }
+    Ok(())
+}
+
+/// Open a sandbox file using the given scrolls, or else the scrolls
+/// embedded in it.
+fn open(filepath: &str, scroll: &[String]) -> Result<SandboxInstance> {
+    let mut instance = SandboxInstance::new();
+    match scroll {
+        [] => instance.open_self_contained(filepath)?,
+        [scroll] => instance
+            .with_scroll(PathBuf::from(scroll))?
+            .open(filepath)?,
+        _ => return Err(anyhow!(USAGE)),
+    };
+    Ok(instance)
+}